@@ -0,0 +1,21 @@
+//! Interactively calibrate the steering servo and write the calibration file.
+//!
+//! Usage: `calibrate-servo [PATH]`, where `PATH` defaults to the
+//! [calibration file](hs_hackathon_car::calibration::default_path).
+
+use hs_hackathon_car::{calibration, CarConfig, WheelOrientation};
+use std::path::PathBuf;
+
+#[tokio::main]
+async fn main() -> eyre::Result<()> {
+    let path = std::env::args_os()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(calibration::default_path);
+
+    let mut wheels = WheelOrientation::new(&CarConfig::load_default()?).await?;
+    let calibration = wheels.calibrate(std::io::stdin().lock(), std::io::stdout())?;
+    calibration.save(&path)?;
+    println!("wrote {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,353 @@
+//! Per-car calibration of the steering servo.
+//!
+//! No two cars steer quite alike: the wheels point straight at a slightly different servo
+//! angle, and the linkage hits its end stops at different angles. A [`ServoCalibration`]
+//! captures this, is stored as TOML, and is applied by
+//! [`WheelOrientation`](crate::WheelOrientation) whenever the wheels are turned.
+//!
+//! ```toml
+//! # servo angle in degrees is 90 + center_offset when driving straight
+//! center_offset = -4.0
+//! # servo angles in degrees at full left and full right lock
+//! left_limit = 165.0
+//! right_limit = 20.0
+//! # optional, pairs of (angle in [-1, 1], servo angle in degrees) to interpolate between
+//! lookup = [[-1.0, 165.0], [0.0, 86.0], [0.5, 60.0], [1.0, 20.0]]
+//! ```
+//!
+//! The `calibrate-servo` binary walks through measuring these values and writes the file.
+
+use crate::Angle;
+use eyre::{ensure, Context};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// The environment variable pointing to the calibration file.
+pub const CALIBRATION_ENV_VAR: &str = "HS_HACKATHON_SERVO_CALIBRATION";
+
+/// The calibration file used if [`CALIBRATION_ENV_VAR`] is not set.
+pub const DEFAULT_CALIBRATION_FILE: &str = "servo-calibration.toml";
+
+/// Angle in degrees the servo can turn in total.
+const ACTUATION_RANGE: f32 = 180.0;
+
+/// Calibration of the steering servo, mapping an [`Angle`] to a servo angle in degrees.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServoCalibration {
+    /// Offset in degrees from 90° at which the wheels point straight.
+    pub center_offset: f32,
+    /// Servo angle in degrees at full left lock ([`Angle::left`]).
+    pub left_limit: f32,
+    /// Servo angle in degrees at full right lock ([`Angle::right`]).
+    pub right_limit: f32,
+    /// Optional pairs of (angle in [-1, 1], servo angle in degrees) to interpolate between,
+    /// for linkages that do not steer linearly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lookup: Option<Vec<[f32; 2]>>,
+}
+
+impl Default for ServoCalibration {
+    fn default() -> Self {
+        Self {
+            center_offset: 0.0,
+            left_limit: ACTUATION_RANGE,
+            right_limit: 0.0,
+            lookup: None,
+        }
+    }
+}
+
+impl ServoCalibration {
+    /// Servo angle in degrees at which the wheels point straight.
+    pub fn center(&self) -> f32 {
+        ACTUATION_RANGE / 2.0 + self.center_offset
+    }
+
+    /// Checks that all servo angles are reachable and the limits lie on either side of
+    /// the center.
+    pub fn validate(&self) -> eyre::Result<()> {
+        let range = 0.0..=ACTUATION_RANGE;
+        for (name, degrees) in [
+            ("center", self.center()),
+            ("left limit", self.left_limit),
+            ("right limit", self.right_limit),
+        ] {
+            ensure!(
+                range.contains(&degrees),
+                "{name} must be between 0° and {ACTUATION_RANGE}°, not {degrees}°"
+            );
+        }
+        let (low, high) = self.bounds();
+        ensure!(
+            (low..=high).contains(&self.center()),
+            "center must lie between the left and the right limit"
+        );
+        if let Some(lookup) = &self.lookup {
+            ensure!(lookup.len() >= 2, "lookup needs at least two points");
+            ensure!(
+                lookup.windows(2).all(|pair| pair[0][0] < pair[1][0]),
+                "lookup angles must be strictly increasing"
+            );
+            for [angle, degrees] in lookup {
+                ensure!(
+                    (-1.0..=1.0).contains(angle),
+                    "lookup angle must be between -1 and 1, not {angle}"
+                );
+                ensure!(
+                    range.contains(degrees),
+                    "lookup servo angle must be between 0° and {ACTUATION_RANGE}°, not {degrees}°"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The servo angle in degrees to turn the wheels to the given angle.
+    ///
+    /// The result always lies between the left and the right limit.
+    pub fn degrees(&self, angle: Angle) -> f32 {
+        let angle = angle.into_inner();
+        let degrees = match &self.lookup {
+            Some(lookup) if !lookup.is_empty() => interpolate(lookup, angle),
+            _ if angle < 0.0 => self.center() - angle * (self.left_limit - self.center()),
+            _ => self.center() + angle * (self.right_limit - self.center()),
+        };
+        let (low, high) = self.bounds();
+        degrees.clamp(low, high)
+    }
+
+    /// Reads a calibration from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("read servo calibration {}", path.display()))?;
+        let calibration: Self = toml::from_str(&content)
+            .wrap_err_with(|| format!("parse servo calibration {}", path.display()))?;
+        calibration.validate()?;
+        Ok(calibration)
+    }
+
+    /// Reads the calibration from [`CALIBRATION_ENV_VAR`] or [`DEFAULT_CALIBRATION_FILE`],
+    /// falling back to the uncalibrated default if neither exists.
+    pub fn load_default() -> eyre::Result<Self> {
+        let path = default_path();
+        if !path.exists() {
+            tracing::debug!("no servo calibration at {}", path.display());
+            return Ok(Self::default());
+        }
+        Self::load(path)
+    }
+
+    /// Writes the calibration to a TOML file.
+    pub fn save(&self, path: impl AsRef<Path>) -> eyre::Result<()> {
+        let path = path.as_ref();
+        self.validate()?;
+        let content = toml::to_string_pretty(self).wrap_err("serialize servo calibration")?;
+        std::fs::write(path, content)
+            .wrap_err_with(|| format!("write servo calibration {}", path.display()))
+    }
+
+    fn bounds(&self) -> (f32, f32) {
+        (
+            self.left_limit.min(self.right_limit),
+            self.left_limit.max(self.right_limit),
+        )
+    }
+}
+
+/// The path of the calibration file to use.
+pub fn default_path() -> PathBuf {
+    std::env::var_os(CALIBRATION_ENV_VAR)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CALIBRATION_FILE))
+}
+
+/// Piecewise linear interpolation between the given points, sorted by angle.
+fn interpolate(lookup: &[[f32; 2]], angle: f32) -> f32 {
+    let first = lookup[0];
+    let last = lookup[lookup.len() - 1];
+    if angle <= first[0] {
+        return first[1];
+    }
+    if angle >= last[0] {
+        return last[1];
+    }
+    lookup
+        .windows(2)
+        .find(|pair| angle <= pair[1][0])
+        .map(|pair| {
+            let [[a0, d0], [a1, d1]] = [pair[0], pair[1]];
+            d0 + (angle - a0) / (a1 - a0) * (d1 - d0)
+        })
+        .unwrap_or(last[1])
+}
+
+/// Interactively measures a calibration by turning the servo while the user watches
+/// the wheels.
+///
+/// For the center, the left and the right limit in turn, the user adjusts the servo
+/// angle until the wheels point the right way: `+`/`-` nudge by one degree, `++`/`--`
+/// by five, a number sets the angle directly, and an empty line accepts it.
+pub fn calibrate(
+    mut turn: impl FnMut(f32) -> eyre::Result<()>,
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> eyre::Result<ServoCalibration> {
+    let mut calibration = ServoCalibration::default();
+    let defaults = [
+        ("straight ahead", calibration.center()),
+        ("as far left as they go", calibration.left_limit),
+        ("as far right as they go", calibration.right_limit),
+    ];
+
+    let mut measured = [0.0; 3];
+    for (measurement, (target, start)) in measured.iter_mut().zip(defaults) {
+        let mut degrees = start;
+        writeln!(
+            output,
+            "Turn the wheels {target} (+/- nudge, ++/-- nudge more, a number sets the angle, enter accepts)"
+        )?;
+        loop {
+            turn(degrees)?;
+            write!(output, "{degrees:.1}° > ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                eyre::bail!("input ended before the calibration was complete");
+            }
+            let next = match line.trim() {
+                "" => break,
+                "+" => degrees + 1.0,
+                "-" => degrees - 1.0,
+                "++" => degrees + 5.0,
+                "--" => degrees - 5.0,
+                other => match other.parse::<f32>() {
+                    Ok(value) => value,
+                    Err(_) => {
+                        writeln!(output, "not a command: {other}")?;
+                        continue;
+                    }
+                },
+            };
+            degrees = next.clamp(0.0, ACTUATION_RANGE);
+        }
+        *measurement = degrees;
+    }
+
+    let [center, left, right] = measured;
+    calibration.center_offset = center - ACTUATION_RANGE / 2.0;
+    calibration.left_limit = left;
+    calibration.right_limit = right;
+    calibration.validate()?;
+    turn(calibration.center())?;
+    Ok(calibration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn angle(value: f32) -> Angle {
+        value.try_into().unwrap()
+    }
+
+    #[test]
+    fn test_default_is_linear() {
+        let calibration = ServoCalibration::default();
+        for value in [-1.0, -0.5, 0.0, 0.25, 1.0] {
+            assert_eq!(-90.0 * value + 90.0, calibration.degrees(angle(value)));
+        }
+    }
+
+    #[test]
+    fn test_center_offset_and_limits() {
+        let calibration = ServoCalibration {
+            center_offset: -10.0,
+            left_limit: 160.0,
+            right_limit: 30.0,
+            lookup: None,
+        };
+        assert_eq!(80.0, calibration.degrees(Angle::straight()));
+        assert_eq!(160.0, calibration.degrees(Angle::left()));
+        assert_eq!(30.0, calibration.degrees(Angle::right()));
+        assert_eq!(120.0, calibration.degrees(angle(-0.5)));
+        assert_eq!(55.0, calibration.degrees(angle(0.5)));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let calibration = ServoCalibration {
+            lookup: Some(vec![[-1.0, 170.0], [0.0, 95.0], [0.5, 60.0], [1.0, 10.0]]),
+            left_limit: 165.0,
+            right_limit: 10.0,
+            ..Default::default()
+        };
+        // clamped to the left limit
+        assert_eq!(165.0, calibration.degrees(Angle::left()));
+        assert_eq!(95.0, calibration.degrees(Angle::straight()));
+        assert_eq!(77.5, calibration.degrees(angle(0.25)));
+        assert_eq!(35.0, calibration.degrees(angle(0.75)));
+    }
+
+    #[test]
+    fn test_toml_roundtrip() {
+        let toml = "center_offset = -4.0\nleft_limit = 165.0\nright_limit = 20.0\nlookup = [[-1.0, 165.0], [1.0, 20.0]]\n";
+        let calibration: ServoCalibration = toml::from_str(toml).unwrap();
+        calibration.validate().unwrap();
+        assert_eq!(86.0, calibration.center());
+
+        let written = toml::to_string_pretty(&calibration).unwrap();
+        assert_eq!(calibration, toml::from_str(&written).unwrap());
+
+        let partial: ServoCalibration = toml::from_str("center_offset = 3.0").unwrap();
+        assert_eq!(180.0, partial.left_limit);
+        assert!(toml::from_str::<ServoCalibration>("centre = 3.0").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut calibration = ServoCalibration {
+            center_offset: 100.0,
+            ..Default::default()
+        };
+        assert!(calibration.validate().is_err());
+        calibration.center_offset = 0.0;
+        calibration.right_limit = 120.0;
+        assert!(calibration.validate().is_err());
+        calibration.right_limit = 0.0;
+        calibration.lookup = Some(vec![[0.5, 90.0], [-0.5, 90.0]]);
+        assert!(calibration.validate().is_err());
+    }
+
+    #[test]
+    fn test_interactive_calibration() {
+        let input = "--\n+\n\n150\n\n\n";
+        let mut output = Vec::new();
+        let mut turned = Vec::new();
+        let calibration = calibrate(
+            |degrees| {
+                turned.push(degrees);
+                Ok(())
+            },
+            input.as_bytes(),
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(-4.0, calibration.center_offset);
+        assert_eq!(150.0, calibration.left_limit);
+        assert_eq!(0.0, calibration.right_limit);
+        assert_eq!(vec![90.0, 85.0, 86.0, 180.0, 150.0, 0.0, 86.0], turned);
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("straight ahead"));
+    }
+
+    #[test]
+    fn test_interactive_calibration_needs_all_steps() {
+        assert!(calibrate(|_| Ok(()), "\n".as_bytes(), std::io::sink()).is_err());
+    }
+}
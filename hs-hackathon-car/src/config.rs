@@ -0,0 +1,277 @@
+//! How the HATs are connected and the motors are wired.
+//!
+//! The defaults match the hackathon cars. A rewired car is described in a TOML file
+//! instead, all fields of which are optional:
+//!
+//! ```toml
+//! i2c_bus = "/dev/i2c-1"
+//! motor_address = 0x60
+//! servo_address = 0x40
+//! motor_frequency = 1220.0
+//! servo_frequency = 50.0
+//! motor = 2
+//! invert_motor = false
+//! servo_channel = 0
+//! ```
+//!
+//! Every field can also be overridden through an environment variable, e.g.
+//! `HS_HACKATHON_MOTOR=2`, see [`CarConfig::with_env`].
+
+use eyre::{ensure, Context};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The environment variable pointing to the config file.
+pub const CONFIG_ENV_VAR: &str = "HS_HACKATHON_CAR_CONFIG";
+
+/// The config file used if [`CONFIG_ENV_VAR`] is not set.
+pub const DEFAULT_CONFIG_FILE: &str = "car.toml";
+
+/// The I2C bus of the nixified RPi. Try /dev/i2c-1 for most other cases.
+pub const DEFAULT_I2C_BUS: &str = "/dev/i2c-3";
+
+/// Frequency range the PCA9685 can run at with its internal oscillator.
+const FREQUENCY_RANGE: std::ops::RangeInclusive<f32> = 24.0..=1526.0;
+
+/// Where the HATs of the car are connected and how its motors are wired.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CarConfig {
+    /// The I2C bus both HATs are connected to.
+    pub i2c_bus: PathBuf,
+    /// I2C address of the Motor HAT.
+    pub motor_address: u8,
+    /// I2C address of the Servo HAT.
+    pub servo_address: u8,
+    /// PWM frequency of the Motor HAT in Hz.
+    pub motor_frequency: f32,
+    /// PWM frequency of the Servo HAT in Hz.
+    pub servo_frequency: f32,
+    /// The terminal (1-4) of the Motor HAT the drive motor is wired to.
+    pub motor: u8,
+    /// Whether the motor, or both motors of a differential drive, are wired such that
+    /// positive throttle drives backwards.
+    pub invert_motor: bool,
+    /// The channel (0-15) of the Servo HAT the steering servo is plugged into.
+    pub servo_channel: u8,
+}
+
+impl Default for CarConfig {
+    fn default() -> Self {
+        Self {
+            i2c_bus: PathBuf::from(DEFAULT_I2C_BUS),
+            motor_address: 0x60,
+            servo_address: 0x40,
+            // a pre-scale of 4
+            motor_frequency: 1220.0,
+            servo_frequency: 50.0,
+            motor: 1,
+            // we wired it backwards
+            invert_motor: true,
+            servo_channel: 0,
+        }
+    }
+}
+
+impl CarConfig {
+    /// Checks that the addresses, frequencies, motor and channel exist.
+    pub fn validate(&self) -> eyre::Result<()> {
+        for (name, address) in [
+            ("motor address", self.motor_address),
+            ("servo address", self.servo_address),
+        ] {
+            ensure!(
+                (0x40..=0x7f).contains(&address),
+                "{name} must be between 0x40 and 0x7f, not {address:#x}"
+            );
+        }
+        for (name, frequency) in [
+            ("motor frequency", self.motor_frequency),
+            ("servo frequency", self.servo_frequency),
+        ] {
+            ensure!(
+                FREQUENCY_RANGE.contains(&frequency),
+                "{name} must be between {}Hz and {}Hz, not {frequency}Hz",
+                FREQUENCY_RANGE.start(),
+                FREQUENCY_RANGE.end(),
+            );
+        }
+        ensure!(
+            (1..=4).contains(&self.motor),
+            "motor must be between 1 and 4, not {}",
+            self.motor
+        );
+        ensure!(
+            self.servo_channel <= 15,
+            "servo channel must be between 0 and 15, not {}",
+            self.servo_channel
+        );
+        Ok(())
+    }
+
+    /// Reads a config from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("read car config {}", path.display()))?;
+        let config: Self = toml::from_str(&content)
+            .wrap_err_with(|| format!("parse car config {}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads the config from [`CONFIG_ENV_VAR`] or [`DEFAULT_CONFIG_FILE`], falling back to
+    /// the defaults if neither exists, and applies the overrides from the environment.
+    pub fn load_default() -> eyre::Result<Self> {
+        let path = std::env::var_os(CONFIG_ENV_VAR)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_FILE));
+        let config = if path.exists() {
+            Self::load(path)?
+        } else {
+            tracing::debug!("no car config at {}", path.display());
+            Self::default()
+        };
+        config.with_env()
+    }
+
+    /// Overrides fields from the environment variables `HS_HACKATHON_I2C_BUS`,
+    /// `HS_HACKATHON_MOTOR_ADDRESS`, `HS_HACKATHON_SERVO_ADDRESS`,
+    /// `HS_HACKATHON_MOTOR_FREQUENCY`, `HS_HACKATHON_SERVO_FREQUENCY`, `HS_HACKATHON_MOTOR`,
+    /// `HS_HACKATHON_INVERT_MOTOR` and `HS_HACKATHON_SERVO_CHANNEL`.
+    ///
+    /// Addresses may be given in hex with a `0x` prefix.
+    pub fn with_env(self) -> eyre::Result<Self> {
+        self.with_overrides(|name| std::env::var(name).ok())
+    }
+
+    fn with_overrides(mut self, lookup: impl Fn(&str) -> Option<String>) -> eyre::Result<Self> {
+        fn parse<T: FromStr>(name: &str, value: &str) -> eyre::Result<T>
+        where
+            T::Err: std::error::Error + Send + Sync + 'static,
+        {
+            value
+                .trim()
+                .parse()
+                .wrap_err_with(|| format!("parse {name}"))
+        }
+        fn parse_address(name: &str, value: &str) -> eyre::Result<u8> {
+            let value = value.trim();
+            match value
+                .strip_prefix("0x")
+                .or_else(|| value.strip_prefix("0X"))
+            {
+                Some(hex) => u8::from_str_radix(hex, 16).wrap_err_with(|| format!("parse {name}")),
+                None => parse(name, value),
+            }
+        }
+
+        let var = |field: &str| {
+            let name = format!("HS_HACKATHON_{field}");
+            lookup(&name).map(|value| (name, value))
+        };
+        if let Some((_, value)) = var("I2C_BUS") {
+            self.i2c_bus = PathBuf::from(value);
+        }
+        if let Some((name, value)) = var("MOTOR_ADDRESS") {
+            self.motor_address = parse_address(&name, &value)?;
+        }
+        if let Some((name, value)) = var("SERVO_ADDRESS") {
+            self.servo_address = parse_address(&name, &value)?;
+        }
+        if let Some((name, value)) = var("MOTOR_FREQUENCY") {
+            self.motor_frequency = parse(&name, &value)?;
+        }
+        if let Some((name, value)) = var("SERVO_FREQUENCY") {
+            self.servo_frequency = parse(&name, &value)?;
+        }
+        if let Some((name, value)) = var("MOTOR") {
+            self.motor = parse(&name, &value)?;
+        }
+        if let Some((name, value)) = var("INVERT_MOTOR") {
+            self.invert_motor = parse(&name, &value)?;
+        }
+        if let Some((name, value)) = var("SERVO_CHANNEL") {
+            self.servo_channel = parse(&name, &value)?;
+        }
+        self.validate()?;
+        Ok(self)
+    }
+
+    /// Opens the Motor HAT.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn motor_pwm(
+        &self,
+    ) -> eyre::Result<pwm_pca9685::Pca9685<linux_embedded_hal::I2cdev>> {
+        let i2c = crate::raw::open_i2c_bus(&self.i2c_bus)?;
+        Ok(crate::raw::init_pwm(
+            i2c,
+            self.motor_address,
+            self.motor_frequency,
+        )?)
+    }
+
+    /// Opens the Servo HAT.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn servo_pwm(
+        &self,
+    ) -> eyre::Result<pwm_pca9685::Pca9685<linux_embedded_hal::I2cdev>> {
+        let i2c = crate::raw::open_i2c_bus(&self.i2c_bus)?;
+        Ok(crate::raw::init_pwm(
+            i2c,
+            self.servo_address,
+            self.servo_frequency,
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_toml() {
+        let config: CarConfig =
+            toml::from_str("i2c_bus = \"/dev/i2c-1\"\nmotor = 2\ninvert_motor = false\n").unwrap();
+        config.validate().unwrap();
+        assert_eq!(Path::new("/dev/i2c-1"), config.i2c_bus);
+        assert_eq!(2, config.motor);
+        assert!(!config.invert_motor);
+        assert_eq!(0x60, config.motor_address);
+
+        let written = toml::to_string(&config).unwrap();
+        assert_eq!(config, toml::from_str(&written).unwrap());
+        assert!(toml::from_str::<CarConfig>("moter = 2").is_err());
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let env = HashMap::from([
+            ("HS_HACKATHON_MOTOR_ADDRESS", "0x61"),
+            ("HS_HACKATHON_SERVO_ADDRESS", "65"),
+            ("HS_HACKATHON_SERVO_FREQUENCY", "60"),
+            ("HS_HACKATHON_INVERT_MOTOR", "false"),
+            ("HS_HACKATHON_SERVO_CHANNEL", "3"),
+        ]);
+        let lookup = |name: &str| env.get(name).map(|value| value.to_string());
+        let config = CarConfig::default().with_overrides(lookup).unwrap();
+        assert_eq!(0x61, config.motor_address);
+        assert_eq!(0x41, config.servo_address);
+        assert_eq!(60.0, config.servo_frequency);
+        assert!(!config.invert_motor);
+        assert_eq!(3, config.servo_channel);
+        assert_eq!(1, config.motor);
+
+        let invalid = |name: &'static str, value: &'static str| {
+            CarConfig::default()
+                .with_overrides(|n| (n == name).then(|| value.to_string()))
+                .is_err()
+        };
+        assert!(invalid("HS_HACKATHON_MOTOR", "5"));
+        assert!(invalid("HS_HACKATHON_MOTOR", "one"));
+        assert!(invalid("HS_HACKATHON_MOTOR_ADDRESS", "0x20"));
+        assert!(invalid("HS_HACKATHON_MOTOR_FREQUENCY", "5000"));
+    }
+}
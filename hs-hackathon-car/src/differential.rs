@@ -0,0 +1,366 @@
+use crate::raw::Motor;
+use crate::sim::{self, Simulation};
+use crate::watchdog::{Clock, Deadline, Stop, SystemClock};
+use crate::{Angle, CarConfig, DcKit, PwmController, Velocity, Watchdog, MAX_COMMAND_TIMEOUT};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The driver behind a [`DifferentialDrive`]
+enum DriveDriver {
+    Pwm {
+        left: DcKit,
+        right: DcKit,
+        pwm: Box<dyn PwmController + Send>,
+        /// Whether both motors are wired the wrong way around
+        inverted: bool,
+    },
+    Sim(Simulation),
+}
+
+/// A [`DriveDriver`] and whether a [`Watchdog`] halted it
+struct DriveBackend {
+    driver: DriveDriver,
+    halted: bool,
+}
+
+impl Stop for DriveBackend {
+    fn stop(&mut self) -> eyre::Result<()> {
+        match &mut self.driver {
+            DriveDriver::Pwm {
+                left, right, pwm, ..
+            } => {
+                left.stop(pwm)?;
+                right.stop(pwm)?;
+            }
+            DriveDriver::Sim(sim) => sim.set_wheels(0.0, 0.0),
+        }
+        Ok(())
+    }
+
+    fn halt(&mut self) -> eyre::Result<()> {
+        self.halted = true;
+        self.stop()
+    }
+}
+
+impl DriveBackend {
+    /// Sets the throttle of both sides, each in the range [-1.0, 1.0]
+    fn set_wheels(&mut self, left_throttle: f32, right_throttle: f32) -> eyre::Result<()> {
+        eyre::ensure!(
+            (left_throttle == 0.0 && right_throttle == 0.0) || !self.halted,
+            "the watchdog stopped the motors for good"
+        );
+        match &mut self.driver {
+            DriveDriver::Pwm {
+                left,
+                right,
+                pwm,
+                inverted,
+            } => {
+                let sign = if *inverted { -1.0 } else { 1.0 };
+                left.set_throttle(pwm, sign * left_throttle)?;
+                right.set_throttle(pwm, sign * right_throttle)?;
+            }
+            DriveDriver::Sim(sim) => sim.set_wheels(left_throttle, right_throttle),
+        }
+        Ok(())
+    }
+}
+
+/// Mixes a linear and an angular velocity into the throttle of the left and the right wheels
+///
+/// [`Angle::left`] turns on the spot to the left, [`Angle::right`] to the right. If a wheel
+/// would need more than full throttle, both are scaled down to keep the turning radius.
+fn mix(linear: Velocity, angular: Angle) -> (f32, f32) {
+    let linear = linear.into_inner() / 100.0;
+    let turn = -angular.into_inner();
+    let (left, right) = (linear - turn, linear + turn);
+    let scale = left.abs().max(right.abs()).max(1.0);
+    (left / scale, right / scale)
+}
+
+/// A car without a steering servo that steers by driving its left and right wheels at
+/// different speeds
+///
+/// Offers the same safety cutoffs as the [`MotorSocket`](crate::MotorSocket): moves are
+/// capped at [`MAX_COMMAND_TIMEOUT`], continuous commands expire, and the motors stop when
+/// this is dropped.
+pub struct DifferentialDrive {
+    backend: Arc<Mutex<DriveBackend>>,
+    cooldown_since: Instant,
+    command_timeout: Duration,
+    deadline: Option<Deadline>,
+}
+
+impl DifferentialDrive {
+    /// Open the pins to talk to the DC motors wired to the given terminals (1-4) of the
+    /// Motor HAT, which is connected according to the config
+    ///
+    /// [`CarConfig::invert_motor`] applies to both motors.
+    ///
+    /// Uses the global [`Simulation`] instead if the simulation backend is
+    /// [enabled](sim::enabled).
+    pub async fn open(config: &CarConfig, left: u8, right: u8) -> eyre::Result<Self> {
+        config.validate()?;
+        if sim::enabled() {
+            Motor::dc(left)?;
+            Motor::dc(right)?;
+            return Ok(Self::simulated(Simulation::global()));
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            Self::from_pwm_config(config.motor_pwm()?, config, left, right)
+        }
+        #[cfg(not(target_os = "linux"))]
+        unreachable!("the simulation is always enabled off linux")
+    }
+
+    /// Drive the DC motors wired to the given terminals (1-4) through the given, already
+    /// initialized, PWM controller
+    pub fn from_pwm(
+        pwm: impl PwmController + Send + 'static,
+        left: u8,
+        right: u8,
+    ) -> eyre::Result<Self> {
+        let config = CarConfig {
+            invert_motor: false,
+            ..Default::default()
+        };
+        Self::from_pwm_config(pwm, &config, left, right)
+    }
+
+    /// Drive the DC motors wired to the given terminals (1-4) through the given, already
+    /// initialized, PWM controller, as wired according to the config
+    pub fn from_pwm_config(
+        pwm: impl PwmController + Send + 'static,
+        config: &CarConfig,
+        left: u8,
+        right: u8,
+    ) -> eyre::Result<Self> {
+        eyre::ensure!(left != right, "left and right motor must differ");
+        let mut pwm: Box<dyn PwmController + Send> = Box::new(pwm);
+        let left = DcKit::try_new(&mut pwm, Motor::dc(left)?)?;
+        let right = DcKit::try_new(&mut pwm, Motor::dc(right)?)?;
+        Ok(Self::with_driver(DriveDriver::Pwm {
+            left,
+            right,
+            pwm,
+            inverted: config.invert_motor,
+        }))
+    }
+
+    /// Drive the given simulated car instead of the hardware
+    pub fn simulated(sim: &Simulation) -> Self {
+        Self::with_driver(DriveDriver::Sim(sim.clone()))
+    }
+
+    fn with_driver(driver: DriveDriver) -> Self {
+        let backend = DriveBackend {
+            driver,
+            halted: false,
+        };
+        DifferentialDrive {
+            backend: Arc::new(Mutex::new(backend)),
+            cooldown_since: Instant::now(),
+            command_timeout: Duration::from_millis(500),
+            deadline: None,
+        }
+    }
+
+    /// Drive with the given linear and angular velocity
+    ///
+    /// Car will not move for longer than 1 second.
+    pub async fn move_for(
+        &mut self,
+        linear: Velocity,
+        angular: Angle,
+        max_dur: Duration,
+    ) -> eyre::Result<()> {
+        if let Some(deadline) = &self.deadline {
+            deadline.clear();
+        }
+        if let Some(left) = Duration::from_secs(4).checked_sub(self.cooldown_since.elapsed()) {
+            tokio::time::sleep(left).await;
+        }
+        let (left, right) = mix(linear, angular);
+        self.backend().set_wheels(left, right)?;
+        let actual_dur = std::cmp::min(MAX_COMMAND_TIMEOUT, max_dur);
+        tokio::time::sleep(actual_dur).await;
+        self.backend().set_wheels(0.0, 0.0)?;
+        self.cooldown_since = Instant::now();
+        Ok(())
+    }
+
+    /// Set the linear and angular velocity and return right away
+    ///
+    /// The motors keep running until the next call, or until no new velocity has been
+    /// set for the [command timeout](Self::set_command_timeout).
+    pub async fn set_velocity(&mut self, linear: Velocity, angular: Angle) -> eyre::Result<()> {
+        let (left, right) = mix(linear, angular);
+        self.backend().set_wheels(left, right)?;
+
+        let deadline = self.deadline.get_or_insert_with(|| {
+            let backend = Arc::clone(&self.backend);
+            Deadline::spawn(move || {
+                tracing::warn!("no velocity command within the timeout, stopping the car");
+                let mut backend = backend.lock().unwrap_or_else(|p| p.into_inner());
+                if let Err(e) = backend.set_wheels(0.0, 0.0) {
+                    tracing::error!("failed to stop the car: {e:?}");
+                }
+            })
+        });
+        if left == 0.0 && right == 0.0 {
+            deadline.clear();
+        } else {
+            deadline.renew(self.command_timeout);
+        }
+        Ok(())
+    }
+
+    /// Configure how long the motors keep running after the last
+    /// [`set_velocity`](Self::set_velocity)
+    ///
+    /// The timeout is capped at [`MAX_COMMAND_TIMEOUT`] and defaults to 500ms.
+    pub fn set_command_timeout(&mut self, timeout: Duration) {
+        self.command_timeout = std::cmp::min(MAX_COMMAND_TIMEOUT, timeout);
+    }
+
+    /// Start a dead-man [`Watchdog`] for both motors
+    ///
+    /// The motors are stopped unless [`Watchdog::heartbeat`] is called at least once per
+    /// `timeout`. Once the watchdog tripped, [`set_velocity`](Self::set_velocity) and
+    /// [`move_for`](Self::move_for) fail.
+    pub fn watchdog(&self, timeout: Duration) -> eyre::Result<Watchdog> {
+        self.watchdog_with(timeout, SystemClock)
+    }
+
+    pub(crate) fn watchdog_with(
+        &self,
+        timeout: Duration,
+        clock: impl Clock,
+    ) -> eyre::Result<Watchdog> {
+        Watchdog::spawn(self.backend.clone(), timeout, clock)
+    }
+
+    /// Stop both motors.
+    ///
+    /// Note that once this is called, the motors will not start again without re-initialization.
+    pub fn stop(&mut self) -> eyre::Result<()> {
+        if let Some(deadline) = &self.deadline {
+            deadline.clear();
+        }
+        self.backend().stop()
+    }
+
+    fn backend(&self) -> MutexGuard<'_, DriveBackend> {
+        // the backend holds no invariants a panicking holder could break
+        self.backend
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Drop for DifferentialDrive {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watchdog::ManualClock;
+    use crate::{Channel, RecordingPwm};
+
+    #[test]
+    fn test_mix() {
+        assert_eq!((1.0, 1.0), mix(Velocity::forward(), Angle::straight()));
+        assert_eq!((1.0, -1.0), mix(Velocity::none(), Angle::right()));
+        assert_eq!((0.0, 1.0), mix(Velocity::forward(), Angle::left()));
+        assert_eq!((0.5, -0.5), mix(Velocity::none(), 0.5.try_into().unwrap()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pwm_channels() {
+        let pwm = RecordingPwm::new();
+        let mut drive = DifferentialDrive::from_pwm(pwm.clone(), 1, 2).unwrap();
+        assert_eq!(4095, pwm.duty_cycle(Channel::C8));
+        assert_eq!(4095, pwm.duty_cycle(Channel::C13));
+
+        drive
+            .set_velocity(Velocity::none(), Angle::left())
+            .await
+            .unwrap();
+        // left wheel backwards, right wheel forwards
+        assert_eq!(4095, pwm.duty_cycle(Channel::C10));
+        assert_eq!(4095, pwm.duty_cycle(Channel::C11));
+
+        tokio::time::sleep(MAX_COMMAND_TIMEOUT).await;
+        assert_eq!(0, pwm.duty_cycle(Channel::C10));
+        assert_eq!(0, pwm.duty_cycle(Channel::C11));
+
+        drop(drive);
+        assert_eq!(0, pwm.duty_cycle(Channel::C8));
+        assert_eq!(0, pwm.duty_cycle(Channel::C13));
+
+        assert!(DifferentialDrive::from_pwm(RecordingPwm::new(), 1, 1).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_inverted_motors() {
+        let pwm = RecordingPwm::new();
+        let config = CarConfig {
+            invert_motor: true,
+            ..Default::default()
+        };
+        let mut drive = DifferentialDrive::from_pwm_config(pwm.clone(), &config, 1, 2).unwrap();
+        drive
+            .set_velocity(Velocity::forward(), Angle::straight())
+            .await
+            .unwrap();
+        // both wheels are driven backwards, which is forwards for motors wired the other way
+        assert_eq!(4095, pwm.duty_cycle(Channel::C10));
+        assert_eq!(4095, pwm.duty_cycle(Channel::C12));
+        assert_eq!(0, pwm.duty_cycle(Channel::C9));
+        assert_eq!(0, pwm.duty_cycle(Channel::C11));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_turn_on_the_spot() {
+        let sim = Simulation::new(Default::default());
+        let mut drive = DifferentialDrive::simulated(&sim);
+
+        drive
+            .move_for(Velocity::none(), Angle::left(), Duration::from_millis(100))
+            .await
+            .unwrap();
+        let pose = sim.pose();
+        assert!(pose.x.abs() < 1e-6 && pose.y.abs() < 1e-6);
+        assert!(pose.heading > 0.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tripped_watchdog_latches() {
+        let sim = Simulation::new(Default::default());
+        let mut drive = DifferentialDrive::simulated(&sim);
+        let (clock, hand) = ManualClock::new();
+        let watchdog = drive
+            .watchdog_with(Duration::from_millis(100), clock)
+            .unwrap();
+        hand.advance(Duration::from_millis(100));
+        hand.join();
+        assert!(watchdog.tripped());
+
+        let moved = drive.set_velocity(Velocity::forward(), Angle::straight());
+        assert!(moved.await.is_err());
+        let moved = drive.move_for(Velocity::none(), Angle::left(), Duration::from_millis(100));
+        assert!(moved.await.is_err());
+        assert_eq!(0.0, sim.pose().heading);
+        drive
+            .set_velocity(Velocity::none(), Angle::straight())
+            .await
+            .unwrap();
+    }
+}
@@ -1,20 +1,40 @@
-#[cfg(target_os = "linux")]
+pub mod calibration;
+pub mod config;
+mod differential;
+pub mod ramp;
 mod raw;
+pub mod sim;
+mod stepper;
+mod watchdog;
 
+pub use differential::DifferentialDrive;
+pub use pwm_pca9685::Channel;
+pub use raw::dc::DcKit;
+pub use raw::errors::MotorError;
+pub use raw::mock::{PwmWrite, RecordingPwm};
+pub use raw::pwm::PwmController;
+pub use raw::stepper::{StepDirection, StepStyle, StepperKit};
+pub use raw::Motor;
 #[cfg(target_os = "linux")]
-use linux_embedded_hal::I2cdev;
-#[cfg(target_os = "linux")]
-use pwm_pca9685::Pca9685;
-#[cfg(target_os = "linux")]
-use raw::dc::DcKit;
-#[cfg(target_os = "linux")]
+pub use raw::{default_i2c_bus, open_i2c_bus};
+pub use raw::{init_motor_pwm, init_pwm, init_servo_pwm};
+pub use stepper::Stepper;
+
+pub use calibration::ServoCalibration;
+pub use config::CarConfig;
+pub use ramp::{Ramp, RampShape};
 use raw::servo::ServoKit;
-#[cfg(target_os = "linux")]
-use raw::{init_motor_pwm, init_servo_pwm, Motor};
-#[cfg(target_os = "linux")]
+use sim::Simulation;
+use std::future::Future;
 use std::ops::Drop;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::time::Instant;
+pub use watchdog::Watchdog;
+use watchdog::{Clock, Deadline, Stop, SystemClock};
 
-use std::time::{Duration, Instant};
+/// Longest time the car keeps moving without a fresh command
+pub const MAX_COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(PartialEq, PartialOrd)]
 pub struct Velocity(f32);
@@ -38,15 +58,13 @@ impl Velocity {
 }
 
 impl TryFrom<f32> for Velocity {
-    type Error = eyre::Report;
+    type Error = MotorError;
 
-    fn try_from(value: f32) -> eyre::Result<Velocity> {
-        eyre::ensure!(
-            value >= Velocity::backward().0 && value <= Velocity::forward().0,
-            "velocity must be between {} and {}",
-            Velocity::backward().0,
-            Velocity::forward().0,
-        );
+    fn try_from(value: f32) -> Result<Velocity, MotorError> {
+        let (min, max) = (Velocity::backward().0, Velocity::forward().0);
+        if !(value >= min && value <= max) {
+            return Err(MotorError::out_of_range("velocity", value, min, max));
+        }
         Ok(Velocity(value))
     }
 }
@@ -57,60 +75,328 @@ impl Default for Velocity {
     }
 }
 
+/// The driver behind a [`MotorSocket`]
+enum MotorDriver {
+    Pwm {
+        dc_motor: DcKit,
+        dc_pwm: Box<dyn PwmController + Send>,
+        inverted: bool,
+    },
+    Sim(Simulation),
+}
+
+/// A [`MotorDriver`] and the throttle it was last set to
+struct MotorBackend {
+    driver: MotorDriver,
+    throttle: f32,
+    /// Bumped whenever the throttle is set, so that a running ramp knows it was superseded
+    generation: u64,
+    /// Set once a [`Watchdog`] tripped, after which the motor only ever stops
+    halted: bool,
+}
+
+impl Stop for MotorBackend {
+    fn stop(&mut self) -> eyre::Result<()> {
+        self.generation += 1;
+        self.throttle = 0.0;
+        match &mut self.driver {
+            MotorDriver::Pwm {
+                dc_motor, dc_pwm, ..
+            } => dc_motor.stop(dc_pwm)?,
+            MotorDriver::Sim(sim) => sim.set_throttle(0.0),
+        }
+        Ok(())
+    }
+
+    fn halt(&mut self) -> eyre::Result<()> {
+        self.halted = true;
+        self.stop()
+    }
+}
+
+impl MotorBackend {
+    /// Sets the throttle in the range [-1.0, 1.0], cancelling any running ramp
+    fn set_throttle(&mut self, throttle: f32) -> eyre::Result<()> {
+        self.generation += 1;
+        self.write(throttle)
+    }
+
+    fn write(&mut self, throttle: f32) -> eyre::Result<()> {
+        self.check(throttle)?;
+        match &mut self.driver {
+            MotorDriver::Pwm {
+                dc_motor,
+                dc_pwm,
+                inverted,
+            } => {
+                let throttle = if *inverted { -throttle } else { throttle };
+                dc_motor.set_throttle(dc_pwm, throttle)?
+            }
+            MotorDriver::Sim(sim) => sim.set_throttle(throttle),
+        }
+        self.throttle = throttle;
+        Ok(())
+    }
+
+    /// Fails to start the motor once it was halted
+    fn check(&self, throttle: f32) -> eyre::Result<()> {
+        eyre::ensure!(
+            throttle == 0.0 || !self.halted,
+            "the watchdog stopped the motor for good"
+        );
+        Ok(())
+    }
+
+    /// Moves the throttle to `target` along the ramp
+    ///
+    /// Supersedes running ramps right away, and returns early once the throttle is set by
+    /// anybody else.
+    fn ramp(
+        backend: Arc<Mutex<Self>>,
+        ramp: Ramp,
+        target: f32,
+    ) -> eyre::Result<impl Future<Output = eyre::Result<()>> + Send + 'static> {
+        let (from, generation) = {
+            let mut backend = backend.lock().unwrap_or_else(|p| p.into_inner());
+            backend.check(target)?;
+            backend.generation += 1;
+            (backend.throttle, backend.generation)
+        };
+        Ok(async move {
+            for throttle in ramp.steps(from, target) {
+                tokio::time::sleep(ramp.interval).await;
+                let mut backend = backend.lock().unwrap_or_else(|p| p.into_inner());
+                if backend.generation != generation {
+                    return Ok(());
+                }
+                backend.write(throttle)?;
+            }
+            Ok(())
+        })
+    }
+}
+
 /// The motor of the car
 pub struct MotorSocket {
-    #[cfg(target_os = "linux")]
-    dc_motor: DcKit,
-    #[cfg(target_os = "linux")]
-    dc_pwm: Pca9685<I2cdev>,
+    backend: Arc<Mutex<MotorBackend>>,
     cooldown_since: Instant,
+    command_timeout: Duration,
+    deadline: Option<Deadline>,
+    ramp: Option<Ramp>,
 }
 
 impl MotorSocket {
-    /// Open the pins to talk to the car's motor
-    pub async fn open() -> eyre::Result<Self> {
-        #[cfg(target_os = "linux")]
-        let mut dc_pwm = init_motor_pwm(None)?;
+    /// Open the pins to talk to the car's motor, as wired according to the config
+    ///
+    /// Uses the global [`Simulation`] instead if the simulation backend is
+    /// [enabled](sim::enabled).
+    pub async fn open(config: &CarConfig) -> eyre::Result<Self> {
+        Self::open_motor(config, config.motor).await
+    }
+
+    /// Open the pins to talk to the DC motor wired to the given terminal (1-4) of the Motor HAT
+    ///
+    /// If the simulation backend is [enabled](sim::enabled), the car's motor drives the
+    /// global [`Simulation`] and all other motors are backed by a [`RecordingPwm`].
+    pub async fn open_motor(config: &CarConfig, index: u8) -> eyre::Result<Self> {
+        config.validate()?;
+        let config = CarConfig {
+            motor: index,
+            ..config.clone()
+        };
+        if sim::enabled() {
+            Motor::dc(index)?;
+            if index == 1 {
+                return Ok(Self::simulated(Simulation::global()));
+            }
+            return Self::from_pwm_config(RecordingPwm::new(), &config);
+        }
+
         #[cfg(target_os = "linux")]
-        let dc_motor = DcKit::try_new(&mut dc_pwm, Motor::Motor1)?;
-        Ok(MotorSocket {
-            #[cfg(target_os = "linux")]
-            dc_pwm,
-            #[cfg(target_os = "linux")]
+        {
+            Self::from_pwm_config(config.motor_pwm()?, &config)
+        }
+        #[cfg(not(target_os = "linux"))]
+        unreachable!("the simulation is always enabled off linux")
+    }
+
+    /// Drive the motor through the given, already initialized, PWM controller
+    pub fn from_pwm(pwm: impl PwmController + Send + 'static) -> eyre::Result<Self> {
+        Self::from_pwm_config(pwm, &CarConfig::default())
+    }
+
+    /// Drive the DC motor wired to the given terminal (1-4) through the given, already
+    /// initialized, PWM controller
+    pub fn from_pwm_motor(
+        pwm: impl PwmController + Send + 'static,
+        index: u8,
+    ) -> eyre::Result<Self> {
+        let config = CarConfig {
+            motor: index,
+            ..Default::default()
+        };
+        Self::from_pwm_config(pwm, &config)
+    }
+
+    /// Drive the motor through the given, already initialized, PWM controller, as wired
+    /// according to the config
+    pub fn from_pwm_config(
+        pwm: impl PwmController + Send + 'static,
+        config: &CarConfig,
+    ) -> eyre::Result<Self> {
+        let mut dc_pwm: Box<dyn PwmController + Send> = Box::new(pwm);
+        let dc_motor = DcKit::try_new(&mut dc_pwm, Motor::dc(config.motor)?)?;
+        Ok(Self::with_driver(MotorDriver::Pwm {
             dc_motor,
+            dc_pwm,
+            inverted: config.invert_motor,
+        }))
+    }
+
+    /// Drive the given simulated car instead of the hardware
+    pub fn simulated(sim: &Simulation) -> Self {
+        Self::with_driver(MotorDriver::Sim(sim.clone()))
+    }
+
+    fn with_driver(driver: MotorDriver) -> Self {
+        let backend = MotorBackend {
+            driver,
+            throttle: 0.0,
+            generation: 0,
+            halted: false,
+        };
+        MotorSocket {
+            backend: Arc::new(Mutex::new(backend)),
             cooldown_since: Instant::now(),
-        })
+            command_timeout: Duration::from_millis(500),
+            deadline: None,
+            ramp: None,
+        }
+    }
+
+    /// Ramp throttle changes up and down rather than jumping to the new throttle
+    ///
+    /// Stopping, be it through [`stop`](Self::stop), a [`Watchdog`] or a command timeout,
+    /// is always immediate.
+    pub fn set_ramp(&mut self, ramp: Option<Ramp>) -> eyre::Result<()> {
+        if let Some(ramp) = &ramp {
+            ramp.validate()?;
+        }
+        self.ramp = ramp;
+        Ok(())
     }
 
     /// Set the velocity of the motor
     ///
-    /// Car will not move for longer than 1 second.
+    /// Car will not move for longer than 1 second. With a [ramp](Self::set_ramp), ramping
+    /// up counts towards that second and ramping down comes on top.
     pub async fn move_for(&mut self, velocity: Velocity, max_dur: Duration) -> eyre::Result<()> {
+        if let Some(deadline) = &self.deadline {
+            deadline.clear();
+        }
         if let Some(left) = Duration::from_secs(4).checked_sub(self.cooldown_since.elapsed()) {
             tokio::time::sleep(left).await;
         }
-        // -1 because we wired it backwards
-        #[cfg(target_os = "linux")]
-        self.dc_motor
-            .set_throttle(&mut self.dc_pwm, -1. * velocity.into_inner() / 100.0)?;
-        #[cfg(not(target_os = "linux"))]
-        let _ = velocity; // just for unused variables
-        let actual_dur = std::cmp::min(Duration::from_secs(1), max_dur);
-        tokio::time::sleep(actual_dur).await;
-        #[cfg(target_os = "linux")]
-        self.dc_motor
-            .set_throttle(&mut self.dc_pwm, Velocity::none().into_inner() / 100.0)?;
+        let actual_dur = std::cmp::min(MAX_COMMAND_TIMEOUT, max_dur);
+        let until = Instant::now() + actual_dur;
+        let throttle = velocity.into_inner() / 100.0;
+        match self.ramp {
+            Some(ramp) => {
+                let ramp_up = MotorBackend::ramp(self.backend.clone(), ramp, throttle)?;
+                if let Ok(ramped) = tokio::time::timeout_at(until, ramp_up).await {
+                    ramped?;
+                }
+            }
+            None => self.backend().set_throttle(throttle)?,
+        }
+        tokio::time::sleep_until(until).await;
+        let none = Velocity::none().into_inner() / 100.0;
+        match self.ramp {
+            Some(ramp) => MotorBackend::ramp(self.backend.clone(), ramp, none)?.await?,
+            None => self.backend().set_throttle(none)?,
+        }
         self.cooldown_since = Instant::now();
         Ok(())
     }
 
+    /// Set the velocity of the motor and return right away
+    ///
+    /// The motor keeps running until the next call, or until no new velocity has been
+    /// set for the [command timeout](Self::set_command_timeout). This allows steering
+    /// the car while it moves. With a [ramp](Self::set_ramp), the motor ramps to the new
+    /// velocity in the background.
+    pub async fn set_velocity(&mut self, velocity: Velocity) -> eyre::Result<()> {
+        let throttle = velocity.into_inner() / 100.0;
+        match self.ramp {
+            Some(ramp) => {
+                let ramping = MotorBackend::ramp(self.backend.clone(), ramp, throttle)?;
+                tokio::spawn(async move {
+                    if let Err(e) = ramping.await {
+                        tracing::error!("failed to ramp the throttle: {e:?}");
+                    }
+                });
+            }
+            None => self.backend().set_throttle(throttle)?,
+        }
+
+        let deadline = self.deadline.get_or_insert_with(|| {
+            let backend = Arc::clone(&self.backend);
+            Deadline::spawn(move || {
+                tracing::warn!("no velocity command within the timeout, stopping the car");
+                let mut backend = backend.lock().unwrap_or_else(|p| p.into_inner());
+                if let Err(e) = backend.set_throttle(0.0) {
+                    tracing::error!("failed to stop the car: {e:?}");
+                }
+            })
+        });
+        if throttle == 0.0 {
+            deadline.clear();
+        } else {
+            deadline.renew(self.command_timeout);
+        }
+        Ok(())
+    }
+
+    /// Configure how long the motor keeps running after the last
+    /// [`set_velocity`](Self::set_velocity)
+    ///
+    /// The timeout is capped at [`MAX_COMMAND_TIMEOUT`] and defaults to 500ms.
+    pub fn set_command_timeout(&mut self, timeout: Duration) {
+        self.command_timeout = std::cmp::min(MAX_COMMAND_TIMEOUT, timeout);
+    }
+
+    /// Start a dead-man [`Watchdog`] for this motor
+    ///
+    /// The motor is stopped unless [`Watchdog::heartbeat`] is called at least once per
+    /// `timeout`. Once the watchdog tripped, [`set_velocity`](Self::set_velocity) and
+    /// [`move_for`](Self::move_for) fail.
+    pub fn watchdog(&self, timeout: Duration) -> eyre::Result<Watchdog> {
+        self.watchdog_with(timeout, SystemClock)
+    }
+
+    pub(crate) fn watchdog_with(
+        &self,
+        timeout: Duration,
+        clock: impl Clock,
+    ) -> eyre::Result<Watchdog> {
+        Watchdog::spawn(self.backend.clone(), timeout, clock)
+    }
+
     /// Stop the motor.
     ///
     /// Note that once this is called, the motor will not start again without re-initialization.
     pub fn stop(&mut self) -> eyre::Result<()> {
-        #[cfg(target_os = "linux")]
-        self.dc_motor.stop(&mut self.dc_pwm)?;
-        Ok(())
+        if let Some(deadline) = &self.deadline {
+            deadline.clear();
+        }
+        self.backend().stop()
+    }
+
+    fn backend(&self) -> MutexGuard<'_, MotorBackend> {
+        // the backend holds no invariants a panicking holder could break
+        self.backend
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
     }
 }
 
@@ -120,29 +406,140 @@ impl Drop for MotorSocket {
     }
 }
 
+/// The driver behind a [`WheelOrientation`]
+enum ServoBackend {
+    Pwm {
+        servo: ServoKit,
+        servo_pwm: Box<dyn PwmController + Send>,
+    },
+    Sim(Simulation),
+}
+
+impl ServoBackend {
+    fn set(&mut self, angle: Angle, calibration: &ServoCalibration) -> eyre::Result<()> {
+        match self {
+            ServoBackend::Pwm { servo, servo_pwm } => {
+                servo.set_angle(servo_pwm, calibration.degrees(angle))?
+            }
+            // the simulated car steers perfectly straight
+            ServoBackend::Sim(sim) => sim.set_steering(angle.into_inner()),
+        }
+        Ok(())
+    }
+
+    /// Turns the servo to the given angle in degrees, bypassing any calibration
+    fn set_degrees(&mut self, degrees: f32) -> eyre::Result<()> {
+        match self {
+            ServoBackend::Pwm { servo, servo_pwm } => servo.set_angle(servo_pwm, degrees)?,
+            ServoBackend::Sim(sim) => sim.set_steering(((90.0 - degrees) / 90.0).clamp(-1.0, 1.0)),
+        }
+        Ok(())
+    }
+}
+
 /// Discrete wrapper of the wheel orientation
+///
+/// Steering is corrected by a [`ServoCalibration`], which [`WheelOrientation::new`] loads
+/// from the [calibration file](calibration::default_path) if there is one.
 pub struct WheelOrientation {
-    #[cfg(target_os = "linux")]
-    servo: ServoKit,
-    #[cfg(target_os = "linux")]
-    servo_pwm: Pca9685<I2cdev>,
+    backend: ServoBackend,
+    calibration: ServoCalibration,
     current: Angle,
+    ramp: Option<Ramp>,
 }
 
 impl WheelOrientation {
-    /// Open the pins to talk to the car's servo
-    pub async fn new() -> eyre::Result<Self> {
-        #[cfg(target_os = "linux")]
-        let mut servo_pwm = init_servo_pwm(None)?;
+    /// Open the pins to talk to the car's servo, as wired according to the config
+    ///
+    /// Uses the global [`Simulation`] instead if the simulation backend is
+    /// [enabled](sim::enabled).
+    pub async fn new(config: &CarConfig) -> eyre::Result<Self> {
+        config.validate()?;
+        if sim::enabled() {
+            return Ok(Self::simulated(Simulation::global()));
+        }
+
         #[cfg(target_os = "linux")]
-        let servo = ServoKit::try_new(&mut servo_pwm, Motor::Servo)?;
-        Ok(WheelOrientation {
-            #[cfg(target_os = "linux")]
-            servo_pwm,
-            #[cfg(target_os = "linux")]
-            servo,
+        {
+            let mut wheels = Self::from_pwm_config(config.servo_pwm()?, config)?;
+            wheels.set_calibration(ServoCalibration::load_default()?)?;
+            Ok(wheels)
+        }
+        #[cfg(not(target_os = "linux"))]
+        unreachable!("the simulation is always enabled off linux")
+    }
+
+    /// Steer the wheels through the given, already initialized, PWM controller
+    pub fn from_pwm(pwm: impl PwmController + Send + 'static) -> eyre::Result<Self> {
+        Self::from_pwm_config(pwm, &CarConfig::default())
+    }
+
+    /// Steer the wheels through the given, already initialized, PWM controller, with the
+    /// servo plugged into the channel given by the config
+    pub fn from_pwm_config(
+        pwm: impl PwmController + Send + 'static,
+        config: &CarConfig,
+    ) -> eyre::Result<Self> {
+        let channel = Channel::try_from(config.servo_channel).map_err(|()| {
+            eyre::eyre!(
+                "servo channel must be between 0 and 15, not {}",
+                config.servo_channel
+            )
+        })?;
+        let mut servo_pwm: Box<dyn PwmController + Send> = Box::new(pwm);
+        let servo = ServoKit::with_channel(&mut servo_pwm, channel)?;
+        Ok(Self::with_backend(ServoBackend::Pwm { servo, servo_pwm }))
+    }
+
+    /// Steer the given simulated car instead of the hardware
+    pub fn simulated(sim: &Simulation) -> Self {
+        Self::with_backend(ServoBackend::Sim(sim.clone()))
+    }
+
+    fn with_backend(backend: ServoBackend) -> Self {
+        WheelOrientation {
+            backend,
+            calibration: Default::default(),
             current: Default::default(),
-        })
+            ramp: None,
+        }
+    }
+
+    /// Turn the wheels gradually rather than jumping to the new angle
+    pub fn set_ramp(&mut self, ramp: Option<Ramp>) -> eyre::Result<()> {
+        if let Some(ramp) = &ramp {
+            ramp.validate()?;
+        }
+        self.ramp = ramp;
+        Ok(())
+    }
+
+    /// The calibration applied when steering
+    pub fn calibration(&self) -> &ServoCalibration {
+        &self.calibration
+    }
+
+    /// Replace the calibration applied when steering
+    pub fn set_calibration(&mut self, calibration: ServoCalibration) -> eyre::Result<()> {
+        calibration.validate()?;
+        self.calibration = calibration;
+        self.backend.set(self.current, &self.calibration)
+    }
+
+    /// Interactively measure a new calibration, see [`calibration::calibrate`]
+    ///
+    /// The new calibration is applied, but not saved.
+    pub fn calibrate(
+        &mut self,
+        input: impl std::io::BufRead,
+        output: impl std::io::Write,
+    ) -> eyre::Result<ServoCalibration> {
+        let backend = &mut self.backend;
+        let calibration =
+            calibration::calibrate(|degrees| backend.set_degrees(degrees), input, output)?;
+        self.current = Angle::straight();
+        self.set_calibration(calibration.clone())?;
+        Ok(calibration)
     }
 
     /// Retrieve the current orientation of the wheels
@@ -151,21 +548,25 @@ impl WheelOrientation {
     }
 
     /// Set the wheel orientation to a specific value
+    ///
+    /// With a [ramp](Self::set_ramp), this returns once the wheels reached the angle.
     pub async fn set(&mut self, angle: Angle) -> eyre::Result<()> {
+        if let Some(ramp) = self.ramp {
+            for step in ramp.steps(self.current.into_inner(), angle.into_inner()) {
+                tokio::time::sleep(ramp.interval).await;
+                self.backend.set(Angle(step), &self.calibration)?;
+                self.current = Angle(step);
+            }
+            return Ok(());
+        }
         self.current = angle;
-        let raw: f32 = (-90.0 * angle.into_inner() + 90.0).try_into()?;
-        #[cfg(not(target_os = "linux"))]
-        let _ = raw;
-        #[cfg(target_os = "linux")]
-        self.servo.set_angle(&mut self.servo_pwm, raw)?;
-        Ok(())
+        self.backend.set(angle, &self.calibration)
     }
 }
 
 impl Drop for WheelOrientation {
     fn drop(&mut self) {
-        #[cfg(target_os = "linux")]
-        let _ = self.servo.set_angle(&mut self.servo_pwm, 90.0);
+        let _ = self.backend.set(Angle::straight(), &self.calibration);
     }
 }
 
@@ -191,15 +592,13 @@ impl Angle {
 }
 
 impl TryFrom<f32> for Angle {
-    type Error = eyre::Report;
+    type Error = MotorError;
 
-    fn try_from(value: f32) -> eyre::Result<Angle> {
-        eyre::ensure!(
-            value >= Angle::left().0 && value <= Angle::right().0,
-            "angle must be between {} and {}",
-            Angle::left().0,
-            Angle::right().0,
-        );
+    fn try_from(value: f32) -> Result<Angle, MotorError> {
+        let (min, max) = (Angle::left().0, Angle::right().0);
+        if !(value >= min && value <= max) {
+            return Err(MotorError::out_of_range("angle", value, min, max));
+        }
         Ok(Angle(value))
     }
 }
@@ -209,3 +608,194 @@ impl Default for Angle {
         Self::straight()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimConfig;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_motor_socket_pwm() {
+        let pwm = RecordingPwm::new();
+        let mut motor = MotorSocket::from_pwm(pwm.clone()).unwrap();
+
+        let moving = tokio::spawn(async move {
+            motor
+                .move_for(Velocity::forward(), Duration::from_secs(1))
+                .await
+                .unwrap();
+            motor
+        });
+        tokio::time::sleep(Duration::from_millis(4500)).await;
+        // we wired it backwards
+        assert_eq!(4095, pwm.duty_cycle(Channel::C10));
+        assert_eq!(0, pwm.duty_cycle(Channel::C9));
+
+        let motor = moving.await.unwrap();
+        assert_eq!(0, pwm.duty_cycle(Channel::C10));
+        drop(motor);
+        assert_eq!(0, pwm.duty_cycle(Channel::C8));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_set_velocity_timeout() {
+        let pwm = RecordingPwm::new();
+        let mut motor = MotorSocket::from_pwm(pwm.clone()).unwrap();
+        motor.set_command_timeout(Duration::from_millis(200));
+
+        motor.set_velocity(Velocity::forward()).await.unwrap();
+        assert_eq!(4095, pwm.duty_cycle(Channel::C10));
+
+        // fresh commands keep the car moving
+        for _ in 0..5 {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            assert_eq!(4095, pwm.duty_cycle(Channel::C10));
+            motor.set_velocity(Velocity::forward()).await.unwrap();
+        }
+
+        // reversing does not leave the other direction energized
+        motor.set_velocity(Velocity::backward()).await.unwrap();
+        assert_eq!(0, pwm.duty_cycle(Channel::C10));
+        assert_eq!(4095, pwm.duty_cycle(Channel::C9));
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(0, pwm.duty_cycle(Channel::C9));
+        assert_eq!(0, pwm.duty_cycle(Channel::C10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_set_velocity_sim() {
+        let sim = Simulation::new(Default::default());
+        let mut motor = MotorSocket::simulated(&sim);
+        let mut wheels = WheelOrientation::simulated(&sim);
+
+        motor.set_velocity(Velocity::forward()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        wheels.set(Angle::left()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(sim.speed() > 0.0);
+        assert!(sim.pose().heading > 0.0);
+
+        tokio::time::sleep(MAX_COMMAND_TIMEOUT).await;
+        assert_eq!(0.0, sim.speed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_motor_by_index() {
+        let pwm = RecordingPwm::new();
+        let mut motor = MotorSocket::from_pwm_motor(pwm.clone(), 3).unwrap();
+        assert_eq!(4095, pwm.duty_cycle(Channel::C2));
+
+        motor.set_velocity(Velocity::backward()).await.unwrap();
+        assert_eq!(4095, pwm.duty_cycle(Channel::C3));
+        assert_eq!(0, pwm.duty_cycle(Channel::C4));
+
+        assert!(MotorSocket::from_pwm_motor(RecordingPwm::new(), 5).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wheel_orientation_pwm() {
+        let pwm = RecordingPwm::new();
+        let mut wheels = WheelOrientation::from_pwm(pwm.clone()).unwrap();
+
+        wheels.set(Angle::left()).await.unwrap();
+        let left = pwm.duty_cycle(Channel::C0);
+        wheels.set(Angle::right()).await.unwrap();
+        let right = pwm.duty_cycle(Channel::C0);
+        assert!(left > right);
+
+        drop(wheels);
+        assert_eq!((left + right) / 2, pwm.duty_cycle(Channel::C0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ramped_velocity() {
+        let sim = Simulation::new(Default::default());
+        let mut motor = MotorSocket::simulated(&sim);
+        motor.set_ramp(Some(Ramp::linear(2.0))).unwrap();
+        motor.set_command_timeout(MAX_COMMAND_TIMEOUT);
+
+        motor.set_velocity(Velocity::forward()).await.unwrap();
+        assert_eq!(0.0, sim.speed());
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let halfway = sim.speed() / SimConfig::default().max_speed;
+        assert!((0.4..=0.6).contains(&halfway), "{halfway}");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(SimConfig::default().max_speed, sim.speed());
+
+        // a new command takes over from wherever the throttle is
+        motor.set_velocity(Velocity::none()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(sim.speed() < SimConfig::default().max_speed);
+        motor.set_velocity(Velocity::forward()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(SimConfig::default().max_speed, sim.speed());
+
+        // stopping is immediate
+        motor.set_velocity(Velocity::none()).await.unwrap();
+        motor.stop().unwrap();
+        assert_eq!(0.0, sim.speed());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(0.0, sim.speed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ramped_steering() {
+        let sim = Simulation::new(Default::default());
+        let mut wheels = WheelOrientation::simulated(&sim);
+        wheels.set_ramp(Some(Ramp::s_curve(4.0))).unwrap();
+
+        let start = Instant::now();
+        wheels.set(Angle::right()).await.unwrap();
+        assert_eq!(Duration::from_millis(380), start.elapsed());
+        assert_eq!(-SimConfig::default().max_steering, sim.steering());
+        assert!(wheels.set_ramp(Some(Ramp::linear(-1.0))).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rewired_car() {
+        let config = CarConfig {
+            motor: 2,
+            invert_motor: false,
+            servo_channel: 3,
+            ..Default::default()
+        };
+
+        let pwm = RecordingPwm::new();
+        let mut motor = MotorSocket::from_pwm_config(pwm.clone(), &config).unwrap();
+        motor.set_velocity(Velocity::forward()).await.unwrap();
+        // motor 2 forwards without the inversion
+        assert_eq!(4095, pwm.duty_cycle(Channel::C13));
+        assert_eq!(4095, pwm.duty_cycle(Channel::C11));
+        assert_eq!(0, pwm.duty_cycle(Channel::C12));
+
+        let pwm = RecordingPwm::new();
+        let mut wheels = WheelOrientation::from_pwm_config(pwm.clone(), &config).unwrap();
+        wheels.set(Angle::left()).await.unwrap();
+        assert!(pwm.duty_cycle(Channel::C3) > 0);
+        assert_eq!(0, pwm.duty_cycle(Channel::C0));
+    }
+
+    #[tokio::test]
+    async fn test_wheel_orientation_calibration() {
+        let pwm = RecordingPwm::new();
+        let mut wheels = WheelOrientation::from_pwm(pwm.clone()).unwrap();
+        wheels.set(Angle::straight()).await.unwrap();
+        let straight = pwm.duty_cycle(Channel::C0);
+
+        wheels
+            .set_calibration(ServoCalibration {
+                center_offset: -10.0,
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(pwm.duty_cycle(Channel::C0) < straight);
+
+        let invalid = ServoCalibration {
+            left_limit: 200.0,
+            ..Default::default()
+        };
+        assert!(wheels.set_calibration(invalid).is_err());
+        assert_eq!(-10.0, wheels.calibration().center_offset);
+    }
+}
@@ -0,0 +1,220 @@
+//! Acceleration profiles that spread throttle and steering changes over time.
+//!
+//! Jumping from standstill to full throttle makes the car skid, which in turn makes the
+//! drone lose track of its LED. A [`Ramp`] limits how fast the throttle of a
+//! [`MotorSocket`](crate::MotorSocket) or the angle of a
+//! [`WheelOrientation`](crate::WheelOrientation) changes. Both values range over [-1, 1],
+//! so a rate of 2 per second goes from full reverse to full forward in one second.
+
+use std::time::Duration;
+
+/// The shape of a [`Ramp`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RampShape {
+    /// Changes at a constant rate.
+    Linear,
+    /// Eases in and out, changing fastest half way through.
+    SCurve,
+}
+
+/// Limits how fast a throttle or an angle changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ramp {
+    /// The shape of the ramp.
+    pub shape: RampShape,
+    /// Largest change per second while moving away from zero.
+    pub acceleration: f32,
+    /// Largest change per second while moving towards zero.
+    pub deceleration: f32,
+    /// How often the value is updated along the way.
+    pub interval: Duration,
+}
+
+impl Ramp {
+    /// A ramp changing at a constant `rate` per second.
+    pub fn linear(rate: f32) -> Self {
+        Self {
+            shape: RampShape::Linear,
+            acceleration: rate,
+            deceleration: rate,
+            interval: Duration::from_millis(20),
+        }
+    }
+
+    /// A ramp easing in and out, changing at most `rate` per second.
+    pub fn s_curve(rate: f32) -> Self {
+        Self {
+            shape: RampShape::SCurve,
+            ..Self::linear(rate)
+        }
+    }
+
+    /// Use a different rate when moving towards zero, e.g. to brake harder than the car
+    /// accelerates.
+    pub fn with_deceleration(self, rate: f32) -> Self {
+        Self {
+            deceleration: rate,
+            ..self
+        }
+    }
+
+    /// Update the value at the given interval rather than every 20ms.
+    pub fn with_interval(self, interval: Duration) -> Self {
+        Self { interval, ..self }
+    }
+
+    /// Checks that the rates are positive and the interval is not zero.
+    pub fn validate(&self) -> eyre::Result<()> {
+        for (name, rate) in [
+            ("acceleration", self.acceleration),
+            ("deceleration", self.deceleration),
+        ] {
+            eyre::ensure!(
+                rate.is_finite() && rate > 0.0,
+                "{name} must be positive, not {rate}"
+            );
+        }
+        eyre::ensure!(!self.interval.is_zero(), "interval must not be zero");
+        Ok(())
+    }
+
+    /// How long it takes to go from `from` to `to`.
+    ///
+    /// A reversal brakes down to zero before speeding up the other way.
+    pub fn duration(&self, from: f32, to: f32) -> Duration {
+        if crosses_zero(from, to) {
+            return self
+                .segment_duration(from, 0.0)
+                .saturating_add(self.segment_duration(0.0, to));
+        }
+        self.segment_duration(from, to)
+    }
+
+    /// The value `elapsed` into the ramp from `from` to `to`.
+    pub fn value_at(&self, from: f32, to: f32, elapsed: Duration) -> f32 {
+        if crosses_zero(from, to) {
+            let braking = self.segment_duration(from, 0.0);
+            return match elapsed.checked_sub(braking) {
+                Some(elapsed) => self.segment_value(0.0, to, elapsed),
+                None => self.segment_value(from, 0.0, elapsed),
+            };
+        }
+        self.segment_value(from, to, elapsed)
+    }
+
+    /// How long it takes to go from `from` to `to`, both on the same side of zero.
+    fn segment_duration(&self, from: f32, to: f32) -> Duration {
+        let rate = if to.abs() < from.abs() {
+            self.deceleration
+        } else {
+            self.acceleration
+        };
+        let linear = (to - from).abs() / rate;
+        let secs = match self.shape {
+            RampShape::Linear => linear,
+            // the steepest slope of the S-curve is 1.5 times the average one
+            RampShape::SCurve => 1.5 * linear,
+        };
+        // an invalid rate never gets there
+        Duration::try_from_secs_f32(secs).unwrap_or(Duration::MAX)
+    }
+
+    /// The value `elapsed` into going from `from` to `to`, both on the same side of zero.
+    fn segment_value(&self, from: f32, to: f32, elapsed: Duration) -> f32 {
+        let duration = self.segment_duration(from, to);
+        if elapsed >= duration {
+            return to;
+        }
+        let progress = elapsed.as_secs_f32() / duration.as_secs_f32();
+        let progress = match self.shape {
+            RampShape::Linear => progress,
+            RampShape::SCurve => progress * progress * (3.0 - 2.0 * progress),
+        };
+        from + (to - from) * progress
+    }
+
+    /// The values to set after each [`interval`](Self::interval) to go from `from` to `to`.
+    ///
+    /// The last value is `to`, and there are none if the two are equal.
+    pub(crate) fn steps(&self, from: f32, to: f32) -> impl Iterator<Item = f32> + Send {
+        let ramp = *self;
+        let duration = self.duration(from, to);
+        let count = (duration.as_secs_f64() / self.interval.as_secs_f64()).ceil() as u32;
+        let count = if from == to { 0 } else { count.max(1) };
+        (1..=count).map(move |i| ramp.value_at(from, to, ramp.interval * i))
+    }
+}
+
+/// Whether going from `from` to `to` reverses direction.
+fn crosses_zero(from: f32, to: f32) -> bool {
+    from * to < 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linear() {
+        let ramp = Ramp::linear(2.0).with_interval(Duration::from_millis(100));
+        assert_eq!(Duration::from_millis(500), ramp.duration(0.0, 1.0));
+        assert_eq!(0.5, ramp.value_at(0.0, 1.0, Duration::from_millis(250)));
+        let steps: Vec<_> = ramp.steps(0.0, 1.0).collect();
+        assert_eq!(5, steps.len());
+        assert!((steps[0] - 0.2).abs() < 1e-6);
+        assert_eq!(Some(&1.0), steps.last());
+        assert_eq!(0, ramp.steps(0.5, 0.5).count());
+    }
+
+    #[test]
+    fn test_deceleration() {
+        let ramp = Ramp::linear(1.0).with_deceleration(4.0);
+        assert_eq!(Duration::from_secs(1), ramp.duration(0.0, -1.0));
+        assert_eq!(Duration::from_millis(250), ramp.duration(-1.0, 0.0));
+    }
+
+    #[test]
+    fn test_reversal() {
+        let ramp = Ramp::linear(1.0).with_deceleration(4.0);
+        // brakes to zero in 250ms, then speeds up the other way in a second
+        assert_eq!(Duration::from_millis(1250), ramp.duration(1.0, -1.0));
+        assert_eq!(0.5, ramp.value_at(1.0, -1.0, Duration::from_millis(125)));
+        assert_eq!(0.0, ramp.value_at(1.0, -1.0, Duration::from_millis(250)));
+        assert_eq!(-0.5, ramp.value_at(1.0, -1.0, Duration::from_millis(750)));
+        assert_eq!(-1.0, ramp.value_at(1.0, -1.0, Duration::from_millis(1250)));
+        let steps: Vec<_> = ramp.steps(1.0, -1.0).collect();
+        assert_eq!(63, steps.len());
+        assert_eq!(Some(&-1.0), steps.last());
+    }
+
+    #[test]
+    fn test_s_curve() {
+        let ramp = Ramp::s_curve(1.0);
+        assert_eq!(Duration::from_millis(1500), ramp.duration(0.0, 1.0));
+        let quarter = ramp.value_at(0.0, 1.0, Duration::from_millis(375));
+        let half = ramp.value_at(0.0, 1.0, Duration::from_millis(750));
+        assert!(quarter < 0.25);
+        assert!((half - 0.5).abs() < 1e-6);
+
+        // never faster than the rate
+        let values: Vec<_> = std::iter::once(0.0).chain(ramp.steps(0.0, 1.0)).collect();
+        let interval = ramp.interval.as_secs_f32();
+        assert!(values
+            .windows(2)
+            .all(|pair| pair[1] - pair[0] <= interval + 1e-6));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Ramp::linear(1.0).validate().is_ok());
+        assert!(Ramp::linear(0.0).validate().is_err());
+        assert!(Ramp::linear(1.0)
+            .with_deceleration(-1.0)
+            .validate()
+            .is_err());
+        assert!(Ramp::linear(1.0)
+            .with_interval(Duration::ZERO)
+            .validate()
+            .is_err());
+    }
+}
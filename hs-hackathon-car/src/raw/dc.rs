@@ -1,9 +1,8 @@
 use crate::raw::errors::MotorError;
+use crate::raw::pwm::PwmController;
 use crate::raw::Motor;
-use hal::I2cdev;
 use lazy_static::lazy_static;
-use linux_embedded_hal as hal;
-use pwm_pca9685::{Channel, Pca9685};
+use pwm_pca9685::Channel;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 
@@ -62,22 +61,21 @@ pub struct DcKit {
 
 impl DcKit {
     /// Attempts to initialize a DC motor.
-    pub fn try_new(pwm: &mut Pca9685<I2cdev>, motor: Motor) -> Result<Self, MotorError> {
+    pub fn try_new<P: PwmController + ?Sized>(
+        pwm: &mut P,
+        motor: Motor,
+    ) -> Result<Self, MotorError> {
         let channels = DC_CHANNEL_MAP
             .get(&motor)
-            .ok_or(MotorError::InvalidMotorError)?;
+            .ok_or(MotorError::InvalidMotor(motor))?;
 
         // Set the channels we'll be using to on at 0.
-        pwm.set_channel_on(channels.ref_channel, 0)
-            .map_err(|_| MotorError::ChannelError)?;
-        pwm.set_channel_on(channels.forward_channel, 0)
-            .map_err(|_| MotorError::ChannelError)?;
-        pwm.set_channel_on(channels.backward_channel, 0)
-            .map_err(|_| MotorError::ChannelError)?;
+        pwm.set_channel_on(channels.ref_channel, 0)?;
+        pwm.set_channel_on(channels.forward_channel, 0)?;
+        pwm.set_channel_on(channels.backward_channel, 0)?;
 
         // Set the reference channel to run at full blast.
-        pwm.set_channel_off(channels.ref_channel, 4095)
-            .map_err(|_| MotorError::ChannelError)?;
+        pwm.set_channel_off(channels.ref_channel, 4095)?;
         Ok(Self {
             channels: *channels,
         })
@@ -85,44 +83,83 @@ impl DcKit {
 
     /// Sets the throttle for the motor. Valid throttle values are in the
     /// range [-1.0, 1.0].
-    pub fn set_throttle(
+    pub fn set_throttle<P: PwmController + ?Sized>(
         &mut self,
-        pwm: &mut Pca9685<I2cdev>,
+        pwm: &mut P,
         throttle: f32,
     ) -> Result<(), MotorError> {
-        if throttle > 1.0 || throttle < -1.0 {
-            return Err(MotorError::ThrottleError);
+        if !(-1.0..=1.0).contains(&throttle) {
+            return Err(MotorError::out_of_range("throttle", throttle, -1.0, 1.0));
         }
         let duty_cycle = (4095.0 * throttle.abs()) as u16;
 
         match throttle.partial_cmp(&0.0) {
             Some(Ordering::Greater) => {
-                pwm.set_channel_off(self.channels.forward_channel, duty_cycle)
-                    .map_err(|_| MotorError::ChannelError)?;
+                pwm.set_channel_full_off(self.channels.backward_channel)?;
+                pwm.set_channel_off(self.channels.forward_channel, duty_cycle)?;
             }
             Some(Ordering::Less) => {
-                pwm.set_channel_off(self.channels.backward_channel, duty_cycle)
-                    .map_err(|_| MotorError::ChannelError)?;
+                pwm.set_channel_full_off(self.channels.forward_channel)?;
+                pwm.set_channel_off(self.channels.backward_channel, duty_cycle)?;
             }
             _ => {
-                pwm.set_channel_full_off(self.channels.forward_channel)
-                    .map_err(|_| MotorError::ChannelError)?;
-                pwm.set_channel_full_off(self.channels.backward_channel)
-                    .map_err(|_| MotorError::ChannelError)?;
+                pwm.set_channel_full_off(self.channels.forward_channel)?;
+                pwm.set_channel_full_off(self.channels.backward_channel)?;
             }
         }
         Ok(())
     }
 
     /// Stops energizing the PWMs for this motor.
-    pub fn stop(&mut self, pwm: &mut Pca9685<I2cdev>) -> Result<(), MotorError> {
+    pub fn stop<P: PwmController + ?Sized>(&mut self, pwm: &mut P) -> Result<(), MotorError> {
         // Set the reference channel to run at full blast.
-        pwm.set_channel_full_off(self.channels.ref_channel)
-            .map_err(|_| MotorError::ChannelError)?;
-        pwm.set_channel_full_off(self.channels.forward_channel)
-            .map_err(|_| MotorError::ChannelError)?;
-        pwm.set_channel_full_off(self.channels.backward_channel)
-            .map_err(|_| MotorError::ChannelError)?;
+        pwm.set_channel_full_off(self.channels.ref_channel)?;
+        pwm.set_channel_full_off(self.channels.forward_channel)?;
+        pwm.set_channel_full_off(self.channels.backward_channel)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw::mock::RecordingPwm;
+
+    #[test]
+    fn test_throttle_duty_cycles() {
+        let mut pwm = RecordingPwm::new();
+        let mut motor = DcKit::try_new(&mut pwm, Motor::Motor1).unwrap();
+        assert_eq!(4095, pwm.duty_cycle(Channel::C8));
+
+        motor.set_throttle(&mut pwm, -1.0).unwrap();
+        assert_eq!(4095, pwm.duty_cycle(Channel::C10));
+        assert_eq!(0, pwm.duty_cycle(Channel::C9));
+
+        motor.set_throttle(&mut pwm, 0.0).unwrap();
+        assert_eq!(0, pwm.duty_cycle(Channel::C9));
+        assert_eq!(0, pwm.duty_cycle(Channel::C10));
+
+        motor.set_throttle(&mut pwm, 0.5).unwrap();
+        assert_eq!(2047, pwm.duty_cycle(Channel::C9));
+        assert_eq!(0, pwm.duty_cycle(Channel::C10));
+
+        motor.stop(&mut pwm).unwrap();
+        assert_eq!(0, pwm.duty_cycle(Channel::C8));
+        assert_eq!(0, pwm.duty_cycle(Channel::C9));
+    }
+
+    #[test]
+    fn test_throttle_out_of_range() {
+        let mut pwm = RecordingPwm::new();
+        let mut motor = DcKit::try_new(&mut pwm, Motor::Motor2).unwrap();
+        pwm.clear();
+        assert!(motor.set_throttle(&mut pwm, 1.5).is_err());
+        assert!(pwm.writes().is_empty());
+    }
+
+    #[test]
+    fn test_rejects_servo() {
+        let mut pwm = RecordingPwm::new();
+        assert!(DcKit::try_new(&mut pwm, Motor::Servo).is_err());
+    }
+}
@@ -1,43 +1,119 @@
+use crate::raw::Motor;
+use pwm_pca9685::Channel;
 use std::error::Error;
 use std::fmt;
+use std::path::PathBuf;
 
+/// Why talking to the motors failed.
+///
+/// The high level types such as [`MotorSocket`](crate::MotorSocket) return
+/// [`eyre::Report`]s, from which the original error can be recovered with
+/// [`downcast_ref::<MotorError>()`](eyre::Report::downcast_ref).
 #[derive(Debug)]
 pub enum MotorError {
-    /// An error occurred initializing the I2C bus.
-    I2cError,
-    /// An error occurred configuring the PCA9685.
-    PwmError,
-    /// An error occurred setting a channel.
-    ChannelError,
-    /// The value for throttle is not in the bounds of [-1.0, 1.0].
-    ThrottleError,
+    /// The I2C bus could not be opened.
+    OpenBus {
+        path: PathBuf,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// Talking to a HAT over the I2C bus failed, e.g. because it did not acknowledge.
+    ///
+    /// This is often transient, see [`MotorError::is_transient`].
+    I2c(Box<dyn Error + Send + Sync>),
+    /// No PCA9685 can live at the given I2C address.
+    InvalidAddress(u8),
+    /// The PCA9685 cannot run at the given PWM frequency in Hz.
+    InvalidFrequency(f32),
+    /// The channel cannot be used for this.
+    InvalidChannel(Channel),
     /// An invalid motor was provided to a constructor, i.e. a stepper motor
     /// passed into the DcMotor constructor.
-    InvalidMotorError,
-    PositionError,
-    InvalidAngle,
+    InvalidMotor(Motor),
+    /// A stepper needs an even number of at least 2 microsteps per step.
+    InvalidMicrosteps(u16),
+    /// A value, e.g. a throttle or an angle, is out of its bounds.
+    OutOfRange {
+        name: &'static str,
+        value: f32,
+        min: f32,
+        max: f32,
+    },
+}
+
+impl MotorError {
+    /// Whether retrying the same operation may well succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, MotorError::I2c(_))
+    }
+
+    pub(crate) fn out_of_range(name: &'static str, value: f32, min: f32, max: f32) -> Self {
+        MotorError::OutOfRange {
+            name,
+            value,
+            min,
+            max,
+        }
+    }
 }
 
 impl fmt::Display for MotorError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            MotorError::OpenBus { path, .. } => {
+                write!(f, "failed to open the I2C bus {}", path.display())
+            }
+            MotorError::I2c(_) => write!(f, "failed to talk to the HAT over I2C"),
+            MotorError::InvalidAddress(address) => {
+                write!(f, "{address:#x} is not a PCA9685 address (0x40-0x7f)")
+            }
+            MotorError::InvalidFrequency(frequency) => {
+                write!(f, "the PCA9685 cannot run at {frequency}Hz")
+            }
+            MotorError::InvalidChannel(channel) => write!(f, "channel {channel:?} cannot be used"),
+            MotorError::InvalidMotor(motor) => write!(f, "{motor:?} cannot be used"),
+            MotorError::InvalidMicrosteps(microsteps) => write!(
+                f,
+                "microsteps must be even and at least 2, not {microsteps}"
+            ),
+            MotorError::OutOfRange {
+                name,
+                value,
+                min,
+                max,
+            } => write!(f, "{name} must be between {min} and {max}, not {value}"),
+        }
     }
 }
 
-impl Error for MotorError {}
-
-#[derive(Debug)]
-pub enum ServoError {
-    /// An error occurred initializing the I2C bus.
-    I2cError,
-    /// An error occurred configuring the PCA9685.
-    PwmError,
+impl Error for MotorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MotorError::OpenBus { source, .. } | MotorError::I2c(source) => Some(source.as_ref()),
+            _ => None,
+        }
+    }
 }
 
-impl fmt::Display for ServoError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_chain() {
+        let nack = std::io::Error::other("remote I/O error");
+        let error = MotorError::I2c(Box::new(nack));
+        assert!(error.is_transient());
+        assert_eq!("remote I/O error", error.source().unwrap().to_string());
+
+        let report = eyre::Report::new(error).wrap_err("set throttle");
+        let error = report.downcast_ref::<MotorError>().unwrap();
+        assert!(matches!(error, MotorError::I2c(_)));
+
+        let error = MotorError::out_of_range("throttle", 1.5, -1.0, 1.0);
+        assert!(!error.is_transient());
+        assert_eq!(
+            "throttle must be between -1 and 1, not 1.5",
+            error.to_string()
+        );
     }
 }
-
-impl Error for ServoError {}
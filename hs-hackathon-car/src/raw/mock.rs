@@ -0,0 +1,98 @@
+use crate::raw::errors::MotorError;
+use crate::raw::pwm::PwmController;
+use pwm_pca9685::Channel;
+use std::sync::{Arc, Mutex};
+
+/// A single register write issued to a [`RecordingPwm`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PwmWrite {
+    On(Channel, u16),
+    Off(Channel, u16),
+    FullOff(Channel),
+}
+
+/// A [`PwmController`] that records every register write instead of talking to a PCA9685.
+///
+/// Clones share the same recording, so a test can keep one clone around while handing
+/// another one to the code under test.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingPwm {
+    writes: Arc<Mutex<Vec<PwmWrite>>>,
+}
+
+impl RecordingPwm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All writes recorded so far, in order.
+    pub fn writes(&self) -> Vec<PwmWrite> {
+        self.lock().clone()
+    }
+
+    /// Forgets all writes recorded so far.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// The duty cycle the given channel currently outputs, in the range [0, 4095].
+    ///
+    /// Like on the PCA9685, a full off takes precedence until the `OFF` counter is
+    /// written again.
+    pub fn duty_cycle(&self, channel: Channel) -> u16 {
+        let mut on = 0;
+        let mut off = None;
+        for write in self.lock().iter() {
+            match *write {
+                PwmWrite::On(c, value) if c == channel => on = value,
+                PwmWrite::Off(c, value) if c == channel => off = Some(value),
+                PwmWrite::FullOff(c) if c == channel => off = None,
+                _ => {}
+            }
+        }
+        off.map_or(0, |off| off.saturating_sub(on))
+    }
+
+    fn record(&self, write: PwmWrite) {
+        self.lock().push(write);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<PwmWrite>> {
+        self.writes
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl PwmController for RecordingPwm {
+    fn set_channel_on(&mut self, channel: Channel, value: u16) -> Result<(), MotorError> {
+        if value > 4095 {
+            return Err(MotorError::out_of_range(
+                "PWM counter",
+                value.into(),
+                0.0,
+                4095.0,
+            ));
+        }
+        self.record(PwmWrite::On(channel, value));
+        Ok(())
+    }
+
+    fn set_channel_off(&mut self, channel: Channel, value: u16) -> Result<(), MotorError> {
+        if value > 4095 {
+            return Err(MotorError::out_of_range(
+                "PWM counter",
+                value.into(),
+                0.0,
+                4095.0,
+            ));
+        }
+        self.record(PwmWrite::Off(channel, value));
+        Ok(())
+    }
+
+    fn set_channel_full_off(&mut self, channel: Channel) -> Result<(), MotorError> {
+        self.record(PwmWrite::FullOff(channel));
+        Ok(())
+    }
+}
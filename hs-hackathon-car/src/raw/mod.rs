@@ -1,16 +1,25 @@
 pub mod dc;
 pub mod errors;
+pub mod mock;
+pub mod pwm;
 pub mod servo;
+pub mod stepper;
 
+#[cfg(target_os = "linux")]
 use crate::raw::dc::DcKit;
+#[cfg(target_os = "linux")]
 use crate::raw::servo::ServoKit;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
 use errors::MotorError;
+#[cfg(target_os = "linux")]
 use linux_embedded_hal::I2cdev;
 use pwm_pca9685::{Pca9685, SlaveAddr};
+#[cfg(target_os = "linux")]
 use std::thread::sleep;
+#[cfg(target_os = "linux")]
 use std::time::Duration;
 
-#[derive(Debug, Hash, PartialEq, Eq)]
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
 /// An enumeration of all potential motors that can be controlled via the
 /// Motor HAT or the Servo HAT.
 pub enum Motor {
@@ -18,94 +27,150 @@ pub enum Motor {
     Motor2,
     Motor3,
     Motor4,
+    /// A stepper wired to the terminals of `Motor1` and `Motor2`.
     Stepper1,
+    /// A stepper wired to the terminals of `Motor3` and `Motor4`.
     Stepper2,
     Servo,
 }
 
-/// Initializes the PWM to control the Motor HAT. This makes a few assumptions:
-/// - Assumes only one Motor HAT as 0x96.
-/// - Assumes only a pre-scale of 4 so the HAT is running at ~1600 Hz.
+impl Motor {
+    /// The DC motor wired to the given terminal (1-4) of the Motor HAT.
+    pub fn dc(index: u8) -> Result<Motor, MotorError> {
+        match index {
+            1 => Ok(Motor::Motor1),
+            2 => Ok(Motor::Motor2),
+            3 => Ok(Motor::Motor3),
+            4 => Ok(Motor::Motor4),
+            _ => Err(MotorError::out_of_range("DC motor", index.into(), 1.0, 4.0)),
+        }
+    }
+}
+
+/// Opens the I2C bus the HATs are connected to.
 ///
-/// If no I2C bus is provided to the function, it will attempt to
-/// connect to /dev/i2c-3 which will work for the nixified RPi.
-/// Try /dev/i2c-1 for most other cases.
-pub fn init_motor_pwm(i2c: Option<I2cdev>) -> Result<Pca9685<I2cdev>, MotorError> {
-    let i2c = if let Some(i2c) = i2c {
-        i2c
-    } else {
-        I2cdev::new("/dev/i2c-3").map_err(|_| MotorError::I2cError)?
-    };
+/// This is /dev/i2c-3 which will work for the nixified RPi.
+/// Try /dev/i2c-1 for most other cases, see [`CarConfig`](crate::CarConfig).
+#[cfg(target_os = "linux")]
+pub fn default_i2c_bus() -> Result<I2cdev, MotorError> {
+    open_i2c_bus(crate::config::DEFAULT_I2C_BUS)
+}
 
-    // The default address for the motor hat is 96 (0x60).
-    let address = SlaveAddr::Alternative(true, false, false, false, false, false);
-    tracing::debug!(
-        "Connecting to DC motor at address: {:#x?}",
-        address.address()
-    );
+/// Opens the I2C bus at the given path.
+#[cfg(target_os = "linux")]
+pub fn open_i2c_bus(path: impl AsRef<std::path::Path>) -> Result<I2cdev, MotorError> {
+    let path = path.as_ref();
+    I2cdev::new(path).map_err(|e| MotorError::OpenBus {
+        path: path.to_owned(),
+        source: Box::new(e),
+    })
+}
 
-    let mut pwm = Pca9685::new(i2c, address);
-    pwm.enable().map_err(|_| MotorError::PwmError)?;
-    pwm.set_prescale(4).map_err(|_| MotorError::PwmError)?;
-    Ok(pwm)
+/// The slave address of a PCA9685 from its 7 bit I2C address (0x40-0x7f).
+fn slave_addr(address: u8) -> Result<SlaveAddr, MotorError> {
+    if !(0x40..=0x7f).contains(&address) {
+        return Err(MotorError::InvalidAddress(address));
+    }
+    let bit = |n: u8| address & (1 << n) != 0;
+    Ok(SlaveAddr::Alternative(
+        bit(5),
+        bit(4),
+        bit(3),
+        bit(2),
+        bit(1),
+        bit(0),
+    ))
 }
 
-/// Initializes the PWM to control the Servo HAT.
-///
-/// If no I2C bus is provided to the function, it will attempt to
-/// connect to /dev/i2c-3 which will work for the nixified RPi.
-/// Try /dev/i2c-1 for most other cases.
-pub fn init_servo_pwm(i2c: Option<I2cdev>) -> Result<Pca9685<I2cdev>, MotorError> {
-    let i2c = if let Some(i2c) = i2c {
-        i2c
-    } else {
-        I2cdev::new("/dev/i2c-3").map_err(|_| MotorError::I2cError)?
-    };
+/// The pre-scale for the given PWM frequency in Hz with the internal 25MHz oscillator.
+fn prescale(frequency: f32) -> Result<u8, MotorError> {
+    let osc_clock = 25_000_000.0; // 25 MHz
+    let prescale = (osc_clock / (4096.0 * frequency)).round() - 1.0;
+    if !(3.0..=255.0).contains(&prescale) {
+        return Err(MotorError::InvalidFrequency(frequency));
+    }
+    Ok(prescale as u8)
+}
 
-    let address = SlaveAddr::Alternative(false, false, false, false, false, false); // 0x40
+/// Initializes a PCA9685 at the given I2C address to run at the given frequency in Hz.
+///
+/// Works on top of any `embedded-hal` I2C bus, e.g. the one returned by
+/// [`default_i2c_bus`] on the RPi.
+pub fn init_pwm<I2C, E>(i2c: I2C, address: u8, frequency: f32) -> Result<Pca9685<I2C>, MotorError>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let address = slave_addr(address)?;
+    let prescale = prescale(frequency)?;
     tracing::debug!(
-        "Connecting to servo motor at address: {:#x?}",
+        "Connecting to PWM at address {:#x?} with prescale {prescale}",
         address.address()
     );
 
+    let config_error = |e| match e {
+        pwm_pca9685::Error::I2C(e) => MotorError::I2c(Box::new(e)),
+        pwm_pca9685::Error::InvalidInputData => MotorError::InvalidFrequency(frequency),
+    };
     let mut pwm = Pca9685::new(i2c, address);
-    tracing::debug!("Enabling pwm");
-    pwm.enable().map_err(|_| MotorError::PwmError)?;
+    pwm.enable().map_err(config_error)?;
+    pwm.set_prescale(prescale).map_err(config_error)?;
+    Ok(pwm)
+}
 
-    // Calculate prescale value for 50Hz
-    let osc_clock = 25_000_000; // 25 MHz
-    let pwm_freq = 50; // 50 Hz
-    let prescale_value = (osc_clock as f32 / (4096.0 * pwm_freq as f32)).round() as u8 - 1;
-    tracing::debug!("Setting prescale to {}", prescale_value);
-    pwm.set_prescale(prescale_value)
-        .map_err(|_| MotorError::PwmError)?;
+/// Initializes the PWM to control the Motor HAT with the default [`CarConfig`](crate::CarConfig):
+/// - Assumes only one Motor HAT at 0x60.
+/// - Assumes a pre-scale of 4 so the HAT is running at ~1220 Hz.
+///
+/// Works on top of any `embedded-hal` I2C bus, e.g. the one returned by
+/// [`default_i2c_bus`] on the RPi.
+pub fn init_motor_pwm<I2C, E>(i2c: I2C) -> Result<Pca9685<I2C>, MotorError>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let config = crate::CarConfig::default();
+    init_pwm(i2c, config.motor_address, config.motor_frequency)
+}
 
-    Ok(pwm)
+/// Initializes the PWM to control the Servo HAT with the default
+/// [`CarConfig`](crate::CarConfig), i.e. at 0x40 running at 50 Hz.
+///
+/// Works on top of any `embedded-hal` I2C bus, e.g. the one returned by
+/// [`default_i2c_bus`] on the RPi.
+pub fn init_servo_pwm<I2C, E>(i2c: I2C) -> Result<Pca9685<I2C>, MotorError>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let config = crate::CarConfig::default();
+    init_pwm(i2c, config.servo_address, config.servo_frequency)
 }
 
+#[cfg(target_os = "linux")]
 #[allow(dead_code)]
 fn main() -> eyre::Result<()> {
     // DC Motor
-    let mut dc_pwm = init_motor_pwm(None)?;
+    let mut dc_pwm = init_motor_pwm(default_i2c_bus()?)?;
     let mut dc_motor = DcKit::try_new(&mut dc_pwm, Motor::Motor1)?;
 
-    let _ = dc_motor.set_throttle(&mut dc_pwm, 1.0)?;
+    dc_motor.set_throttle(&mut dc_pwm, 1.0)?;
     sleep(Duration::from_secs(1));
 
-    let _ = dc_motor.set_throttle(&mut dc_pwm, 0.0)?;
+    dc_motor.set_throttle(&mut dc_pwm, 0.0)?;
     sleep(Duration::from_secs(1));
 
-    let _ = dc_motor.set_throttle(&mut dc_pwm, -1.0)?;
+    dc_motor.set_throttle(&mut dc_pwm, -1.0)?;
     sleep(Duration::from_secs(1));
 
-    let _ = dc_motor.set_throttle(&mut dc_pwm, 0.0)?;
+    dc_motor.set_throttle(&mut dc_pwm, 0.0)?;
     dc_motor.stop(&mut dc_pwm)?;
 
     // Wait before turning
     sleep(Duration::from_secs(1));
 
     // Servo
-    let mut servo_pwm = init_servo_pwm(None)?;
+    let mut servo_pwm = init_servo_pwm(default_i2c_bus()?)?;
     let mut servo = ServoKit::try_new(&mut servo_pwm, Motor::Servo)?;
     // TODO: This doesn't work with a shorter 500ms waiting time!
 
@@ -125,3 +190,24 @@ fn main() -> eyre::Result<()> {
     let _ = servo.set_angle(&mut servo_pwm, 90.0);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slave_addr() {
+        assert_eq!(0x60, slave_addr(0x60).unwrap().address());
+        assert_eq!(0x40, slave_addr(0x40).unwrap().address());
+        assert_eq!(0x7f, slave_addr(0x7f).unwrap().address());
+        assert!(slave_addr(0x20).is_err());
+    }
+
+    #[test]
+    fn test_prescale() {
+        let config = crate::CarConfig::default();
+        assert_eq!(4, prescale(config.motor_frequency).unwrap());
+        assert_eq!(121, prescale(config.servo_frequency).unwrap());
+        assert!(prescale(5000.0).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+use crate::raw::errors::MotorError;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use pwm_pca9685::{Channel, Error, Pca9685};
+
+/// The subset of a PCA9685 PWM controller needed to drive the DC motors and servos.
+///
+/// Implemented for [`Pca9685`] on top of any `embedded-hal` I2C bus, and for the
+/// [`RecordingPwm`](crate::raw::mock::RecordingPwm) used in tests.
+pub trait PwmController {
+    /// Sets the `ON` counter of the given channel. Valid values are in the range [0, 4095].
+    fn set_channel_on(&mut self, channel: Channel, value: u16) -> Result<(), MotorError>;
+
+    /// Sets the `OFF` counter of the given channel. Valid values are in the range [0, 4095].
+    fn set_channel_off(&mut self, channel: Channel, value: u16) -> Result<(), MotorError>;
+
+    /// Turns the given channel off, regardless of its counters.
+    fn set_channel_full_off(&mut self, channel: Channel) -> Result<(), MotorError>;
+}
+
+/// Keeps the I2C error as the source, and blames anything else on the counter value.
+fn counter_error<E>(error: Error<E>, value: u16) -> MotorError
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    match error {
+        Error::I2C(e) => MotorError::I2c(Box::new(e)),
+        Error::InvalidInputData => {
+            MotorError::out_of_range("PWM counter", value.into(), 0.0, 4095.0)
+        }
+    }
+}
+
+impl<I2C, E> PwmController for Pca9685<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    fn set_channel_on(&mut self, channel: Channel, value: u16) -> Result<(), MotorError> {
+        Pca9685::set_channel_on(self, channel, value).map_err(|e| counter_error(e, value))
+    }
+
+    fn set_channel_off(&mut self, channel: Channel, value: u16) -> Result<(), MotorError> {
+        Pca9685::set_channel_off(self, channel, value).map_err(|e| counter_error(e, value))
+    }
+
+    fn set_channel_full_off(&mut self, channel: Channel) -> Result<(), MotorError> {
+        Pca9685::set_channel_full_off(self, channel).map_err(|e| counter_error(e, 0))
+    }
+}
+
+impl<P: PwmController + ?Sized> PwmController for Box<P> {
+    fn set_channel_on(&mut self, channel: Channel, value: u16) -> Result<(), MotorError> {
+        (**self).set_channel_on(channel, value)
+    }
+
+    fn set_channel_off(&mut self, channel: Channel, value: u16) -> Result<(), MotorError> {
+        (**self).set_channel_off(channel, value)
+    }
+
+    fn set_channel_full_off(&mut self, channel: Channel) -> Result<(), MotorError> {
+        (**self).set_channel_full_off(channel)
+    }
+}
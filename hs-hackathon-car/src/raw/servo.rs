@@ -1,8 +1,7 @@
 use crate::raw::errors::MotorError;
+use crate::raw::pwm::PwmController;
 use crate::raw::Motor;
-use hal::I2cdev;
-use linux_embedded_hal as hal;
-use pwm_pca9685::{Channel, Pca9685};
+use pwm_pca9685::Channel;
 
 // Constants for servo control
 const SERVO_MIN_PULSE: u16 = 2458; // in microseconds
@@ -15,27 +14,45 @@ pub struct ServoKit {
 }
 
 impl ServoKit {
-    pub fn try_new(pwm: &mut Pca9685<I2cdev>, motor: Motor) -> Result<Self, MotorError> {
+    pub fn try_new<P: PwmController + ?Sized>(
+        pwm: &mut P,
+        motor: Motor,
+    ) -> Result<Self, MotorError> {
         // Map the motor to the corresponding channel
         let channel = match motor {
             Motor::Servo => Channel::C0, // Example channel, adjust as necessary
-            _ => return Err(MotorError::InvalidMotorError),
+            _ => return Err(MotorError::InvalidMotor(motor)),
         };
+        Self::with_channel(pwm, channel)
+    }
 
-        pwm.set_channel_on(channel, 0)
-            .map_err(|_| MotorError::ChannelError)?;
+    /// Initializes a servo plugged into the given channel of the Servo HAT.
+    pub fn with_channel<P: PwmController + ?Sized>(
+        pwm: &mut P,
+        channel: Channel,
+    ) -> Result<Self, MotorError> {
+        if channel == Channel::All {
+            return Err(MotorError::InvalidChannel(channel));
+        }
+        pwm.set_channel_on(channel, 0)?;
 
         Ok(ServoKit { channel })
     }
 
-    pub fn set_angle(&mut self, pwm: &mut Pca9685<I2cdev>, angle: f32) -> Result<(), MotorError> {
-        if angle < 0.0 || angle > ACTUATION_RANGE {
-            return Err(MotorError::InvalidAngle);
+    pub fn set_angle<P: PwmController + ?Sized>(
+        &mut self,
+        pwm: &mut P,
+        angle: f32,
+    ) -> Result<(), MotorError> {
+        if !(0.0..=ACTUATION_RANGE).contains(&angle) {
+            return Err(MotorError::out_of_range(
+                "servo angle",
+                angle,
+                0.0,
+                ACTUATION_RANGE,
+            ));
         };
         let fraction = angle / ACTUATION_RANGE;
-        if !(fraction >= 0.0 && fraction <= 1.0) {
-            return Err(MotorError::InvalidAngle);
-        }
         let duty_cycle: u16 = (SERVO_MIN_PULSE as f32
             + (fraction * (SERVO_MAX_PULSE - SERVO_MIN_PULSE) as f32))
             as u16;
@@ -46,33 +63,48 @@ impl ServoKit {
         Ok(())
     }
 
-    pub fn set_duty_cycle(
+    pub fn set_duty_cycle<P: PwmController + ?Sized>(
         &mut self,
-        pwm: &mut Pca9685<I2cdev>,
+        pwm: &mut P,
         duty_cycle: u16,
     ) -> Result<(), MotorError> {
         let shifted_value = duty_cycle >> 4; // Shift by 4 bits for 12-bit resolution
 
         if duty_cycle == 0xFFFF {
             // Fully on
-            pwm.set_channel_on(self.channel, 0x1000)
-                .map_err(|_| MotorError::ChannelError)?;
-            pwm.set_channel_off(self.channel, 0)
-                .map_err(|_| MotorError::ChannelError)?;
+            pwm.set_channel_on(self.channel, 0x1000)?;
+            pwm.set_channel_off(self.channel, 0)?;
         } else if duty_cycle < 0x0010 {
             // Fully off
-            pwm.set_channel_on(self.channel, 0)
-                .map_err(|_| MotorError::ChannelError)?;
-            pwm.set_channel_off(self.channel, 0x1000)
-                .map_err(|_| MotorError::ChannelError)?;
+            pwm.set_channel_on(self.channel, 0)?;
+            pwm.set_channel_off(self.channel, 0x1000)?;
         } else {
             // Normal case
-            pwm.set_channel_on(self.channel, 0)
-                .map_err(|_| MotorError::ChannelError)?;
-            pwm.set_channel_off(self.channel, shifted_value)
-                .map_err(|_| MotorError::ChannelError)?;
+            pwm.set_channel_on(self.channel, 0)?;
+            pwm.set_channel_off(self.channel, shifted_value)?;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw::mock::RecordingPwm;
+
+    #[test]
+    fn test_angle_duty_cycles() {
+        let mut pwm = RecordingPwm::new();
+        let mut servo = ServoKit::try_new(&mut pwm, Motor::Servo).unwrap();
+
+        servo.set_angle(&mut pwm, 0.0).unwrap();
+        assert_eq!(SERVO_MIN_PULSE >> 4, pwm.duty_cycle(Channel::C0));
+
+        servo.set_angle(&mut pwm, 180.0).unwrap();
+        assert_eq!(SERVO_MAX_PULSE >> 4, pwm.duty_cycle(Channel::C0));
+
+        assert!(servo.set_angle(&mut pwm, 181.0).is_err());
+        assert_eq!(SERVO_MAX_PULSE >> 4, pwm.duty_cycle(Channel::C0));
+    }
+}
@@ -0,0 +1,293 @@
+use crate::raw::errors::MotorError;
+use crate::raw::pwm::PwmController;
+use crate::raw::Motor;
+use pwm_pca9685::Channel;
+use std::f32::consts::PI;
+
+/// Number of microsteps per full step.
+pub const DEFAULT_MICROSTEPS: u16 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// How the coils of a stepper are energized for a step.
+pub enum StepStyle {
+    /// Full steps with one coil energized at a time.
+    Single,
+    /// Full steps with two coils energized at a time, for more torque.
+    Double,
+    /// Half steps, alternating between one and two energized coils.
+    Interleave,
+    /// Microsteps, driving the coils along a sine curve.
+    Microstep,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The direction to turn a stepper in.
+pub enum StepDirection {
+    Forward,
+    Backward,
+}
+
+#[derive(Clone, Copy)]
+/// Channels used to control a stepper motor.
+struct StepperChannels {
+    /// Reference channels of the two H-bridges, kept at full blast.
+    ref_channels: [Channel; 2],
+    /// The coils in the order they are energized when stepping forward.
+    coils: [Channel; 4],
+}
+
+/// A structure to initialize and control a stepper motor.
+///
+/// Follows the stepping scheme of Adafruit's MotorKit, where all step styles are
+/// expressed as positions on the microstep curve.
+pub struct StepperKit {
+    channels: StepperChannels,
+    microsteps: i32,
+    current_microstep: i32,
+    curve: Vec<u16>,
+}
+
+impl StepperKit {
+    /// Attempts to initialize a stepper motor with the given number of microsteps
+    /// per full step, which must be even and at least 2.
+    pub fn try_new<P: PwmController + ?Sized>(
+        pwm: &mut P,
+        motor: Motor,
+        microsteps: u16,
+    ) -> Result<Self, MotorError> {
+        let channels = match motor {
+            Motor::Stepper1 => StepperChannels {
+                ref_channels: [Channel::C8, Channel::C13],
+                coils: [Channel::C9, Channel::C11, Channel::C10, Channel::C12],
+            },
+            Motor::Stepper2 => StepperChannels {
+                ref_channels: [Channel::C7, Channel::C2],
+                coils: [Channel::C3, Channel::C5, Channel::C4, Channel::C6],
+            },
+            _ => return Err(MotorError::InvalidMotor(motor)),
+        };
+        if microsteps < 2 || !microsteps.is_multiple_of(2) {
+            return Err(MotorError::InvalidMicrosteps(microsteps));
+        }
+
+        // Set the reference channels to run at full blast.
+        for channel in channels.ref_channels {
+            pwm.set_channel_on(channel, 0)?;
+            pwm.set_channel_off(channel, 4095)?;
+        }
+        for channel in channels.coils {
+            pwm.set_channel_on(channel, 0)?;
+        }
+
+        let curve = (0..=microsteps)
+            .map(|i| (4095.0 * (PI / (2.0 * microsteps as f32) * i as f32).sin()).round() as u16)
+            .collect();
+        let mut kit = Self {
+            channels,
+            microsteps: microsteps as i32,
+            current_microstep: 0,
+            curve,
+        };
+        kit.update_coils(pwm, false)?;
+        Ok(kit)
+    }
+
+    /// The current position in microsteps, relative to where the stepper was initialized.
+    pub fn position(&self) -> i32 {
+        self.current_microstep
+    }
+
+    /// Moves the stepper by one step of the given style, returning the new position in
+    /// microsteps.
+    pub fn onestep<P: PwmController + ?Sized>(
+        &mut self,
+        pwm: &mut P,
+        direction: StepDirection,
+        style: StepStyle,
+    ) -> Result<i32, MotorError> {
+        let half_step = self.microsteps / 2;
+        let full_step = self.microsteps;
+
+        let step_size = match style {
+            StepStyle::Microstep => 1,
+            _ => {
+                // A previous microstep may have left us between two half steps, so
+                // first align with the half step pattern.
+                let additional = self.current_microstep.rem_euclid(half_step);
+                if additional != 0 {
+                    match direction {
+                        StepDirection::Forward => self.current_microstep += half_step - additional,
+                        StepDirection::Backward => self.current_microstep -= additional,
+                    }
+                    0
+                } else {
+                    let on_double = self.current_microstep.div_euclid(half_step) % 2 != 0;
+                    match style {
+                        StepStyle::Interleave => half_step,
+                        // Switch between single and double coil positions first.
+                        StepStyle::Single if on_double => half_step,
+                        StepStyle::Double if !on_double => half_step,
+                        _ => full_step,
+                    }
+                }
+            }
+        };
+
+        match direction {
+            StepDirection::Forward => self.current_microstep += step_size,
+            StepDirection::Backward => self.current_microstep -= step_size,
+        }
+        self.update_coils(pwm, style == StepStyle::Microstep)?;
+        Ok(self.current_microstep)
+    }
+
+    /// Stops energizing the coils, which lets the stepper spin freely.
+    pub fn release<P: PwmController + ?Sized>(&mut self, pwm: &mut P) -> Result<(), MotorError> {
+        for channel in self.channels.coils {
+            pwm.set_channel_full_off(channel)?;
+        }
+        Ok(())
+    }
+
+    /// Stops energizing the PWMs for this motor.
+    pub fn stop<P: PwmController + ?Sized>(&mut self, pwm: &mut P) -> Result<(), MotorError> {
+        self.release(pwm)?;
+        for channel in self.channels.ref_channels {
+            pwm.set_channel_full_off(channel)?;
+        }
+        Ok(())
+    }
+
+    /// The duty cycle of each coil at the current position.
+    fn duty_cycles(&self, microstepping: bool) -> [u16; 4] {
+        let mut duty_cycles = [0; 4];
+        let trailing = self
+            .current_microstep
+            .div_euclid(self.microsteps)
+            .rem_euclid(4) as usize;
+        let leading = (trailing + 1) % 4;
+        let microstep = self.current_microstep.rem_euclid(self.microsteps) as usize;
+        duty_cycles[leading] = self.curve[microstep];
+        duty_cycles[trailing] = self.curve[self.microsteps as usize - microstep];
+
+        // Make sure double steps use full torque rather than the middle of the curve.
+        if !microstepping
+            && duty_cycles[leading] == duty_cycles[trailing]
+            && duty_cycles[leading] > 0
+        {
+            duty_cycles[leading] = 4095;
+            duty_cycles[trailing] = 4095;
+        }
+        duty_cycles
+    }
+
+    fn update_coils<P: PwmController + ?Sized>(
+        &mut self,
+        pwm: &mut P,
+        microstepping: bool,
+    ) -> Result<(), MotorError> {
+        for (channel, duty_cycle) in self
+            .channels
+            .coils
+            .into_iter()
+            .zip(self.duty_cycles(microstepping))
+        {
+            if duty_cycle == 0 {
+                pwm.set_channel_full_off(channel)?;
+            } else {
+                pwm.set_channel_off(channel, duty_cycle)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw::mock::RecordingPwm;
+
+    fn coils(pwm: &RecordingPwm) -> [u16; 4] {
+        [Channel::C9, Channel::C11, Channel::C10, Channel::C12].map(|c| pwm.duty_cycle(c))
+    }
+
+    #[test]
+    fn test_single_steps() {
+        let mut pwm = RecordingPwm::new();
+        let mut stepper = StepperKit::try_new(&mut pwm, Motor::Stepper1, 16).unwrap();
+        assert_eq!(4095, pwm.duty_cycle(Channel::C8));
+        assert_eq!(4095, pwm.duty_cycle(Channel::C13));
+        assert_eq!([4095, 0, 0, 0], coils(&pwm));
+
+        let single = |stepper: &mut StepperKit, pwm: &mut RecordingPwm| {
+            stepper
+                .onestep(pwm, StepDirection::Forward, StepStyle::Single)
+                .unwrap()
+        };
+        assert_eq!(16, single(&mut stepper, &mut pwm));
+        assert_eq!([0, 4095, 0, 0], coils(&pwm));
+        assert_eq!(32, single(&mut stepper, &mut pwm));
+        assert_eq!([0, 0, 4095, 0], coils(&pwm));
+
+        let back = stepper
+            .onestep(&mut pwm, StepDirection::Backward, StepStyle::Single)
+            .unwrap();
+        assert_eq!(16, back);
+        assert_eq!([0, 4095, 0, 0], coils(&pwm));
+    }
+
+    #[test]
+    fn test_double_and_interleave_steps() {
+        let mut pwm = RecordingPwm::new();
+        let mut stepper = StepperKit::try_new(&mut pwm, Motor::Stepper1, 16).unwrap();
+
+        // the first double step moves onto the double coil position
+        let position = stepper
+            .onestep(&mut pwm, StepDirection::Forward, StepStyle::Double)
+            .unwrap();
+        assert_eq!(8, position);
+        assert_eq!([4095, 4095, 0, 0], coils(&pwm));
+        let position = stepper
+            .onestep(&mut pwm, StepDirection::Forward, StepStyle::Double)
+            .unwrap();
+        assert_eq!(24, position);
+        assert_eq!([0, 4095, 4095, 0], coils(&pwm));
+
+        let position = stepper
+            .onestep(&mut pwm, StepDirection::Forward, StepStyle::Interleave)
+            .unwrap();
+        assert_eq!(32, position);
+        assert_eq!([0, 0, 4095, 0], coils(&pwm));
+    }
+
+    #[test]
+    fn test_microsteps() {
+        let mut pwm = RecordingPwm::new();
+        let mut stepper = StepperKit::try_new(&mut pwm, Motor::Stepper2, 4).unwrap();
+
+        for _ in 0..2 {
+            stepper
+                .onestep(&mut pwm, StepDirection::Forward, StepStyle::Microstep)
+                .unwrap();
+        }
+        let coils = [Channel::C3, Channel::C5].map(|c| pwm.duty_cycle(c));
+        // half way between the first two coils both run at sin(45°)
+        assert_eq!([2896, 2896], coils);
+
+        // a single step from here aligns with the next full step
+        let position = stepper
+            .onestep(&mut pwm, StepDirection::Forward, StepStyle::Single)
+            .unwrap();
+        assert_eq!(4, position);
+
+        stepper.release(&mut pwm).unwrap();
+        assert_eq!(0, pwm.duty_cycle(Channel::C5));
+    }
+
+    #[test]
+    fn test_rejects_dc_motor() {
+        let mut pwm = RecordingPwm::new();
+        assert!(StepperKit::try_new(&mut pwm, Motor::Motor1, 16).is_err());
+        assert!(StepperKit::try_new(&mut pwm, Motor::Stepper1, 3).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+//! A hardware-free simulation of the car.
+//!
+//! When the simulation backend is active, [`MotorSocket`](crate::MotorSocket) and
+//! [`WheelOrientation`](crate::WheelOrientation) do not talk to the PWM HATs at all.
+//! Instead they drive a kinematic bicycle model whose [`Pose`] can be read back at
+//! any time, which allows developing and testing a full drive loop on a laptop or
+//! in CI. A [`DifferentialDrive`](crate::DifferentialDrive) turns the same car by
+//! driving its left and right wheels at different speeds instead.
+//!
+//! The simulation backend is picked if any of the following holds:
+//!
+//! - the crate is compiled with the `sim` feature
+//! - the target is not linux (there is no PWM HAT to talk to)
+//! - the `HS_HACKATHON_SIM` environment variable is set to anything but `0` or `false`
+//!
+//! Time is measured with [`tokio::time::Instant`], so tests running with a paused tokio
+//! clock are fully deterministic.
+
+use lazy_static::lazy_static;
+use std::f32::consts::PI;
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+
+/// The environment variable used to opt into the simulation backend at runtime.
+pub const SIM_ENV_VAR: &str = "HS_HACKATHON_SIM";
+
+lazy_static! {
+    static ref GLOBAL: Simulation = Simulation::new(SimConfig::default());
+}
+
+/// Whether [`MotorSocket`](crate::MotorSocket) and [`WheelOrientation`](crate::WheelOrientation)
+/// use the simulation instead of the hardware.
+pub fn enabled() -> bool {
+    if cfg!(feature = "sim") || cfg!(not(target_os = "linux")) {
+        return true;
+    }
+    std::env::var(SIM_ENV_VAR)
+        .map(|value| !matches!(value.trim(), "" | "0" | "false"))
+        .unwrap_or(false)
+}
+
+/// Physical parameters of the simulated car.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimConfig {
+    /// Distance between the front and the rear axle in meters.
+    pub wheelbase: f32,
+    /// Speed in meters per second the car drives at full throttle.
+    pub max_speed: f32,
+    /// Steering angle of the front wheels in radians at full lock.
+    pub max_steering: f32,
+    /// Distance between the left and the right wheels in meters.
+    pub track_width: f32,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            wheelbase: 0.16,
+            max_speed: 1.0,
+            max_steering: 30f32.to_radians(),
+            track_width: 0.14,
+        }
+    }
+}
+
+/// Position and orientation of the simulated car.
+///
+/// `x` and `y` are in meters, `heading` is in radians in the range (-π, π]. A heading
+/// of 0 points along the x axis; positive headings turn counter-clockwise (to the left).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Pose {
+    pub x: f32,
+    pub y: f32,
+    pub heading: f32,
+}
+
+impl Pose {
+    /// Advances the pose by `dt` seconds of driving at `speed` m/s with the front wheels
+    /// turned by `steering` radians, using the kinematic bicycle model.
+    pub fn advance(self, config: &SimConfig, speed: f32, steering: f32, dt: f32) -> Pose {
+        self.integrate(speed, speed * steering.tan() / config.wheelbase, dt)
+    }
+
+    /// Advances the pose by `dt` seconds of driving at `speed` m/s while turning at
+    /// `yaw_rate` rad/s.
+    pub fn integrate(self, speed: f32, yaw_rate: f32, dt: f32) -> Pose {
+        if yaw_rate.abs() < f32::EPSILON {
+            return Pose {
+                x: self.x + speed * dt * self.heading.cos(),
+                y: self.y + speed * dt * self.heading.sin(),
+                heading: self.heading,
+            };
+        }
+
+        // with constant inputs the car drives along a circle (of radius 0 when turning
+        // on the spot), so integrate exactly
+        let heading = self.heading + yaw_rate * dt;
+        let radius = speed / yaw_rate;
+        Pose {
+            x: self.x + radius * (heading.sin() - self.heading.sin()),
+            y: self.y + radius * (self.heading.cos() - heading.cos()),
+            heading: normalize(heading),
+        }
+    }
+}
+
+fn normalize(mut angle: f32) -> f32 {
+    while angle > PI {
+        angle -= 2.0 * PI;
+    }
+    while angle <= -PI {
+        angle += 2.0 * PI;
+    }
+    angle
+}
+
+struct SimulatedCar {
+    config: SimConfig,
+    pose: Pose,
+    /// Current speed in m/s.
+    speed: f32,
+    /// Current steering angle of the front wheels in radians.
+    steering: f32,
+    /// Yaw rate in rad/s caused by driving the wheels at different speeds.
+    spin: f32,
+    updated: Instant,
+}
+
+impl SimulatedCar {
+    /// Integrates the motion since the last update.
+    fn catch_up(&mut self) {
+        let now = Instant::now();
+        let dt = now.duration_since(self.updated).as_secs_f32();
+        let yaw_rate = self.speed * self.steering.tan() / self.config.wheelbase + self.spin;
+        self.pose = self.pose.integrate(self.speed, yaw_rate, dt);
+        self.updated = now;
+    }
+}
+
+/// A handle to a simulated car.
+///
+/// Handles are cheap to clone and all clones observe the same car.
+#[derive(Clone)]
+pub struct Simulation(Arc<Mutex<SimulatedCar>>);
+
+impl Simulation {
+    /// Creates a new simulated car standing still at the origin.
+    pub fn new(config: SimConfig) -> Self {
+        Self(Arc::new(Mutex::new(SimulatedCar {
+            config,
+            pose: Pose::default(),
+            speed: 0.0,
+            steering: 0.0,
+            spin: 0.0,
+            updated: Instant::now(),
+        })))
+    }
+
+    /// The simulation used by [`MotorSocket::open`](crate::MotorSocket::open) and
+    /// [`WheelOrientation::new`](crate::WheelOrientation::new).
+    pub fn global() -> &'static Simulation {
+        &GLOBAL
+    }
+
+    /// The current pose of the car.
+    pub fn pose(&self) -> Pose {
+        let mut car = self.lock();
+        car.catch_up();
+        car.pose
+    }
+
+    /// The current speed of the car in m/s.
+    pub fn speed(&self) -> f32 {
+        self.lock().speed
+    }
+
+    /// The current steering angle of the front wheels in radians.
+    pub fn steering(&self) -> f32 {
+        self.lock().steering
+    }
+
+    /// Stops the car and puts it back to the origin.
+    pub fn reset(&self) {
+        let mut car = self.lock();
+        car.pose = Pose::default();
+        car.speed = 0.0;
+        car.steering = 0.0;
+        car.spin = 0.0;
+        car.updated = Instant::now();
+    }
+
+    /// Sets the throttle in the range [-1.0, 1.0].
+    pub(crate) fn set_throttle(&self, throttle: f32) {
+        let mut car = self.lock();
+        car.catch_up();
+        car.speed = throttle * car.config.max_speed;
+        car.spin = 0.0;
+    }
+
+    /// Sets the throttle of the left and the right wheels, each in the range [-1.0, 1.0].
+    pub(crate) fn set_wheels(&self, left: f32, right: f32) {
+        let mut car = self.lock();
+        car.catch_up();
+        let max_speed = car.config.max_speed;
+        car.speed = (left + right) / 2.0 * max_speed;
+        car.spin = (right - left) * max_speed / car.config.track_width;
+    }
+
+    /// Sets the steering in the range [-1.0, 1.0], where -1.0 is full left.
+    pub(crate) fn set_steering(&self, steering: f32) {
+        let mut car = self.lock();
+        car.catch_up();
+        car.steering = -steering * car.config.max_steering;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SimulatedCar> {
+        // the simulation state stays consistent even if a holder panicked
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Angle, MotorSocket, Velocity, WheelOrientation};
+    use std::time::Duration;
+
+    fn assert_close(expected: f32, actual: f32) {
+        assert!(
+            (expected - actual).abs() < 1e-3,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn test_straight_line() {
+        let config = SimConfig::default();
+        let pose = Pose::default().advance(&config, 1.0, 0.0, 2.0);
+        assert_close(2.0, pose.x);
+        assert_close(0.0, pose.y);
+        assert_close(0.0, pose.heading);
+    }
+
+    #[test]
+    fn test_full_circle() {
+        let config = SimConfig::default();
+        let steering = config.max_steering;
+        let yaw_rate = steering.tan() / config.wheelbase;
+        let lap = 2.0 * PI / yaw_rate;
+
+        let half = Pose::default().advance(&config, 1.0, steering, lap / 2.0);
+        assert_close(0.0, half.x);
+        assert_close(2.0 / yaw_rate, half.y);
+        assert_close(PI, half.heading.abs());
+
+        let full = half.advance(&config, 1.0, steering, lap / 2.0);
+        assert_close(0.0, full.x);
+        assert_close(0.0, full.y);
+        assert_close(0.0, full.heading);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drive_loop() {
+        let sim = Simulation::new(SimConfig::default());
+        let mut motor = MotorSocket::simulated(&sim);
+        let mut wheels = WheelOrientation::simulated(&sim);
+
+        motor
+            .move_for(Velocity::forward(), Duration::from_millis(500))
+            .await
+            .unwrap();
+        let pose = sim.pose();
+        assert_close(0.5, pose.x);
+        assert_close(0.0, pose.y);
+        assert_eq!(0.0, sim.speed());
+
+        wheels.set(Angle::left()).await.unwrap();
+        motor
+            .move_for(Velocity::forward(), Duration::from_millis(500))
+            .await
+            .unwrap();
+        let pose = sim.pose();
+        assert!(pose.heading > 0.0, "turning left increases the heading");
+        assert!(pose.y > 0.0, "turning left drifts towards positive y");
+    }
+}
@@ -0,0 +1,215 @@
+use crate::raw::stepper::{StepDirection, StepStyle, StepperKit, DEFAULT_MICROSTEPS};
+use crate::raw::Motor;
+use crate::{sim, CarConfig, PwmController, RecordingPwm};
+use std::time::Duration;
+
+/// A stepper motor wired to the Motor HAT, e.g. to turn a turret
+pub struct Stepper {
+    kit: StepperKit,
+    pwm: Box<dyn PwmController + Send>,
+}
+
+impl Stepper {
+    /// Open the pins to talk to the given stepper ([`Motor::Stepper1`] or [`Motor::Stepper2`])
+    /// on the Motor HAT, which is connected according to the config
+    ///
+    /// Is backed by a [`RecordingPwm`] if the simulation backend is [enabled](sim::enabled).
+    pub async fn open(config: &CarConfig, motor: Motor) -> eyre::Result<Self> {
+        config.validate()?;
+        if sim::enabled() {
+            return Self::from_pwm(RecordingPwm::new(), motor);
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            Self::from_pwm(config.motor_pwm()?, motor)
+        }
+        #[cfg(not(target_os = "linux"))]
+        unreachable!("the simulation is always enabled off linux")
+    }
+
+    /// Drive the stepper through the given, already initialized, PWM controller
+    pub fn from_pwm(pwm: impl PwmController + Send + 'static, motor: Motor) -> eyre::Result<Self> {
+        let mut pwm: Box<dyn PwmController + Send> = Box::new(pwm);
+        let kit = StepperKit::try_new(&mut pwm, motor, DEFAULT_MICROSTEPS)?;
+        Ok(Self { kit, pwm })
+    }
+
+    /// The current position in microsteps, relative to where the stepper was opened
+    pub fn position(&self) -> i32 {
+        self.kit.position()
+    }
+
+    /// Move the stepper by `steps` steps, waiting `interval` after each of them
+    pub async fn step(
+        &mut self,
+        steps: u32,
+        direction: StepDirection,
+        style: StepStyle,
+        interval: Duration,
+    ) -> eyre::Result<i32> {
+        for _ in 0..steps {
+            self.kit.onestep(&mut self.pwm, direction, style)?;
+            tokio::time::sleep(interval).await;
+        }
+        Ok(self.kit.position())
+    }
+
+    /// Stop energizing the coils, which lets the stepper spin freely
+    pub fn release(&mut self) -> eyre::Result<()> {
+        self.kit.release(&mut self.pwm)?;
+        Ok(())
+    }
+}
+
+impl Drop for Stepper {
+    fn drop(&mut self) {
+        let _ = self.kit.stop(&mut self.pwm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Channel;
+
+    /// The duty cycles of the coils of [`Motor::Stepper1`], in the order they are energized
+    fn coils(pwm: &RecordingPwm) -> [u16; 4] {
+        [Channel::C9, Channel::C11, Channel::C10, Channel::C12].map(|c| pwm.duty_cycle(c))
+    }
+
+    /// Steps once at a time, returning the position and the coils after each step
+    async fn sequence(
+        stepper: &mut Stepper,
+        pwm: &RecordingPwm,
+        steps: u32,
+        direction: StepDirection,
+        style: StepStyle,
+    ) -> Vec<(i32, [u16; 4])> {
+        let mut sequence = Vec::new();
+        for _ in 0..steps {
+            let interval = Duration::from_millis(10);
+            let position = stepper.step(1, direction, style, interval).await.unwrap();
+            sequence.push((position, coils(pwm)));
+        }
+        sequence
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_full_steps() {
+        let pwm = RecordingPwm::new();
+        let mut stepper = Stepper::from_pwm(pwm.clone(), Motor::Stepper1).unwrap();
+        assert_eq!([4095, 0, 0, 0], coils(&pwm));
+
+        let single = sequence(
+            &mut stepper,
+            &pwm,
+            4,
+            StepDirection::Forward,
+            StepStyle::Single,
+        );
+        assert_eq!(
+            vec![
+                (16, [0, 4095, 0, 0]),
+                (32, [0, 0, 4095, 0]),
+                (48, [0, 0, 0, 4095]),
+                (64, [4095, 0, 0, 0]),
+            ],
+            single.await
+        );
+
+        let pwm = RecordingPwm::new();
+        let mut stepper = Stepper::from_pwm(pwm.clone(), Motor::Stepper1).unwrap();
+        let double = sequence(
+            &mut stepper,
+            &pwm,
+            4,
+            StepDirection::Forward,
+            StepStyle::Double,
+        );
+        assert_eq!(
+            vec![
+                (8, [4095, 4095, 0, 0]),
+                (24, [0, 4095, 4095, 0]),
+                (40, [0, 0, 4095, 4095]),
+                (56, [4095, 0, 0, 4095]),
+            ],
+            double.await
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_interleave_and_microsteps() {
+        let pwm = RecordingPwm::new();
+        let mut stepper = Stepper::from_pwm(pwm.clone(), Motor::Stepper1).unwrap();
+        let interleave = sequence(
+            &mut stepper,
+            &pwm,
+            4,
+            StepDirection::Forward,
+            StepStyle::Interleave,
+        );
+        assert_eq!(
+            vec![
+                (8, [4095, 4095, 0, 0]),
+                (16, [0, 4095, 0, 0]),
+                (24, [0, 4095, 4095, 0]),
+                (32, [0, 0, 4095, 0]),
+            ],
+            interleave.await
+        );
+
+        let pwm = RecordingPwm::new();
+        let mut stepper = Stepper::from_pwm(pwm.clone(), Motor::Stepper1).unwrap();
+        let microsteps = sequence(
+            &mut stepper,
+            &pwm,
+            16,
+            StepDirection::Forward,
+            StepStyle::Microstep,
+        );
+        let microsteps = microsteps.await;
+        assert_eq!(16, microsteps.len());
+        // the current moves from the first coil to the second along a sine curve
+        assert_eq!((8, [2896, 2896, 0, 0]), microsteps[7]);
+        assert_eq!((16, [0, 4095, 0, 0]), microsteps[15]);
+        for steps in microsteps.windows(2) {
+            let ([first, _, ..], [next, _, ..]) = (steps[0].1, steps[1].1);
+            assert!(next < first);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reversal() {
+        let pwm = RecordingPwm::new();
+        let mut stepper = Stepper::from_pwm(pwm.clone(), Motor::Stepper1).unwrap();
+        let interval = Duration::from_millis(10);
+        let position = stepper
+            .step(2, StepDirection::Forward, StepStyle::Single, interval)
+            .await
+            .unwrap();
+        assert_eq!(32, position);
+
+        let back = sequence(
+            &mut stepper,
+            &pwm,
+            3,
+            StepDirection::Backward,
+            StepStyle::Single,
+        );
+        assert_eq!(
+            vec![
+                (16, [0, 4095, 0, 0]),
+                (0, [4095, 0, 0, 0]),
+                (-16, [0, 0, 0, 4095]),
+            ],
+            back.await
+        );
+        assert_eq!(-16, stepper.position());
+
+        drop(stepper);
+        assert_eq!([0; 4], coils(&pwm));
+        assert_eq!(0, pwm.duty_cycle(Channel::C8));
+        assert_eq!(0, pwm.duty_cycle(Channel::C13));
+    }
+}
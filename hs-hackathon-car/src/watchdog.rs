@@ -0,0 +1,410 @@
+//! Deadlines and dead-man switches that cut the motor once they pass without being renewed.
+
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, Once, TryLockError, Weak};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Something that can bring the car to a halt in an emergency.
+pub(crate) trait Stop: Send {
+    fn stop(&mut self) -> eyre::Result<()>;
+
+    /// Stops, and refuses to start again from now on.
+    fn halt(&mut self) -> eyre::Result<()>;
+}
+
+/// How the watchdog thread waits for heartbeats.
+pub(crate) trait Clock: Send + 'static {
+    /// Waits up to `timeout` for the next heartbeat.
+    fn recv_timeout(
+        &mut self,
+        heartbeats: &mpsc::Receiver<()>,
+        timeout: Duration,
+    ) -> Result<(), RecvTimeoutError>;
+}
+
+/// Waits in real time.
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn recv_timeout(
+        &mut self,
+        heartbeats: &mpsc::Receiver<()>,
+        timeout: Duration,
+    ) -> Result<(), RecvTimeoutError> {
+        heartbeats.recv_timeout(timeout)
+    }
+}
+
+type Stoppable = Weak<Mutex<dyn Stop>>;
+
+lazy_static! {
+    /// Everything the panic hook and the signal handler stop.
+    static ref EMERGENCY_STOPS: Mutex<Vec<Stoppable>> = Mutex::new(Vec::new());
+}
+
+/// A [`Clock`] that only moves when the test [advances](ClockHand::advance) it.
+///
+/// Heartbeats are received once the clock is advanced next.
+#[cfg(test)]
+pub(crate) struct ManualClock {
+    advances: mpsc::Receiver<Duration>,
+    done: mpsc::Sender<()>,
+}
+
+/// Advances a [`ManualClock`].
+#[cfg(test)]
+pub(crate) struct ClockHand {
+    advances: mpsc::Sender<Duration>,
+    done: mpsc::Receiver<()>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub(crate) fn new() -> (Self, ClockHand) {
+        let (advances, rx) = mpsc::channel();
+        let (done, done_rx) = mpsc::channel();
+        let clock = Self { advances: rx, done };
+        let hand = ClockHand {
+            advances,
+            done: done_rx,
+        };
+        (clock, hand)
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn recv_timeout(
+        &mut self,
+        heartbeats: &mpsc::Receiver<()>,
+        timeout: Duration,
+    ) -> Result<(), RecvTimeoutError> {
+        let mut waited = Duration::ZERO;
+        loop {
+            let Ok(by) = self.advances.recv() else {
+                return Err(RecvTimeoutError::Disconnected);
+            };
+            waited += by;
+            let received = match heartbeats.try_recv() {
+                Ok(()) => Some(Ok(())),
+                Err(mpsc::TryRecvError::Disconnected) => Some(Err(RecvTimeoutError::Disconnected)),
+                Err(mpsc::TryRecvError::Empty) if waited >= timeout => {
+                    Some(Err(RecvTimeoutError::Timeout))
+                }
+                Err(mpsc::TryRecvError::Empty) => None,
+            };
+            let _ = self.done.send(());
+            if let Some(received) = received {
+                return received;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl ClockHand {
+    /// Moves the clock forward, once the watchdog dealt with the heartbeats sent so far.
+    pub(crate) fn advance(&self, by: Duration) {
+        self.advances.send(by).unwrap();
+        // the watchdog may have stopped in the meantime
+        let _ = self.done.recv();
+    }
+
+    /// Waits until the watchdog stopped the motor and exited.
+    pub(crate) fn join(&self) {
+        while self.done.recv().is_ok() {}
+    }
+}
+
+/// A dead-man switch for a [`MotorSocket`](crate::MotorSocket).
+///
+/// Created through [`MotorSocket::watchdog`](crate::MotorSocket::watchdog). Unless
+/// [`heartbeat`](Watchdog::heartbeat) is called at least once per timeout, the motor is
+/// stopped for good: setting a velocity fails from then on, until the motor is
+/// re-initialized. Dropping the last clone of the watchdog stops the motor as well.
+///
+/// The watchdog runs on a dedicated thread, so it also fires if the tokio runtime
+/// stalls. Creating the first watchdog installs a panic hook and, on unix, a
+/// SIGINT/SIGTERM handler that zero the PWM channels of every watched motor.
+///
+/// Note that the panic hook runs for every panic in the process, in any thread, before
+/// anyone gets to catch it. So a panic that is caught with [`std::panic::catch_unwind`],
+/// or by a tokio task or a thread that is joined, stops every watched motor for good too.
+#[derive(Clone)]
+pub struct Watchdog {
+    heartbeats: mpsc::Sender<()>,
+    tripped: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    pub(crate) fn spawn(
+        motor: Arc<Mutex<dyn Stop>>,
+        timeout: Duration,
+        mut clock: impl Clock,
+    ) -> eyre::Result<Self> {
+        install_emergency_stop();
+        register_emergency_stop(Arc::downgrade(&motor));
+
+        let (heartbeats, rx) = mpsc::channel();
+        let tripped = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&tripped);
+        std::thread::Builder::new()
+            .name("motor-watchdog".into())
+            .spawn(move || {
+                loop {
+                    match clock.recv_timeout(&rx, timeout) {
+                        Ok(()) => continue,
+                        Err(RecvTimeoutError::Timeout) => {
+                            tracing::error!("watchdog missed its heartbeat, stopping the motor");
+                            break;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => {
+                            tracing::debug!("watchdog dropped, stopping the motor");
+                            break;
+                        }
+                    }
+                }
+                let mut motor = motor.lock().unwrap_or_else(|p| p.into_inner());
+                if let Err(e) = motor.halt() {
+                    tracing::error!("watchdog failed to stop the motor: {e:?}");
+                }
+                flag.store(true, Ordering::SeqCst);
+            })?;
+
+        Ok(Self {
+            heartbeats,
+            tripped,
+        })
+    }
+
+    /// Signal that the control loop is still alive.
+    pub fn heartbeat(&self) {
+        // once tripped nobody listens anymore, which `tripped` reports
+        let _ = self.heartbeats.send(());
+    }
+
+    /// Whether the watchdog has stopped the motor.
+    pub fn tripped(&self) -> bool {
+        self.tripped.load(Ordering::SeqCst)
+    }
+}
+
+fn register_emergency_stop(motor: Stoppable) {
+    let mut stops = EMERGENCY_STOPS.lock().unwrap_or_else(|p| p.into_inner());
+    stops.retain(|stop| stop.strong_count() > 0);
+    stops.push(motor);
+}
+
+/// Zeroes the PWM channels of every watched motor.
+///
+/// Motors that are locked by someone else are skipped rather than waited for unless
+/// `blocking` is set, since a panic may well have happened while holding the lock.
+fn emergency_stop(blocking: bool) {
+    let stops = EMERGENCY_STOPS.lock().unwrap_or_else(|p| p.into_inner());
+    for motor in stops.iter().filter_map(Weak::upgrade) {
+        let mut motor = if blocking {
+            motor.lock().unwrap_or_else(|p| p.into_inner())
+        } else {
+            match motor.try_lock() {
+                Ok(motor) => motor,
+                Err(TryLockError::Poisoned(p)) => p.into_inner(),
+                Err(TryLockError::WouldBlock) => continue,
+            }
+        };
+        let _ = motor.halt();
+    }
+}
+
+fn install_emergency_stop() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            emergency_stop(false);
+            previous(info);
+        }));
+
+        #[cfg(unix)]
+        {
+            use signal_hook::consts::{SIGINT, SIGTERM};
+            use signal_hook::iterator::Signals;
+
+            match Signals::new([SIGINT, SIGTERM]) {
+                Ok(mut signals) => {
+                    let spawned = std::thread::Builder::new()
+                        .name("motor-signals".into())
+                        .spawn(move || {
+                            if let Some(signal) = signals.forever().next() {
+                                tracing::warn!("received signal {signal}, stopping the motor");
+                                emergency_stop(true);
+                                let _ = signal_hook::low_level::emulate_default_handler(signal);
+                            }
+                        });
+                    if let Err(e) = spawned {
+                        tracing::error!("failed to spawn the signal handler: {e:?}");
+                    }
+                }
+                Err(e) => tracing::error!("failed to install the signal handler: {e:?}"),
+            }
+        }
+    });
+}
+
+/// A deadline enforced by a background task.
+///
+/// Once the deadline passes without being [renewed](Deadline::renew) or
+/// [cleared](Deadline::clear), the task runs the expiry action. The task exits when the
+/// [`Deadline`] is dropped.
+pub(crate) struct Deadline {
+    tx: watch::Sender<Option<Instant>>,
+}
+
+impl Deadline {
+    /// Spawns the task enforcing the deadline onto the current tokio runtime.
+    ///
+    /// The deadline starts out cleared.
+    pub(crate) fn spawn(mut on_expiry: impl FnMut() + Send + 'static) -> Self {
+        let (tx, mut rx) = watch::channel(None);
+        tokio::spawn(async move {
+            loop {
+                let deadline = *rx.borrow_and_update();
+                match deadline {
+                    Some(at) => {
+                        tokio::select! {
+                            _ = tokio::time::sleep_until(at) => {
+                                on_expiry();
+                                if rx.changed().await.is_err() {
+                                    return;
+                                }
+                            }
+                            changed = rx.changed() => {
+                                if changed.is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                    None => {
+                        if rx.changed().await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// Moves the deadline to `timeout` from now.
+    pub(crate) fn renew(&self, timeout: Duration) {
+        self.tx.send_replace(Some(Instant::now() + timeout));
+    }
+
+    /// Disarms the deadline until it is renewed again.
+    pub(crate) fn clear(&self) {
+        self.tx.send_replace(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClockHand, ManualClock};
+    use crate::{Channel, MotorSocket, RecordingPwm, Velocity};
+    use std::time::Duration;
+
+    fn watched(timeout: Duration) -> (RecordingPwm, MotorSocket, super::Watchdog, ClockHand) {
+        let pwm = RecordingPwm::new();
+        let mut motor = MotorSocket::from_pwm(pwm.clone()).unwrap();
+        motor.set_command_timeout(Duration::from_secs(1));
+        let (clock, hand) = ManualClock::new();
+        let watchdog = motor.watchdog_with(timeout, clock).unwrap();
+        (pwm, motor, watchdog, hand)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeats_keep_motor_running() {
+        let (pwm, mut motor, watchdog, clock) = watched(Duration::from_millis(100));
+
+        motor.set_velocity(Velocity::forward()).await.unwrap();
+        for _ in 0..5 {
+            clock.advance(Duration::from_millis(60));
+            watchdog.heartbeat();
+        }
+        clock.advance(Duration::from_millis(60));
+        assert!(!watchdog.tripped());
+        assert_eq!(4095, pwm.duty_cycle(Channel::C8));
+        assert_eq!(4095, pwm.duty_cycle(Channel::C10));
+
+        // the control loop hangs
+        clock.advance(Duration::from_millis(100));
+        clock.join();
+        assert!(watchdog.tripped());
+        assert_eq!(0, pwm.duty_cycle(Channel::C8));
+        assert_eq!(0, pwm.duty_cycle(Channel::C10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tripped_watchdog_latches() {
+        let (pwm, mut motor, watchdog, clock) = watched(Duration::from_millis(100));
+        clock.advance(Duration::from_millis(100));
+        clock.join();
+        assert!(watchdog.tripped());
+
+        assert!(motor.set_velocity(Velocity::forward()).await.is_err());
+        let moved = motor.move_for(Velocity::forward(), Duration::from_millis(500));
+        assert!(moved.await.is_err());
+        motor.set_ramp(Some(crate::Ramp::linear(2.0))).unwrap();
+        assert!(motor.set_velocity(Velocity::backward()).await.is_err());
+        assert_eq!(0, pwm.duty_cycle(Channel::C8));
+        assert_eq!(0, pwm.duty_cycle(Channel::C9));
+        assert_eq!(0, pwm.duty_cycle(Channel::C10));
+
+        // stopping still works
+        motor.set_velocity(Velocity::none()).await.unwrap();
+        motor.stop().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dropping_watchdog_stops_motor() {
+        let (pwm, mut motor, watchdog, clock) = watched(Duration::from_secs(10));
+
+        motor.set_velocity(Velocity::forward()).await.unwrap();
+        let tripped = watchdog.clone();
+        drop(watchdog);
+        clock.advance(Duration::ZERO);
+        assert!(!tripped.tripped());
+        drop(tripped);
+
+        clock.advance(Duration::ZERO);
+        clock.join();
+        assert_eq!(0, pwm.duty_cycle(Channel::C8));
+        assert!(motor.set_velocity(Velocity::forward()).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_panic_stops_motor() {
+        // a panic stops every watched motor in the process, so this runs in a process of its own
+        const CHILD: &str = "HS_HACKATHON_WATCHDOG_PANIC";
+        if std::env::var_os(CHILD).is_none() {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "watchdog::tests::test_panic_stops_motor"])
+                .env(CHILD, "1")
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success(), "{stdout}");
+            assert!(stdout.contains("1 passed"), "{stdout}");
+            return;
+        }
+
+        let (pwm, mut motor, _watchdog, _clock) = watched(Duration::from_secs(10));
+        motor.set_velocity(Velocity::forward()).await.unwrap();
+        assert_eq!(4095, pwm.duty_cycle(Channel::C8));
+        let _ = std::thread::spawn(|| panic!("control loop bug")).join();
+        assert_eq!(0, pwm.duty_cycle(Channel::C8));
+    }
+}
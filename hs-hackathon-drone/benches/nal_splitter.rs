@@ -0,0 +1,62 @@
+//! How fast the drone's video is split into NAL units, as it arrives and all at once.
+//!
+//! Run with `cargo bench -p hs-hackathon-drone`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use hs_hackathon_drone::h264::NalSplitter;
+
+/// The payload of a UDP packet from the drone.
+const PACKET_SIZE: usize = 1460;
+
+/// An annex-B stream of `count` NAL units of `size` bytes, with start codes of both lengths
+fn stream(count: usize, size: usize) -> Vec<u8> {
+    let mut stream = Vec::with_capacity(count * (size + 4));
+    let mut state = 0x2545_f491_u32;
+    for i in 0..count {
+        stream.extend_from_slice(if i % 2 == 0 {
+            &[0, 0, 0, 1]
+        } else {
+            &[0, 0, 1]
+        });
+        stream.push(0x41);
+        for _ in 1..size {
+            // xorshift, so the payload looks like coded video without ever emulating a start code
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            stream.push((state as u8).max(2));
+        }
+    }
+    stream
+}
+
+fn split(stream: &[u8], packet_size: usize) -> usize {
+    let mut splitter = NalSplitter::new();
+    let mut split = 0;
+    for packet in stream.chunks(packet_size) {
+        splitter.push(packet);
+        while let Some(nal) = splitter.next_nal() {
+            split += nal.len();
+        }
+    }
+    split + splitter.finish().map_or(0, <[u8]>::len)
+}
+
+fn bench_split(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nal_splitter");
+    // small P frames, and I frames spanning dozens of packets
+    for size in [500, 50_000] {
+        let stream = stream(4 * 1024 * 1024 / size, size);
+        group.throughput(Throughput::Bytes(stream.len() as u64));
+        group.bench_with_input(BenchmarkId::new("packets", size), &stream, |b, stream| {
+            b.iter(|| split(stream, PACKET_SIZE))
+        });
+        group.bench_with_input(BenchmarkId::new("whole", size), &stream, |b, stream| {
+            b.iter(|| split(stream, stream.len()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_split);
+criterion_main!(benches);
@@ -0,0 +1,209 @@
+//! Hovers the drone so that the markers at the corners of the arena are in frame.
+//!
+//! The camera of the drone faces forward, so markers too far left are brought into frame by
+//! flying left, markers too high by flying up, and markers spread too wide by backing off.
+
+use hs_hackathon_drone::{Command, Drone, DroneError, Frame, Response};
+use hs_hackathon_vision::{detect, BoundingBox, LedDetectionConfig};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::watch;
+#[allow(unused_imports)]
+use tracing::{debug, info, trace, warn};
+
+/// How far each correction moves the drone, the least it can move.
+const STEP: u32 = 20;
+
+/// How long to let the drone settle after a correction before looking again.
+const SETTLE: Duration = Duration::from_secs(1);
+
+/// How many frames in a row need to be framed for the framing to be stable.
+const STABLE_FRAMES: usize = 3;
+
+/// What to frame, and how
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FramingConfig {
+    /// How many markers there are at the corners of the arena
+    pub markers: usize,
+    /// How far the markers need to stay from the edges, as a fraction of the frame
+    pub margin: f32,
+    /// How many corrections to try before giving up
+    pub max_steps: usize,
+}
+
+/// How far auto-framing got
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum FramingStatus {
+    /// Auto-framing has not been started
+    #[default]
+    Idle,
+    /// The drone is being moved, with the given number of corrections so far
+    Framing { steps: usize },
+    /// All markers are in frame, after the given number of corrections
+    Framed { steps: usize },
+    /// Auto-framing gave up
+    Failed { reason: String },
+}
+
+/// What to do about the markers seen in a `width`x`height` frame
+#[derive(Debug, Clone, PartialEq)]
+pub enum Correction {
+    /// All markers are in frame, with the margin to spare
+    Framed,
+    /// Move the drone to bring the markers into frame
+    Move(Command),
+}
+
+impl FramingConfig {
+    /// The correction that brings `markers` seen in a `width`x`height` frame into frame.
+    pub fn correction(&self, markers: &[BoundingBox], width: u32, height: u32) -> Correction {
+        // with markers out of sight, there is no telling where they are but further out
+        if markers.len() < self.markers {
+            return Correction::Move(Command::Back { cm: STEP });
+        }
+        let (x_min, y_min, x_max, y_max) = markers.iter().fold(
+            (u32::MAX, u32::MAX, 0, 0),
+            |(x_min, y_min, x_max, y_max), marker| {
+                (
+                    x_min.min(marker.x_min()),
+                    y_min.min(marker.y_min()),
+                    x_max.max(marker.x_max()),
+                    y_max.max(marker.y_max()),
+                )
+            },
+        );
+        let (width, height) = (width as f32, height as f32);
+        let (left, right) = (self.margin * width, (1.0 - self.margin) * width);
+        let (top, bottom) = (self.margin * height, (1.0 - self.margin) * height);
+        let (x_min, y_min, x_max, y_max) = (x_min as f32, y_min as f32, x_max as f32, y_max as f32);
+
+        let command = if x_max - x_min > right - left || y_max - y_min > bottom - top {
+            Command::Back { cm: STEP }
+        } else if x_min < left {
+            Command::Left { cm: STEP }
+        } else if x_max > right {
+            Command::Right { cm: STEP }
+        } else if y_min < top {
+            Command::Up { cm: STEP }
+        } else if y_max > bottom {
+            Command::Down { cm: STEP }
+        } else {
+            return Correction::Framed;
+        };
+        Correction::Move(command)
+    }
+}
+
+/// Moves the drone until the markers are stably in frame, publishing how far it got
+pub async fn frame(
+    config: FramingConfig,
+    drone: &Drone,
+    mut camera: watch::Receiver<Frame>,
+    led_config: &LedDetectionConfig,
+    status: &watch::Sender<FramingStatus>,
+) {
+    let done = match frame_arena(config, drone, &mut camera, led_config, status).await {
+        Ok(steps) => {
+            info!("framed after {steps} corrections");
+            FramingStatus::Framed { steps }
+        }
+        Err(reason) => {
+            warn!("failed to frame: {reason}");
+            FramingStatus::Failed { reason }
+        }
+    };
+    status.send_replace(done);
+}
+
+async fn frame_arena(
+    config: FramingConfig,
+    drone: &Drone,
+    camera: &mut watch::Receiver<Frame>,
+    led_config: &LedDetectionConfig,
+    status: &watch::Sender<FramingStatus>,
+) -> Result<usize, String> {
+    let mut steps = 0;
+    let mut stable = 0;
+    while stable < STABLE_FRAMES {
+        status.send_replace(FramingStatus::Framing { steps });
+        camera
+            .changed()
+            .await
+            .map_err(|_| String::from("the video stream ended"))?;
+        let frame = camera.borrow_and_update().image.clone();
+        let markers = detect(&frame, led_config).map_err(|e| format!("detect markers: {e}"))?;
+        let markers: Vec<_> = markers.into_iter().map(|led| led.bbox).collect();
+        let command = match config.correction(&markers, frame.width(), frame.height()) {
+            Correction::Framed => {
+                stable += 1;
+                trace!("framed in {stable} frames in a row");
+                continue;
+            }
+            Correction::Move(command) => command,
+        };
+        stable = 0;
+        if steps == config.max_steps {
+            return Err(format!("not framed after {steps} corrections"));
+        }
+        debug!(
+            "{} markers in sight, correcting with `{command}`",
+            markers.len()
+        );
+        match drone.send(command.clone()).await {
+            Ok(Response::Ok) => {}
+            Ok(response) => return Err(format!("`{command}` failed: {response:?}")),
+            Err(DroneError::Unsafe(e)) => return Err(e.to_string()),
+            Err(e) => return Err(format!("`{command}` failed: {e}")),
+        }
+        steps += 1;
+        tokio::time::sleep(SETTLE).await;
+        // only look at frames from after the drone settled
+        camera.borrow_and_update();
+    }
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: FramingConfig = FramingConfig {
+        markers: 4,
+        margin: 0.1,
+        max_steps: 20,
+    };
+
+    fn markers(corners: [(u32, u32); 4]) -> Vec<BoundingBox> {
+        corners
+            .into_iter()
+            .map(|(x, y)| BoundingBox::new(x, y, x + 10, y + 10).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_correction() {
+        let centred = markers([(200, 200), (700, 200), (200, 500), (700, 500)]);
+        assert_eq!(Correction::Framed, CONFIG.correction(&centred, 960, 720));
+        assert_eq!(
+            Correction::Move(Command::Back { cm: STEP }),
+            CONFIG.correction(&centred[..3], 960, 720)
+        );
+
+        let left = markers([(50, 200), (600, 200), (50, 500), (600, 500)]);
+        assert_eq!(
+            Correction::Move(Command::Left { cm: STEP }),
+            CONFIG.correction(&left, 960, 720)
+        );
+        let low = markers([(200, 300), (700, 300), (200, 690), (700, 690)]);
+        assert_eq!(
+            Correction::Move(Command::Down { cm: STEP }),
+            CONFIG.correction(&low, 960, 720)
+        );
+        let wide = markers([(50, 200), (900, 200), (50, 500), (900, 500)]);
+        assert_eq!(
+            Correction::Move(Command::Back { cm: STEP }),
+            CONFIG.correction(&wide, 960, 720)
+        );
+    }
+}
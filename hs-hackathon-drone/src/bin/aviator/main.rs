@@ -1,51 +1,65 @@
 use axum::{
+    body::Body,
     extract::{Query, State},
-    http::{header, StatusCode},
-    response::IntoResponse,
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
     routing::{get, post},
     Json, Router,
 };
 use clap::Parser;
 use eyre::Context;
 use hs_hackathon_vision::{detect, draw_on_image, LedDetectionConfig};
-use image::{DynamicImage, RgbImage};
+use image::DynamicImage;
 use imageproc::drawing::draw_text_mut;
 use rusttype::{Font, Scale};
 use serde::{Deserialize, Serialize};
+use session::{Record, Recorder};
 use std::{
     collections::HashMap,
+    future::Future,
     io::Cursor,
     net::SocketAddr,
+    path::PathBuf,
     sync::{Arc, OnceLock},
     time::{Duration, Instant},
 };
 use tokio::{
-    net::UdpSocket,
-    sync::{mpsc, oneshot, watch, Mutex},
+    net::{TcpListener, UdpSocket},
+    sync::{broadcast::error::RecvError, watch},
+    task::JoinHandle,
 };
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn};
 use tracing_core::LevelFilter;
 use tracing_subscriber::EnvFilter;
+mod framing;
 mod raw;
-use raw::control::Command;
+mod session;
+use framing::{FramingConfig, FramingStatus};
+use futures::Stream;
+use hs_hackathon_drone::camera::{part_header, STREAM_BOUNDARY};
+use hs_hackathon_drone::{
+    Command, Drone, DroneError, FailsafeState, Frame, Response, SafetyEnvelope, Telemetry,
+};
+use raw::h264::{FrameDecoder, VideoStats};
 use tracing::Instrument;
 
 pub const FONT_DATA: &[u8] = include_bytes!("../../../DejaVuSans.ttf");
 static FONT: OnceLock<Font<'static>> = OnceLock::new();
 
 struct AppState {
-    camera: watch::Receiver<image::RgbImage>,
-    drone: Mutex<Drone>,
+    camera: watch::Receiver<Frame>,
+    /// `None` while replaying a session
+    drone: Option<Drone>,
+    telemetry: watch::Receiver<Option<Telemetry>>,
+    failsafe: watch::Receiver<FailsafeState>,
+    video: Arc<watch::Sender<VideoStats>>,
     led_config: LedDetectionConfig,
-}
-
-struct Drone {
-    battery: i32,
-    altitude: i32,
-    moved_x: i8,
-    moved_y: i8,
-    task: mpsc::Sender<(Command, tokio::sync::oneshot::Sender<String>)>,
+    framing_config: FramingConfig,
+    framing: watch::Sender<FramingStatus>,
 }
 
 #[derive(Parser, Debug)]
@@ -78,188 +92,236 @@ struct Args {
     /// Maximum width for a detected bounding box
     #[arg(long, default_value_t = 20, required = false)]
     max_size_height: u32,
+
+    /// Where to send commands to the drone
+    #[arg(long, default_value = "192.168.10.1:8889")]
+    drone: SocketAddr,
+
+    /// Where to send commands from, and receive their answers
+    #[arg(long, default_value = "0.0.0.0:8889")]
+    command_bind: SocketAddr,
+
+    /// Where to receive the telemetry of the drone
+    #[arg(long, default_value = "0.0.0.0:8890")]
+    state_bind: SocketAddr,
+
+    /// Where to receive the video stream of the drone
+    #[arg(long, default_value = "0.0.0.0:11111")]
+    video_bind: SocketAddr,
+
+    /// Where to serve the HTTP API
+    #[arg(long, default_value = "0.0.0.0:3000")]
+    listen: SocketAddr,
+
+    /// How far in cm the drone may fly forward or backward of where it took off
+    #[arg(long, default_value_t = 60)]
+    max_x: i32,
+
+    /// How far in cm the drone may fly left or right of where it took off
+    #[arg(long, default_value_t = 60)]
+    max_y: i32,
+
+    /// How high in cm the drone may fly
+    #[arg(long, default_value_t = 180)]
+    ceiling: i32,
+
+    /// Battery percentage at which to warn that the drone will land soon
+    #[arg(long, default_value_t = 20)]
+    warn_battery: i32,
+
+    /// Battery percentage at which the drone lands
+    #[arg(long, default_value_t = 10)]
+    land_battery: i32,
+
+    /// Temperature in °C at which the drone lands
+    #[arg(long, default_value_t = 85)]
+    max_temperature: i32,
+
+    /// Seconds without telemetry after which the drone lands
+    #[arg(long, default_value_t = 3)]
+    telemetry_timeout: u64,
+
+    /// How many markers at the corners of the arena auto-framing looks for
+    #[arg(long, default_value_t = 4)]
+    frame_markers: usize,
+
+    /// How far the markers need to stay from the edges, as a fraction of the frame
+    #[arg(long, default_value_t = 0.1)]
+    frame_margin: f32,
+
+    /// How many corrections auto-framing tries before giving up
+    #[arg(long, default_value_t = 20)]
+    frame_max_steps: usize,
+
+    /// Record the video, telemetry and commands of the flight into this session file
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Serve a recorded session file instead of flying a drone
+    #[arg(long, conflicts_with = "record")]
+    replay: Option<PathBuf>,
+
+    /// How many times as fast as it was recorded to replay a session
+    #[arg(long, default_value_t = 1.0)]
+    replay_speed: f64,
+}
+
+/// What the aviator listens on, bound before anything starts
+struct Sockets {
+    /// Where the HTTP API is served
+    listen: TcpListener,
+    /// Where the aviator talks to the drone, unless it replays a session
+    drone: Option<DroneSockets>,
+}
+
+/// Where commands are sent from, and the telemetry and the video stream received
+struct DroneSockets {
+    command: UdpSocket,
+    state: UdpSocket,
+    video: UdpSocket,
+}
+
+impl Sockets {
+    async fn bind(args: &Args) -> color_eyre::Result<Self> {
+        let listen = TcpListener::bind(args.listen)
+            .await
+            .wrap_err("bind to http socket")?;
+        let drone = match args.replay {
+            Some(_) => None,
+            None => Some(DroneSockets {
+                command: UdpSocket::bind(args.command_bind)
+                    .await
+                    .wrap_err("bind to command socket")?,
+                state: UdpSocket::bind(args.state_bind)
+                    .await
+                    .wrap_err("bind to state receive socket")?,
+                video: UdpSocket::bind(args.video_bind)
+                    .await
+                    .wrap_err("bind to video receive socket")?,
+            }),
+        };
+        Ok(Self { listen, drone })
+    }
 }
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
     let args = Args::parse();
 
-    let mut led_config = LedDetectionConfig::default();
-    led_config.threshold_value = args.threshold;
-    led_config.width = args.width;
-    led_config.height = args.height;
-    led_config.max_size = (args.max_size_width, args.max_size_height);
-    led_config.min_size = (args.min_size_width, args.min_size_height);
-
-    println!("Led configuration: {:?}", led_config);
-
     let filter = EnvFilter::builder()
         .with_default_directive(LevelFilter::DEBUG.into())
         .from_env()
         .expect("internal error: failed to setup tracing");
     tracing_subscriber::fmt().with_env_filter(filter).init();
 
-    let (frame_tx, frame_rx) = watch::channel(RgbImage::new(960, 720));
-    let (command_tx, command_rx) = mpsc::channel(1);
-    let (syn, ack) = oneshot::channel();
-    command_tx.try_send((Command::SDKInit, syn)).unwrap();
-    let drone = Drone {
-        battery: 0,
-        altitude: 0,
-        moved_x: 0,
-        moved_y: 0,
-        task: command_tx,
+    run(args).await
+}
+
+async fn run(args: Args) -> color_eyre::Result<()> {
+    let sockets = Sockets::bind(&args).await?;
+    serve(args, sockets).await
+}
+
+async fn serve(args: Args, sockets: Sockets) -> color_eyre::Result<()> {
+    let led_config = LedDetectionConfig {
+        threshold_value: args.threshold,
+        width: args.width,
+        height: args.height,
+        max_size: (args.max_size_width, args.max_size_height),
+        min_size: (args.min_size_width, args.min_size_height),
+        ..Default::default()
     };
-    let shared_state = Arc::new(AppState {
-        drone: Mutex::new(drone),
-        camera: frame_rx,
-        led_config,
-    });
 
-    // spawn video capturer
-    let vidcap = tokio::spawn(
-        async move {
-            let recv_socket = UdpSocket::bind(SocketAddr::from((raw::VID_ADDR, raw::VID_PORT)))
-                .await
-                .wrap_err("bind to video receive socket")?;
-            raw::h264::watch_latest_frame(frame_tx, recv_socket)
-                .await
-                .wrap_err("watch for h264 frames")?;
-            Ok::<_, color_eyre::Report>(())
+    println!("Led configuration: {:?}", led_config);
+
+    let (frame_tx, frame_rx) = watch::channel(raw::h264::blank_frame(960, 720));
+    let video = Arc::new(watch::channel(VideoStats::default()).0);
+    let (drone, telemetry, vidcap) = match (args.replay.clone(), sockets.drone) {
+        (Some(path), _) => {
+            let (telemetry_tx, telemetry) = watch::channel(None);
+            let frames = FrameDecoder::new(frame_tx, telemetry.clone(), Arc::clone(&video))?;
+            let speed = args.replay_speed;
+            let replay = tokio::spawn(
+                session::replay(path, speed, frames, telemetry_tx)
+                    .instrument(tracing::info_span!("replay")),
+            );
+            (None, telemetry, replay)
         }
-        .instrument(tracing::info_span!("video")),
-    );
-    // spawn command dispatcher
-    let dispatcher = tokio::spawn(async move {
-        raw::control::send_commands(command_rx)
-            .instrument(tracing::info_span!("command"))
-            .await
-            .wrap_err("open command loop")?;
-        Ok::<_, color_eyre::Report>(())
-    });
-    // spawn state tracker
-    let for_spawn = Arc::clone(&shared_state);
-    let tracker = tokio::spawn(
+        (None, Some(bound)) => {
+            let (drone, vidcap) = fly(&args, bound, frame_tx, Arc::clone(&video)).await?;
+            let telemetry = drone.watch_state();
+            (Some(drone), telemetry, vidcap)
+        }
+        (None, None) => eyre::bail!("there are no sockets to talk to the drone"),
+    };
+
+    // log the state of the drone every now and then
+    let mut states = telemetry.clone();
+    tokio::spawn(
         async move {
-            debug!("started");
-            let shared_state = for_spawn;
-            let socket = UdpSocket::bind(SocketAddr::from((raw::RCV_ADDR, raw::RCV_PORT)))
-                .await
-                .wrap_err("bind tracker")?;
-            let mut buffer = [0u8; 2000];
             let mut every = Instant::now();
-            loop {
-                trace!("await update");
-                let size = socket.recv(&mut buffer).await.wrap_err("recv")?;
-                trace!("got update");
-                let received = String::from_utf8(buffer[0..size].to_vec())?;
-                if let Ok(raw::sensors::State { h, bat, .. }) =
-                    received.parse::<raw::sensors::State>()
-                {
-                    if every.elapsed() > Duration::from_secs(5) {
-                        info!("drone @ {h:03}cm, {bat:02}% battery");
-                        every = Instant::now();
-                    } else {
-                        trace!("drone @ {h:03}cm, {bat:02}% battery");
-                    }
-                    let mut drone = shared_state.drone.lock().await;
-                    drone.battery = bat;
-                    drone.altitude = h;
+            while states.changed().await.is_ok() {
+                let (h, bat) = match &*states.borrow_and_update() {
+                    Some(telemetry) => (telemetry.state.h, telemetry.state.bat),
+                    None => continue,
+                };
+                if every.elapsed() > Duration::from_secs(5) {
+                    info!("drone @ {h:03}cm, {bat:02}% battery");
+                    every = Instant::now();
                 } else {
-                    warn!("Invalid drone state: {received}");
+                    trace!("drone @ {h:03}cm, {bat:02}% battery");
                 }
             }
-            #[allow(unreachable_code)]
-            Ok::<_, color_eyre::Report>(())
         }
         .instrument(tracing::info_span!("state")),
     );
 
-    // wait for sdk init to be acked
-    debug!("wait for sdk-init to complete");
-    ack.await.wrap_err("ack sdk-init")?;
-
-    // start the video stream
-    debug!("starting video stream");
-    let (syn, ack) = oneshot::channel();
-    {
-        shared_state
-            .drone
-            .lock()
-            .await
-            .task
-            .try_send((Command::EnableStream, syn))
-            .unwrap();
-    }
-    ack.await.wrap_err("ack enable-stream")?;
-
-    info!("drone ready");
-
-    // every 5 seconds, heartbeat
-    let for_spawn = Arc::clone(&shared_state);
-    tokio::spawn(
-        async move {
-            debug!("started");
-            let shared_state = for_spawn;
-            loop {
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                debug!("thud");
-                let (syn, ack) = oneshot::channel();
-                let drone = shared_state.drone.lock().await;
-                if let Err(e) = drone.task.try_send((Command::Stop, syn)) {
-                    match e {
-                        mpsc::error::TrySendError::Closed(_) => {
-                            debug!("exiting since command channel is closed");
-                            // time to exit
-                            break Ok::<_, color_eyre::Report>(());
-                        }
-                        mpsc::error::TrySendError::Full(_) => {
-                            // fine -- means there are commands flowing
-                            debug!("skip");
-                            continue;
-                        }
-                    }
-                }
-                drop(drone);
-                trace!("thud-ack");
-                // if a command fails to ack (eg, because drone shuts down),
-                // we don't want the heartbeat loop to stop!
-                match ack.await {
-                    Ok(s) => debug!("wait after {s}"),
-                    Err(_) => debug!("wait despite no ack"),
-                }
-            }
-        }
-        .instrument(tracing::info_span!("heartbeat")),
-    );
+    let failsafes = match &drone {
+        Some(drone) => drone.watch_failsafe(),
+        None => watch::channel(FailsafeState::Nominal).1,
+    };
+    let shared_state = Arc::new(AppState {
+        drone,
+        telemetry,
+        failsafe: failsafes,
+        video,
+        camera: frame_rx,
+        led_config,
+        framing_config: FramingConfig {
+            markers: args.frame_markers,
+            margin: args.frame_margin,
+            max_steps: args.frame_max_steps,
+        },
+        framing: watch::channel(FramingStatus::Idle).0,
+    });
 
     let server = tokio::spawn(
         async move {
             let app = Router::new()
                 .route("/", get(root))
                 .route("/camera", get(camera))
+                .route("/camera/stream", get(camera_stream))
+                .route("/video/stats", get(video_stats))
+                .route("/state", get(state))
+                .route("/state/stream", get(state_stream))
+                .route("/failsafe", get(failsafe))
+                .route("/failsafe/stream", get(failsafe_stream))
                 .route("/nudge", post(nudge))
+                .route("/frame", get(framing).post(start_framing))
                 .with_state(shared_state);
 
-            let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
-            axum::serve(listener, app).await?;
+            axum::serve(sockets.listen, app).await?;
             #[allow(unreachable_code)]
             Ok::<_, color_eyre::Report>(())
         }
         .instrument(tracing::info_span!("http")),
     );
 
-    match tokio::try_join!(vidcap, dispatcher, tracker, server) {
-        Ok((vidcap, dispatcher, tracker, server)) => {
+    match tokio::try_join!(vidcap, server) {
+        Ok((vidcap, server)) => {
             if let Err(e) = vidcap {
                 error!("video capture task failed: {e:?}");
             }
-            if let Err(e) = dispatcher {
-                error!("dispatcher failed: {e:?}");
-            }
-            if let Err(e) = tracker {
-                error!("drone state tracker failed: {e:?}");
-            }
             if let Err(e) = server {
                 error!("http server failed: {e:?}");
             }
@@ -272,6 +334,100 @@ async fn main() -> color_eyre::Result<()> {
     Ok(())
 }
 
+/// Connects to the drone and starts its video stream, recording the flight if asked to
+async fn fly(
+    args: &Args,
+    sockets: DroneSockets,
+    frame_tx: watch::Sender<Frame>,
+    video: Arc<watch::Sender<VideoStats>>,
+) -> color_eyre::Result<(Drone, JoinHandle<color_eyre::Result<()>>)> {
+    debug!("wait for sdk-init to complete");
+    let mut drone = Drone::connect_with(args.drone, sockets.command, sockets.state)
+        .await
+        .wrap_err("connect to drone")?;
+    drone.set_envelope(Some(SafetyEnvelope {
+        max_x: args.max_x,
+        max_y: args.max_y,
+        ceiling: args.ceiling,
+        warn_battery: args.warn_battery,
+        land_battery: args.land_battery,
+        max_temperature: args.max_temperature,
+        telemetry_timeout: Duration::from_secs(args.telemetry_timeout),
+    }));
+
+    let recorder = match &args.record {
+        Some(path) => {
+            let (recorder, writer) = Recorder::create(path)?;
+            info!("recording into {}", path.display());
+            tokio::spawn(record(recorder.clone(), &drone, writer));
+            Some(recorder)
+        }
+        None => None,
+    };
+
+    // spawn video capturer before the drone starts streaming
+    let frames = FrameDecoder::new(frame_tx, drone.watch_state(), video)?;
+    let vidcap = tokio::spawn(
+        async move {
+            raw::h264::watch_latest_frame(frames, sockets.video, recorder)
+                .await
+                .wrap_err("watch for h264 frames")?;
+            Ok::<_, color_eyre::Report>(())
+        }
+        .instrument(tracing::info_span!("video")),
+    );
+
+    // start the video stream
+    debug!("starting video stream");
+    drone
+        .send_ok(Command::EnableStream)
+        .await
+        .wrap_err("ack enable-stream")?;
+
+    info!("drone ready");
+    Ok((drone, vidcap))
+}
+
+/// Records the telemetry and commands of `drone` until it is gone
+fn record(
+    recorder: Recorder,
+    drone: &Drone,
+    writer: JoinHandle<color_eyre::Result<()>>,
+) -> impl Future<Output = ()> {
+    let mut states = drone.watch_state();
+    let mut commands = drone.watch_commands();
+    async move {
+        loop {
+            tokio::select! {
+                changed = states.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let state = states.borrow_and_update().as_ref().map(|t| t.state.clone());
+                    if let Some(state) = state {
+                        recorder.record(Record::Telemetry(state));
+                    }
+                }
+                answered = commands.recv() => match answered {
+                    Ok((command, response)) => recorder.record(Record::Command {
+                        command,
+                        response: response.to_string(),
+                    }),
+                    Err(RecvError::Lagged(missed)) => warn!("missed {missed} commands"),
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+        drop(recorder);
+        match writer.await {
+            Ok(Ok(())) => info!("recording complete"),
+            Ok(Err(e)) => error!("recording failed: {e:?}"),
+            Err(e) => error!("recording task panicked: {e:?}"),
+        }
+    }
+    .instrument(tracing::info_span!("record"))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 enum Direction {
@@ -301,20 +457,34 @@ impl From<color_eyre::Report> for Oof {
     }
 }
 
+impl AppState {
+    /// The drone, unless a session is being replayed
+    fn drone(&self) -> Result<&Drone, Oof> {
+        self.drone.as_ref().ok_or_else(|| {
+            Oof(
+                StatusCode::CONFLICT,
+                String::from("replaying a recorded session, there is no drone to fly"),
+            )
+        })
+    }
+}
+
 async fn root(State(_): State<Arc<AppState>>) {}
 
 async fn camera(
     State(state): State<Arc<AppState>>,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<impl IntoResponse, Oof> {
-    let bat = {
-        let drone = state.drone.lock().await;
-        drone.battery
-    };
+    let bat = state
+        .telemetry
+        .borrow()
+        .as_ref()
+        .map_or(0, |telemetry| telemetry.state.bat);
 
-    let image = state.camera.borrow();
-    let mut dyn_image: DynamicImage = RgbImage::clone(&image).into();
-    drop(image);
+    let failsafe = *state.failsafe.borrow();
+
+    let frame = state.camera.borrow().clone();
+    let mut dyn_image = frame.image.clone();
 
     if !params.contains_key("clean") {
         // draw battery %
@@ -326,85 +496,361 @@ async fn camera(
             5,
             5,
             Scale::uniform(25.0),
-            &font,
+            font,
             format!("Battery: {:02}%", bat).as_str(),
         );
-        let leds = detect(&dyn_image, &state.led_config)?;
+        if failsafe != FailsafeState::Nominal {
+            draw_text_mut(
+                &mut dyn_image,
+                [255, 0, 0, 128].into(),
+                5,
+                35,
+                Scale::uniform(25.0),
+                font,
+                format!("Failsafe: {failsafe}").as_str(),
+            );
+        }
+        let leds = detect(&dyn_image, &state.led_config).wrap_err("detect leds")?;
         leds.into_iter()
             .for_each(|led| draw_on_image(&mut dyn_image, led));
     }
 
+    let bytes = jpeg(&dyn_image).wrap_err("write image")?;
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"));
+    for (name, value) in frame.headers() {
+        let value = HeaderValue::try_from(value).wrap_err("frame header")?;
+        headers.insert(HeaderName::from_static(name), value);
+    }
+    Ok((headers, bytes))
+}
+
+/// How well decoding keeps up with the drone, as JSON
+async fn video_stats(State(state): State<Arc<AppState>>) -> Json<VideoStats> {
+    Json(*state.video.borrow())
+}
+
+/// Every frame as it is decoded, as MJPEG
+///
+/// Frames decoded while the last one is still being sent are skipped.
+async fn camera_stream(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut frames = state.camera.clone();
+    frames.mark_changed();
+    let parts = futures::stream::unfold(frames, |mut frames| async move {
+        frames.changed().await.ok()?;
+        let frame = frames.borrow_and_update().clone();
+        let part = tokio::task::spawn_blocking(move || {
+            let jpeg = jpeg(&frame.image)?;
+            let mut part = part_header(jpeg.len(), &frame).into_bytes();
+            part.extend(jpeg);
+            part.extend(b"\r\n");
+            Ok::<_, image::ImageError>(part)
+        })
+        .await
+        .ok()?;
+        Some((part, frames))
+    });
+    (
+        [(
+            header::CONTENT_TYPE,
+            format!("multipart/x-mixed-replace; boundary={STREAM_BOUNDARY}"),
+        )],
+        Body::from_stream(parts),
+    )
+}
+
+fn jpeg(image: &DynamicImage) -> Result<Vec<u8>, image::ImageError> {
     let mut bytes = Cursor::new(Vec::new());
-    dyn_image
-        .write_to(&mut bytes, image::ImageFormat::Jpeg)
-        .wrap_err("write image")?;
+    image.write_to(&mut bytes, image::ImageFormat::Jpeg)?;
+    Ok(bytes.into_inner())
+}
+
+/// The latest telemetry of the drone as JSON
+async fn state(State(state): State<Arc<AppState>>) -> Result<Json<Telemetry>, Oof> {
+    let telemetry = state.telemetry.borrow().clone();
+    telemetry.map(Json).ok_or_else(|| {
+        Oof(
+            StatusCode::SERVICE_UNAVAILABLE,
+            String::from("no telemetry from the drone yet"),
+        )
+    })
+}
+
+/// The telemetry of the drone as server-sent events, one JSON object per update
+async fn state_stream(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let mut updates = state.telemetry.clone();
+    // start with the latest telemetry
+    updates.mark_changed();
+    let stream = futures::stream::unfold(updates, |mut updates| async move {
+        loop {
+            updates.changed().await.ok()?;
+            let telemetry = updates.borrow_and_update().clone();
+            if let Some(telemetry) = telemetry {
+                return Some((Event::default().json_data(telemetry), updates));
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// How close the drone is to having to land, as JSON
+async fn failsafe(State(state): State<Arc<AppState>>) -> Json<FailsafeState> {
+    Json(*state.failsafe.borrow())
+}
+
+/// Every change of the failsafe state as server-sent events, starting with the current one
+async fn failsafe_stream(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let mut updates = state.failsafe.clone();
+    updates.mark_changed();
+    let stream = futures::stream::unfold(updates, |mut updates| async move {
+        updates.changed().await.ok()?;
+        let failsafe = *updates.borrow_and_update();
+        Some((Event::default().json_data(failsafe), updates))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// How far auto-framing got, as JSON
+async fn framing(State(state): State<Arc<AppState>>) -> Json<FramingStatus> {
+    Json(state.framing.borrow().clone())
+}
 
-    Ok(([(header::CONTENT_TYPE, "image/jpeg")], bytes.into_inner()))
+/// Start moving the drone until the arena is in frame, see `GET /frame` for how it goes
+async fn start_framing(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, Oof> {
+    state.drone()?;
+    let started = state.framing.send_if_modified(|status| {
+        if matches!(status, FramingStatus::Framing { .. }) {
+            return false;
+        }
+        *status = FramingStatus::Framing { steps: 0 };
+        true
+    });
+    if !started {
+        return Err(Oof(
+            StatusCode::CONFLICT,
+            String::from("the drone is already being framed"),
+        ));
+    }
+    tokio::spawn(
+        async move {
+            let Ok(drone) = state.drone() else {
+                return;
+            };
+            framing::frame(
+                state.framing_config,
+                drone,
+                state.camera.clone(),
+                &state.led_config,
+                &state.framing,
+            )
+            .await
+        }
+        .instrument(tracing::info_span!("framing")),
+    );
+    Ok(StatusCode::ACCEPTED)
 }
 
 async fn nudge(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<Direction>,
 ) -> Result<impl IntoResponse, Oof> {
-    loop {
-        let mut current = state.drone.lock().await;
-        let invoke = match payload {
-            Direction::Left => {
-                if current.moved_x < -2 {
-                    return Ok(());
-                }
-                current.moved_x -= 1;
-                raw::control::Command::TrimLeft
-            }
-            Direction::Right => {
-                if current.moved_x > 2 {
-                    return Ok(());
-                }
-                current.moved_x += 1;
-                raw::control::Command::TrimRight
-            }
-            Direction::Backward => {
-                if current.moved_y < -2 {
-                    return Ok(());
+    let invoke = match payload {
+        Direction::Left => Command::Left { cm: 20 },
+        Direction::Right => Command::Right { cm: 20 },
+        Direction::Backward => Command::Back { cm: 20 },
+        Direction::Forward => Command::Forward { cm: 20 },
+        Direction::Up => Command::Up { cm: 20 },
+        Direction::Down => Command::Down { cm: 20 },
+        Direction::Clockwise => Command::Clockwise { degrees: 10 },
+        Direction::CounterClockwise => Command::CounterClockwise { degrees: 10 },
+        Direction::Takeoff => Command::Takeoff,
+        Direction::Land => Command::Land,
+    };
+
+    let response = match state.drone()?.send(invoke).await {
+        Ok(response) => response,
+        Err(DroneError::Unsafe(e)) => return Err(Oof(StatusCode::CONFLICT, e.to_string())),
+        Err(e) => return Err(eyre::Report::new(e).wrap_err("send cmd").into()),
+    };
+    match response {
+        Response::Ok | Response::Value(_) => Ok(()),
+        Response::Error(reason) => Err(Oof(StatusCode::BAD_GATEWAY, reason)),
+        Response::Timeout => Err(Oof(
+            StatusCode::GATEWAY_TIMEOUT,
+            String::from("the drone did not answer"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use hs_hackathon_drone::emulator::Emulator;
+    use hs_hackathon_drone::Camera;
+
+    /// An emulated drone, and where an aviator flying it listens
+    async fn emulated() -> (Emulator, Sockets) {
+        let (emulator, local) = Emulator::start_local().await.unwrap();
+        let sockets = Sockets {
+            listen: TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            drone: Some(DroneSockets {
+                command: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                state: local.state,
+                video: local.video,
+            }),
+        };
+        (emulator, sockets)
+    }
+
+    #[tokio::test]
+    async fn test_emulated_drone() {
+        let (emulator, sockets) = emulated().await;
+        let listen = sockets.listen.local_addr().unwrap();
+        let drone = emulator.command_addr().to_string();
+        let args = Args::parse_from(["aviator", "--drone", &drone]);
+        tokio::spawn(serve(args, sockets));
+
+        let url = format!("http://{listen}");
+        let client = reqwest::Client::new();
+        let telemetry = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let Ok(response) = client.get(format!("{url}/state")).send().await else {
+                    continue;
+                };
+                if response.status().is_success() {
+                    let body = response.bytes().await.unwrap();
+                    break serde_json::from_slice::<serde_json::Value>(&body).unwrap();
                 }
-                current.moved_y -= 1;
-                raw::control::Command::TrimBwd
             }
-            Direction::Forward => {
-                if current.moved_y > 2 {
-                    return Ok(());
+        })
+        .await
+        .expect("no telemetry in time");
+        assert_eq!(100, telemetry["bat"]);
+        let telemetry_seen = std::time::SystemTime::now();
+
+        let response = client
+            .post(format!("{url}/nudge"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&Direction::Takeoff).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert!(emulator.is_flying());
+
+        let failsafe = client
+            .get(format!("{url}/failsafe"))
+            .send()
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        let failsafe: serde_json::Value = serde_json::from_slice(&failsafe).unwrap();
+        assert_eq!("nominal", failsafe["state"]);
+
+        let camera = Camera::connect_to(&url).await.unwrap();
+        let frame = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let frame = camera.snapshot().await.unwrap();
+                // frames that arrived before the first telemetry are not stamped with it
+                if frame.image.width() == 320 && frame.captured >= telemetry_seen {
+                    break frame;
                 }
-                current.moved_y += 1;
-                raw::control::Command::TrimFwd
+                tokio::time::sleep(Duration::from_millis(100)).await;
             }
-            Direction::Up => {
-                if current.altitude > 160 {
-                    return Ok(());
-                }
-                raw::control::Command::GoHigher
+        })
+        .await
+        .expect("no frame in time");
+        assert_eq!(240, frame.image.height());
+        assert!(frame.sequence > 0);
+        assert_eq!((320, 240), frame.resolution);
+        assert_eq!(Some(100), frame.state.map(|state| state.bat));
+
+        let mut stream = Box::pin(camera.stream().await.unwrap());
+        let first = stream.next().await.unwrap().unwrap();
+        let second = stream.next().await.unwrap().unwrap();
+        assert_eq!((320, 240), (second.image.width(), second.image.height()));
+        assert!(second.sequence > first.sequence);
+        assert!(second.captured >= first.captured);
+        assert_eq!(
+            vec!["command", "streamon", "takeoff"],
+            emulator.received()[..3]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let (emulator, sockets) = emulated().await;
+        let listen = sockets.listen.local_addr().unwrap();
+        // nobody else uses the port while it is bound
+        let session = format!("aviator-{}.session", listen.port());
+        let session = std::env::temp_dir().join(session);
+        let (drone, session_arg) = (emulator.command_addr().to_string(), session.display());
+        let args = Args::parse_from([
+            "aviator",
+            "--drone",
+            &drone,
+            "--record",
+            &session_arg.to_string(),
+        ]);
+        tokio::spawn(serve(args, sockets));
+        let camera = Camera::connect_to(&format!("http://{listen}"))
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while camera.snapshot().await.map_or(0, |frame| frame.sequence) < 10 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
             }
-            Direction::Clockwise => raw::control::Command::RotateCw,
-            Direction::CounterClockwise => raw::control::Command::RotateCcw,
-            Direction::Down => raw::control::Command::GoLower,
-            Direction::Takeoff => raw::control::Command::Takeoff,
-            Direction::Land => raw::control::Command::Land,
-        };
+        })
+        .await
+        .expect("no frames recorded in time");
 
-        let (syn, ack) = tokio::sync::oneshot::channel();
-        if let Err(e) = current.task.try_send((invoke.clone(), syn)) {
-            match e {
-                mpsc::error::TrySendError::Full(_) => {
-                    // try again -- concurrent command
-                    warn!("dropping concurrent command {invoke}");
-                    drop(current);
-                    continue;
-                }
-                mpsc::error::TrySendError::Closed(_) => {
-                    return Err(eyre::eyre!("can't send more commands -- loop terminated").into());
+        let sockets = Sockets {
+            listen: TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            drone: None,
+        };
+        let replay = sockets.listen.local_addr().unwrap();
+        let args = Args::parse_from([
+            "aviator",
+            "--replay",
+            &session_arg.to_string(),
+            "--replay-speed",
+            "4",
+        ]);
+        tokio::spawn(serve(args, sockets));
+        let url = format!("http://{replay}");
+        let camera = Camera::connect_to(&url).await.unwrap();
+        let frame = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                match camera.snapshot().await {
+                    Ok(frame) if frame.sequence > 0 => break frame,
+                    _ => continue,
                 }
             }
-        }
-        ack.await.wrap_err("ack cmd")?;
-        break Ok(());
+        })
+        .await
+        .expect("no frame replayed in time");
+        assert_eq!((320, 240), frame.resolution);
+
+        let client = reqwest::Client::new();
+        let telemetry = client.get(format!("{url}/state")).send().await.unwrap();
+        assert!(telemetry.status().is_success());
+        let response = client
+            .post(format!("{url}/nudge"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&Direction::Takeoff).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CONFLICT, response.status());
+        std::fs::remove_file(session).unwrap();
     }
 }
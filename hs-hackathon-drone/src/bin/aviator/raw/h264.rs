@@ -1,115 +1,460 @@
+use crate::session::Recorder;
 use eyre::Result;
-use image::RgbImage;
+use hs_hackathon_drone::{h264::NalSplitter, Frame, Telemetry};
+use image::{DynamicImage, RgbImage};
 use openh264::{decoder::Decoder, formats::YUVSource};
-use std::collections::VecDeque;
+use serde::Serialize;
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::{net::UdpSocket, sync::watch};
 
 #[allow(unused_imports)]
 use tracing::{debug, info, trace, warn};
 
-const NAL_MIN_0_COUNT: usize = 2;
+/// A black frame to show until the first one is decoded
+pub fn blank_frame(width: u32, height: u32) -> Frame {
+    Frame {
+        image: RgbImage::new(width, height).into(),
+        sequence: 0,
+        captured: SystemTime::now(),
+        decoded: SystemTime::now(),
+        resolution: (width, height),
+        state: None,
+    }
+}
+
+/// How many NAL units may wait for the decoder before it counts as falling behind.
+const DECODE_QUEUE: usize = 8;
+
+/// How much the average decode latency moves towards the latest one with each frame.
+const LATENCY_SMOOTHING: f64 = 0.1;
+
+/// NAL unit types, see table 7-1 of the H.264 spec.
+const NAL_SLICE: u8 = 1;
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+
+/// How well decoding keeps up with the drone
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct VideoStats {
+    /// Frames decoded so far
+    pub decoded: u64,
+    /// Frames dropped so far because the decoder fell behind, counted by their first slice
+    pub dropped: u64,
+    /// Milliseconds from when the last frame arrived until it was decoded
+    pub latency_ms: f64,
+    /// Moving average of `latency_ms`
+    pub average_latency_ms: f64,
+}
+
+/// The `nal_ref_idc` and `nal_unit_type` of a NAL unit, which starts with its start code.
+fn nal_header(nal: &[u8]) -> Option<(u8, u8)> {
+    let start = nal.iter().position(|byte| *byte != 0)?;
+    // skip the 1 ending the start code
+    let header = *nal.get(start + 1)?;
+    Some(((header >> 5) & 0b11, header & 0b1_1111))
+}
 
-/// Extracts the starting position of the n-th NAL unit from the given byte array.
+/// Whether a slice starts a new frame, i.e. its `first_mb_in_slice` is 0
 ///
-/// Copied and adapted from openh264 library.
-#[inline]
-fn nth_nal_index(buffer: &VecDeque<u8>, nth: usize) -> Option<usize> {
-    let mut count_0 = 0;
-    let mut n = 0;
-
-    for (i, byte) in buffer.iter().enumerate() {
-        match byte {
-            0 => count_0 += 1,
-            1 if count_0 >= NAL_MIN_0_COUNT => {
-                if n == nth {
-                    return Some(i - NAL_MIN_0_COUNT);
-                } else {
-                    count_0 = 0;
-                    n += 1;
+/// That is the first field after the header, coded as Exp-Golomb, which codes 0 as a single 1
+/// bit.
+fn starts_frame(nal: &[u8]) -> bool {
+    let Some(start) = nal.iter().position(|byte| *byte != 0) else {
+        return false;
+    };
+    // skip the 1 ending the start code and the header
+    nal.get(start + 2).is_some_and(|byte| byte & 0x80 != 0)
+}
+
+/// Decodes a "raw" annex-b h264 byte stream into frames on a thread of its own, and writes
+/// them into its sink.
+///
+/// Each frame is stamped with the latest of the telemetry it watches. When the decoder falls
+/// behind, frames no other frame refers to are dropped first. Should that not be enough, all
+/// frames up to the next IDR frame are dropped, which refers to no earlier frame. Parameter
+/// sets are never dropped, as no frame can be decoded without them.
+pub struct FrameDecoder {
+    h264: NalSplitter,
+    queue: DecodeQueue,
+}
+
+/// The NAL units on their way to the decoder thread
+struct DecodeQueue {
+    nals: SyncSender<(Vec<u8>, SystemTime)>,
+    /// Buffers of NAL units that are done with, to copy the next ones into
+    spare: Receiver<Vec<u8>>,
+    recycle: Sender<Vec<u8>>,
+    /// Set while waiting for the next IDR frame
+    skipping: bool,
+    stats: Arc<watch::Sender<VideoStats>>,
+}
+
+impl FrameDecoder {
+    pub fn new(
+        sink: watch::Sender<Frame>,
+        telemetry: watch::Receiver<Option<Telemetry>>,
+        stats: Arc<watch::Sender<VideoStats>>,
+    ) -> Result<Self> {
+        let (nals, pending) = std::sync::mpsc::sync_channel(DECODE_QUEUE);
+        let decoder = Decoder::new()?;
+        let decoded = Arc::clone(&stats);
+        let frames = Self::with_queue(nals, stats);
+        let recycle = frames.queue.recycle.clone();
+        let span = tracing::info_span!("decoder");
+        std::thread::Builder::new()
+            .name(String::from("h264-decoder"))
+            .spawn(move || {
+                let _span = span.enter();
+                decode(decoder, pending, &recycle, sink, telemetry, &decoded);
+            })?;
+        Ok(frames)
+    }
+
+    fn with_queue(
+        nals: SyncSender<(Vec<u8>, SystemTime)>,
+        stats: Arc<watch::Sender<VideoStats>>,
+    ) -> Self {
+        let (recycle, spare) = std::sync::mpsc::channel();
+        Self {
+            h264: NalSplitter::new(),
+            queue: DecodeQueue {
+                nals,
+                spare,
+                recycle,
+                skipping: false,
+                stats,
+            },
+        }
+    }
+
+    /// Hands the NAL units completed by the next piece of the byte stream, received at
+    /// `received`, to the decoder.
+    ///
+    /// Returns `false` once the sink is closed.
+    pub fn push(&mut self, bytes: &[u8], received: SystemTime) -> bool {
+        self.h264.push(bytes);
+        while let Some(nal) = self.h264.next_nal() {
+            if !self.queue.hand_off(nal, received) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Starts over after a gap in the byte stream, dropping all frames up to the next IDR
+    /// frame
+    pub fn resync(&mut self) {
+        self.h264 = NalSplitter::new();
+        self.queue.skipping = true;
+    }
+}
+
+impl DecodeQueue {
+    /// Copies `nal` into the queue, unless it is to be dropped
+    fn hand_off(&mut self, nal: &[u8], received: SystemTime) -> bool {
+        let Some((reference, kind)) = nal_header(nal) else {
+            return true;
+        };
+        if self.skipping && kind == NAL_SLICE {
+            self.count_dropped(nal);
+            return true;
+        }
+        let mut buffer = self.spare.try_recv().unwrap_or_default();
+        buffer.clear();
+        buffer.extend_from_slice(nal);
+        if matches!(kind, NAL_SPS | NAL_PPS) {
+            // they are tiny and rare, so waiting for the decoder to take them is brief
+            return self.nals.send((buffer, received)).is_ok();
+        }
+        match self.nals.try_send((buffer, received)) {
+            Ok(()) => {
+                if kind == NAL_IDR && self.skipping {
+                    debug!("caught up");
+                    self.skipping = false;
                 }
+                true
             }
-            _ => count_0 = 0,
+            Err(TrySendError::Full((buffer, _))) => {
+                let _ = self.recycle.send(buffer);
+                if matches!(kind, NAL_SLICE | NAL_IDR) {
+                    self.count_dropped(nal);
+                }
+                // the frames after this one might refer to it
+                if reference != 0 && !self.skipping {
+                    debug!("decoder fell behind, skipping to the next IDR frame");
+                    self.skipping = true;
+                }
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
         }
     }
 
-    None
+    /// Counts a dropped slice, once per frame
+    fn count_dropped(&self, slice: &[u8]) {
+        if starts_frame(slice) {
+            self.stats.send_modify(|stats| stats.dropped += 1);
+        }
+    }
 }
 
-/// Produces all complete h264 NAL units from the given buffer, leaving
-/// any remaining data in the buffer.
+/// Decodes the NAL units from [pending] into [sink] until either is closed, handing their
+/// buffers back to [recycle].
 ///
-/// Copied from openh264 library.
-fn nal_units(buffer: &mut VecDeque<u8>) -> impl Iterator<Item = Vec<u8>> + '_ {
-    std::iter::from_fn(move || {
-        let first = nth_nal_index(buffer, 0);
-        let next = nth_nal_index(buffer, 1);
-        match (first, next) {
-            (Some(_), Some(n)) => {
-                // Note that we're taking a copy of the bytes here, likely in a somewhat inefficient
-                // manner. The alternative is to compact the VecDeque and return a &[u8].
-                // Would need to micro-benchmark in order to figure out which one is more efficient.
-                let nal: Vec<_> = buffer.drain(0..n).collect();
-                Some(nal)
+/// The frame in [sink] is overwritten in place if it has the same size as the next one.
+fn decode(
+    mut decoder: Decoder,
+    pending: Receiver<(Vec<u8>, SystemTime)>,
+    recycle: &Sender<Vec<u8>>,
+    sink: watch::Sender<Frame>,
+    telemetry: watch::Receiver<Option<Telemetry>>,
+    stats: &watch::Sender<VideoStats>,
+) {
+    debug!("started");
+    while let Ok((nal, received)) = pending.recv() {
+        trace!("walking nal unit");
+        let decoded = decoder.decode(nal.as_slice());
+        let _ = recycle.send(nal);
+        let frame = match decoded {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                trace!("skipping empty NAL unit");
+                continue;
+            }
+            Err(e) => {
+                trace!(%e, "skipping packet h264 decoder is unhappy with");
+                continue;
             }
-            _ => None,
+        };
+        trace!("got frame");
+
+        let (width, height) = (frame.width() as u32, frame.height() as u32);
+        let state = telemetry.borrow().as_ref().map(|t| t.state.clone());
+        sink.send_modify(|latest| {
+            match &mut latest.image {
+                DynamicImage::ImageRgb8(image) if image.dimensions() == (width, height) => {
+                    frame.write_rgb8(image);
+                }
+                image => {
+                    let mut buffer = vec![0u8; (width * height * 3) as usize];
+                    frame.write_rgb8(&mut buffer);
+                    let rgb = RgbImage::from_vec(width, height, buffer)
+                        .expect("Size mismatch; this is a bug");
+                    *image = rgb.into();
+                }
+            }
+            latest.sequence += 1;
+            latest.resolution = (width, height);
+            latest.captured = received;
+            latest.decoded = SystemTime::now();
+            latest.state = state;
+        });
+        trace!("updated frame");
+
+        let latency = SystemTime::now()
+            .duration_since(received)
+            .unwrap_or_default()
+            .as_secs_f64()
+            * 1000.0;
+        stats.send_modify(|stats| {
+            stats.average_latency_ms = match stats.decoded {
+                0 => latency,
+                _ => {
+                    stats.average_latency_ms
+                        + LATENCY_SMOOTHING * (latency - stats.average_latency_ms)
+                }
+            };
+            stats.decoded += 1;
+            stats.latency_ms = latency;
+        });
+        if sink.is_closed() {
+            warn!("exiting as there are no receivers");
+            return;
         }
-    })
+    }
+    debug!("exiting as the byte stream ended");
 }
 
-/// Receives video frames from the drone's UDP connection, and writes them into [sink].
+/// Receives video frames from the drone's UDP connection, and decodes them with [frames].
 ///
-/// Blocks until [sink] is closed. Assumes a "raw" annex-b h264 byte stream sent over UDP. Yep,
-/// this is in the official Tello format.
-pub async fn watch_latest_frame(sink: watch::Sender<RgbImage>, socket: UdpSocket) -> Result<()> {
-    let mut decoder = Decoder::new()?;
-
-    let mut rgb_buffer = vec![0u8; 2000 * 2000 * 3]; // upper bound for image size
+/// Blocks until the sink of [frames] is closed. Assumes a "raw" annex-b h264 byte stream sent
+/// over UDP. Yep, this is in the official Tello format. Each packet is recorded with
+/// [recorder].
+pub async fn watch_latest_frame(
+    mut frames: FrameDecoder,
+    socket: UdpSocket,
+    recorder: Option<Recorder>,
+) -> Result<()> {
     let mut packet_buffer = vec![0u8; 2000]; // holds on UDP packet at a time
-    let mut h264_buffer = VecDeque::new(); // ringbuffer holding the h264 byte stream
 
     loop {
         // Write next packet into h264 byte stream buffer
         trace!("await h264 packet");
         let size = socket.recv(packet_buffer.as_mut()).await?;
+        let received = SystemTime::now();
         trace!("got h264 packet");
-        h264_buffer.extend(&packet_buffer[0..size]);
-
-        // Extract NAL units and decode them one by one into RGB frames.
-        for packet in nal_units(&mut h264_buffer) {
-            trace!("walking nal unit");
-            match decoder.decode(packet.as_slice()) {
-                Ok(Some(frame)) => {
-                    trace!("got frame");
-
-                    let num_bytes = (frame.width() * frame.height() * 3) as usize;
-                    if num_bytes > rgb_buffer.len() {
-                        warn!(
-                            "Frame size exceeded buffer size ({} bytes)",
-                            rgb_buffer.len()
-                        );
-                    } else {
-                        let sized_rgb_buffer = rgb_buffer[0..num_bytes].as_mut();
-                        frame.write_rgb8(sized_rgb_buffer);
-                        let image = RgbImage::from_vec(
-                            frame.width() as u32,
-                            frame.height() as u32,
-                            sized_rgb_buffer.to_vec(),
-                        )
-                        .expect("Size mismatch; this is a bug");
+        let packet = &packet_buffer[0..size];
+        if let Some(recorder) = &recorder {
+            recorder.record_video(packet);
+        }
+        if !frames.push(packet, received) {
+            warn!("exiting as there are no receivers");
+            return Ok(());
+        }
+    }
+}
 
-                        trace!("updated frame");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hs_hackathon_drone::emulator::Emulator;
+    use std::time::Duration;
 
-                        if sink.send(image).is_err() {
-                            warn!("exiting as there are no receivers");
-                            return Ok(());
-                        }
-                    }
-                }
-                Ok(None) => trace!("skipping empty NAL unit"),
-                Err(e) => {
-                    trace!(%e, "skipping packet h264 decoder is unhappy with")
-                }
-            }
+    #[tokio::test]
+    async fn test_emulated_stream() {
+        let (emulator, sockets) = Emulator::start_local().await.unwrap();
+
+        let control = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut answer = [0u8; 16];
+        for command in ["command", "streamon"] {
+            control
+                .send_to(command.as_bytes(), emulator.command_addr())
+                .await
+                .unwrap();
+            let size = control.recv(&mut answer).await.unwrap();
+            assert_eq!(b"ok", &answer[..size]);
         }
+
+        let (frame_tx, mut frame_rx) = watch::channel(blank_frame(1, 1));
+        let (_telemetry_tx, telemetry_rx) = watch::channel(None);
+        let stats = Arc::new(watch::channel(VideoStats::default()).0);
+        let frames = FrameDecoder::new(frame_tx, telemetry_rx, Arc::clone(&stats)).unwrap();
+        tokio::spawn(watch_latest_frame(frames, sockets.video, None));
+        tokio::time::timeout(Duration::from_secs(10), frame_rx.changed())
+            .await
+            .expect("no frame in time")
+            .unwrap();
+        let frame = frame_rx.borrow().clone();
+        assert_eq!((320, 240), frame.resolution);
+        assert!(frame.sequence >= 1);
+        assert!(frame.decoded >= frame.captured);
+        let mut stats = stats.subscribe();
+        let counted = tokio::time::timeout(
+            Duration::from_secs(1),
+            stats.wait_for(|stats| stats.decoded > 0),
+        )
+        .await
+        .map(|counted| counted.is_ok());
+        assert_eq!(Ok(true), counted);
+    }
+
+    #[test]
+    fn test_skip_to_idr() {
+        let nal = |header: u8| vec![0, 0, 0, 1, header, 0xff];
+        let (idr, reference, disposable) = (nal(0x65), nal(0x41), nal(0x01));
+        assert_eq!(Some((3, NAL_IDR)), nal_header(&idr));
+        assert_eq!(Some((0, NAL_SLICE)), nal_header(&disposable));
+
+        let (nals, pending) = std::sync::mpsc::sync_channel(1);
+        let stats = Arc::new(watch::channel(VideoStats::default()).0);
+        let mut frames = FrameDecoder::with_queue(nals, Arc::clone(&stats));
+        let now = SystemTime::now();
+        assert!(frames.queue.hand_off(&idr, now));
+        // the decoder is busy, so these are dropped
+        assert!(frames.queue.hand_off(&disposable, now));
+        assert!(!frames.queue.skipping);
+        assert!(frames.queue.hand_off(&reference, now));
+        assert!(frames.queue.skipping);
+        assert_eq!(2, stats.borrow().dropped);
+
+        // even once the decoder caught up, frames referring to dropped ones are dropped
+        assert_eq!(idr, pending.recv().unwrap().0);
+        assert!(frames.queue.hand_off(&reference, now));
+        assert_eq!(3, stats.borrow().dropped);
+        assert!(frames.queue.hand_off(&idr, now));
+        assert!(!frames.queue.skipping);
+        assert_eq!(idr, pending.recv().unwrap().0);
+        assert!(frames.queue.hand_off(&reference, now));
+        assert_eq!(reference, pending.recv().unwrap().0);
+
+        drop(pending);
+        assert!(!frames.queue.hand_off(&reference, now));
+    }
+
+    #[test]
+    fn test_count_dropped_frames() {
+        let slice = |header: u8, first: u8| vec![0, 0, 0, 1, header, first, 0xff];
+        // the second slices of frames have a first_mb_in_slice other than 0
+        let (idr, second_idr) = (slice(0x65, 0x88), slice(0x65, 0x40));
+        let (reference, second_reference) = (slice(0x41, 0x9a), slice(0x41, 0x20));
+        assert!(starts_frame(&idr) && starts_frame(&reference));
+        assert!(!starts_frame(&second_idr) && !starts_frame(&second_reference));
+
+        let (nals, pending) = std::sync::mpsc::sync_channel(1);
+        let stats = Arc::new(watch::channel(VideoStats::default()).0);
+        let mut frames = FrameDecoder::with_queue(nals, Arc::clone(&stats));
+        let now = SystemTime::now();
+        for nal in [&idr, &second_idr, &reference, &second_reference, &reference] {
+            assert!(frames.queue.hand_off(nal, now));
+        }
+        assert!(frames.queue.skipping);
+        assert_eq!(2, stats.borrow().dropped);
+        assert_eq!(idr, pending.recv().unwrap().0);
+    }
+
+    #[test]
+    fn test_recycle_buffers() {
+        let nal = |header: u8| vec![0, 0, 0, 1, header, 0xff];
+        let (idr, reference) = (nal(0x65), nal(0x41));
+
+        let (nals, pending) = std::sync::mpsc::sync_channel(1);
+        let stats = Arc::new(watch::channel(VideoStats::default()).0);
+        let mut frames = FrameDecoder::with_queue(nals, Arc::clone(&stats));
+        let now = SystemTime::now();
+        assert!(frames.queue.hand_off(&idr, now));
+        let (decoded, _) = pending.recv().unwrap();
+        let buffer = decoded.as_ptr();
+        // the decoder hands the buffer back once it is done with it
+        frames.queue.recycle.send(decoded).unwrap();
+        assert!(frames.queue.hand_off(&reference, now));
+        let (next, _) = pending.recv().unwrap();
+        assert_eq!(reference, next);
+        assert_eq!(buffer, next.as_ptr());
+
+        // so are the buffers of dropped NAL units
+        assert!(frames.queue.hand_off(&reference, now));
+        assert!(frames.queue.hand_off(&reference, now));
+        assert_eq!(1, stats.borrow().dropped);
+        assert!(frames.queue.spare.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_keep_parameter_sets() {
+        let nal = |header: u8| vec![0, 0, 0, 1, header, 0xff];
+        let (idr, reference, sps, pps) = (nal(0x65), nal(0x41), nal(0x67), nal(0x68));
+        assert_eq!(Some((3, NAL_SPS)), nal_header(&sps));
+
+        let (nals, pending) = std::sync::mpsc::sync_channel(1);
+        let stats = Arc::new(watch::channel(VideoStats::default()).0);
+        let mut frames = FrameDecoder::with_queue(nals, Arc::clone(&stats));
+        let now = SystemTime::now();
+        assert!(frames.queue.hand_off(&idr, now));
+        assert!(frames.queue.hand_off(&reference, now));
+        assert!(frames.queue.skipping);
+
+        // the parameter sets of the next IDR frame wait for the busy decoder
+        let decoder = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            (0..3)
+                .map(|_| pending.recv().unwrap().0)
+                .collect::<Vec<_>>()
+        });
+        assert!(frames.queue.hand_off(&sps, now));
+        assert!(frames.queue.hand_off(&pps, now));
+        assert_eq!(vec![idr, sps, pps], decoder.join().unwrap());
+        assert_eq!(1, stats.borrow().dropped);
     }
 }
@@ -0,0 +1,355 @@
+//! Recordings of flights, to replay them later.
+//!
+//! A session file starts with [`MAGIC`] and when the recording started, in microseconds since
+//! the UNIX epoch. Then come the records, each a tag, when it was recorded in microseconds
+//! since the start, the length of its payload and the payload, all numbers little-endian.
+
+use crate::raw::h264::FrameDecoder;
+use eyre::{bail, Context, Result};
+use hs_hackathon_drone::{Command, State, Telemetry};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, error::TryRecvError, error::TrySendError};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+#[allow(unused_imports)]
+use tracing::{debug, info, trace, warn};
+
+/// What every session file starts with.
+const MAGIC: &[u8] = b"HSSESSION\x01";
+
+/// How many records may wait to be written before new ones are dropped.
+const BACKLOG: usize = 4096;
+
+/// How many written video packets are kept around to receive the next ones.
+const SPARE_BUFFERS: usize = 64;
+
+const VIDEO: u8 = 0;
+const TELEMETRY: u8 = 1;
+const COMMAND: u8 = 2;
+const GAP: u8 = 3;
+
+/// Something that happened during a flight
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    /// A packet of the drone's raw H.264 stream
+    Video(Vec<u8>),
+    /// The telemetry of the drone
+    Telemetry(State),
+    /// A command sent to the drone, along with its answer
+    Command { command: Command, response: String },
+    /// Records that were dropped here, as the session file could not keep up
+    Gap { dropped: u64 },
+}
+
+/// Records into a session file, until the last clone is dropped
+#[derive(Clone)]
+pub struct Recorder {
+    records: mpsc::Sender<(SystemTime, Record)>,
+    shared: Arc<Shared>,
+}
+
+/// What a [`Recorder`] shares with the task writing its records
+#[derive(Default)]
+struct Shared {
+    /// Buffers of video packets that were written, to hold the next packets
+    spare: Mutex<Vec<Vec<u8>>>,
+    /// How many records were dropped since the last [`Record::Gap`] was written
+    dropped: AtomicU64,
+}
+
+impl Shared {
+    fn recycle(&self, record: Record) {
+        if let Record::Video(mut packet) = record {
+            let mut spare = self.spare.lock().unwrap_or_else(|p| p.into_inner());
+            if spare.len() < SPARE_BUFFERS {
+                packet.clear();
+                spare.push(packet);
+            }
+        }
+    }
+}
+
+impl Recorder {
+    /// Starts recording into a new session file at `path`
+    ///
+    /// The returned task ends once the recording is complete.
+    pub fn create(path: &Path) -> Result<(Self, JoinHandle<Result<()>>)> {
+        let file = File::create(path).wrap_err_with(|| format!("create {}", path.display()))?;
+        let (records, pending) = mpsc::channel(BACKLOG);
+        let shared = Arc::new(Shared::default());
+        let writing = Arc::clone(&shared);
+        let writer = tokio::task::spawn_blocking(move || {
+            write_session(BufWriter::new(file), pending, &writing)
+        });
+        Ok((Self { records, shared }, writer))
+    }
+
+    /// Records `record` as happening just now
+    pub fn record(&self, record: Record) {
+        match self.records.try_send((SystemTime::now(), record)) {
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full((_, record))) => {
+                if self.shared.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    warn!("dropping records, the session file cannot keep up");
+                }
+                self.shared.recycle(record);
+            }
+        }
+    }
+
+    /// Records a packet of the drone's video stream as received just now
+    ///
+    /// Copies the packet into a buffer that was written before, if there is one.
+    pub fn record_video(&self, packet: &[u8]) {
+        let spare = self
+            .shared
+            .spare
+            .lock()
+            .unwrap_or_else(|p| p.into_inner())
+            .pop();
+        let mut buffer = spare.unwrap_or_default();
+        buffer.extend_from_slice(packet);
+        self.record(Record::Video(buffer));
+    }
+}
+
+/// Writes the records from `pending` into `out` until all [`Recorder`]s are dropped
+///
+/// Records that were dropped on the way are written as a [`Record::Gap`].
+fn write_session(
+    mut out: impl Write,
+    mut pending: mpsc::Receiver<(SystemTime, Record)>,
+    shared: &Shared,
+) -> Result<()> {
+    let start = SystemTime::now();
+    out.write_all(MAGIC)?;
+    out.write_all(&micros(start.duration_since(UNIX_EPOCH)?).to_le_bytes())?;
+    let write_gap = |out: &mut dyn Write| -> io::Result<()> {
+        let dropped = shared.dropped.swap(0, Ordering::Relaxed);
+        if dropped == 0 {
+            return Ok(());
+        }
+        warn!("{dropped} records are missing from the session file");
+        let at = start.elapsed().unwrap_or_default();
+        write_record(out, at, &Record::Gap { dropped })
+    };
+    loop {
+        let (at, record) = match pending.try_recv() {
+            Ok(next) => next,
+            Err(TryRecvError::Empty) => {
+                // nothing to do, so make sure what we have is on disk
+                out.flush()?;
+                match pending.blocking_recv() {
+                    Some(next) => next,
+                    None => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        let at = at.duration_since(start).unwrap_or_default();
+        write_record(&mut out, at, &record)?;
+        shared.recycle(record);
+        write_gap(&mut out)?;
+    }
+    write_gap(&mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Plays the session at `path` back into `frames` and `telemetry`, `speed` times as fast as
+/// it was recorded. Blocks until the end of the session.
+///
+/// The frames and telemetry are stamped as received when they are played back.
+pub async fn replay(
+    path: PathBuf,
+    speed: f64,
+    mut frames: FrameDecoder,
+    telemetry: watch::Sender<Option<Telemetry>>,
+) -> Result<()> {
+    let file = File::open(&path).wrap_err_with(|| format!("open {}", path.display()))?;
+    let mut input = BufReader::new(file);
+    let mut magic = [0u8; MAGIC.len()];
+    input.read_exact(&mut magic).wrap_err("read header")?;
+    if magic != MAGIC {
+        bail!("{} is not a session file", path.display());
+    }
+    let mut recorded = [0u8; 8];
+    input.read_exact(&mut recorded).wrap_err("read header")?;
+    let recorded = UNIX_EPOCH + Duration::from_micros(u64::from_le_bytes(recorded));
+    info!("replaying session recorded {recorded:?} at {speed}x");
+
+    // read ahead without blocking the playback
+    let (records, mut next) = mpsc::channel(BACKLOG);
+    let reader = tokio::task::spawn_blocking(move || loop {
+        match read_record(&mut input) {
+            Ok(Some(record)) => {
+                if records.blocking_send(record).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(e) => {
+                warn!("session ends early: {e}");
+                return;
+            }
+        }
+    });
+
+    let start = Instant::now();
+    while let Some((at, record)) = next.recv().await {
+        tokio::time::sleep_until(start + at.div_f64(speed)).await;
+        match record {
+            Record::Video(packet) => {
+                if !frames.push(&packet, SystemTime::now()) {
+                    debug!("exiting as there are no receivers");
+                    break;
+                }
+            }
+            Record::Telemetry(state) => {
+                telemetry.send_replace(Some(Telemetry::now(state)));
+            }
+            Record::Command { command, response } => info!("{command}: {response}"),
+            Record::Gap { dropped } => {
+                warn!("{dropped} records are missing from the session here");
+                frames.resync();
+            }
+        }
+    }
+    reader.abort();
+    info!("replay complete");
+    Ok(())
+}
+
+fn write_record(out: &mut (impl Write + ?Sized), at: Duration, record: &Record) -> io::Result<()> {
+    let (tag, payload) = match record {
+        Record::Video(packet) => (VIDEO, Cow::Borrowed(packet.as_slice())),
+        Record::Telemetry(state) => (TELEMETRY, Cow::Owned(state.to_string().into_bytes())),
+        Record::Command { command, response } => (
+            COMMAND,
+            Cow::Owned(format!("{command}\n{response}").into_bytes()),
+        ),
+        Record::Gap { dropped } => (GAP, Cow::Owned(dropped.to_le_bytes().to_vec())),
+    };
+    out.write_all(&[tag])?;
+    out.write_all(&micros(at).to_le_bytes())?;
+    out.write_all(&(payload.len() as u32).to_le_bytes())?;
+    out.write_all(&payload)
+}
+
+/// Reads the next record, or `None` at the end of the session
+fn read_record(input: &mut impl Read) -> io::Result<Option<(Duration, Record)>> {
+    let mut tag = [0u8; 1];
+    if input.read(&mut tag)? == 0 {
+        return Ok(None);
+    }
+    let mut at = [0u8; 8];
+    input.read_exact(&mut at)?;
+    let at = Duration::from_micros(u64::from_le_bytes(at));
+    let mut length = [0u8; 4];
+    input.read_exact(&mut length)?;
+    let mut payload = vec![0u8; u32::from_le_bytes(length) as usize];
+    input.read_exact(&mut payload)?;
+
+    let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+    let text = || String::from_utf8(payload.clone()).map_err(|e| invalid(e.to_string()));
+    let record = match tag[0] {
+        VIDEO => Record::Video(payload),
+        TELEMETRY => Record::Telemetry(text()?.parse().map_err(|e| invalid(format!("{e}")))?),
+        COMMAND => {
+            let text = text()?;
+            let (command, response) = text.split_once('\n').unwrap_or((&text, ""));
+            Record::Command {
+                command: command.parse().map_err(|e| invalid(format!("{e}")))?,
+                response: response.to_string(),
+            }
+        }
+        GAP => {
+            let dropped = payload.as_slice().try_into();
+            let dropped = dropped.map_err(|_| invalid(String::from("gap of the wrong size")))?;
+            Record::Gap {
+                dropped: u64::from_le_bytes(dropped),
+            }
+        }
+        tag => return Err(invalid(format!("unknown record {tag}"))),
+    };
+    Ok(Some((at, record)))
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let path = std::env::temp_dir().join(format!("session-{}.hs", std::process::id()));
+        let records = vec![
+            Record::Video(vec![0, 0, 0, 1, 0x67, 0x42]),
+            Record::Telemetry(State {
+                bat: 42,
+                h: 80,
+                ..State::default()
+            }),
+            Record::Command {
+                command: Command::Forward { cm: 30 },
+                response: String::from("ok"),
+            },
+            Record::Gap { dropped: 7 },
+        ];
+        let (recorder, writer) = Recorder::create(&path).unwrap();
+        for record in &records {
+            recorder.record(record.clone());
+        }
+        drop(recorder);
+        writer.await.unwrap().unwrap();
+
+        let mut input = BufReader::new(File::open(&path).unwrap());
+        let mut header = [0u8; MAGIC.len() + 8];
+        input.read_exact(&mut header).unwrap();
+        assert_eq!(MAGIC, &header[..MAGIC.len()]);
+        let mut read = Vec::new();
+        while let Some((_, record)) = read_record(&mut input).unwrap() {
+            read.push(record);
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records, read);
+    }
+
+    #[test]
+    fn test_dropped_records() {
+        let (records, pending) = mpsc::channel(1);
+        let recorder = Recorder {
+            records,
+            shared: Arc::default(),
+        };
+        for packet in [[1], [2], [3]] {
+            recorder.record_video(&packet);
+        }
+        let shared = Arc::clone(&recorder.shared);
+        drop(recorder);
+
+        let mut session = Vec::new();
+        write_session(&mut session, pending, &shared).unwrap();
+        let mut input = &session[MAGIC.len() + 8..];
+        let mut read = Vec::new();
+        while let Some((_, record)) = read_record(&mut input).unwrap() {
+            read.push(record);
+        }
+        assert_eq!(
+            vec![Record::Video(vec![1]), Record::Gap { dropped: 2 }],
+            read
+        );
+        // the dropped packets took turns with one buffer, and the written one is back too
+        assert_eq!(2, shared.spare.lock().unwrap().len());
+    }
+}
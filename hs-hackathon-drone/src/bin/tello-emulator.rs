@@ -0,0 +1,50 @@
+//! Pretends to be a drone on localhost, so the aviator can be run without one.
+
+use clap::Parser;
+use hs_hackathon_drone::emulator::{Emulator, EmulatorConfig};
+use std::{net::SocketAddr, path::PathBuf};
+use tracing_core::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Where to receive commands
+    #[arg(long, default_value = "127.0.0.1:8889")]
+    command: SocketAddr,
+
+    /// Where to send telemetry
+    #[arg(long, default_value = "127.0.0.1:8890")]
+    state: SocketAddr,
+
+    /// Where to send the video stream
+    #[arg(long, default_value = "127.0.0.1:11111")]
+    video: SocketAddr,
+
+    /// A raw annex-B H.264 stream to send instead of a generated one
+    #[arg(long)]
+    recording: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> color_eyre::Result<()> {
+    let args = Args::parse();
+
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env()
+        .expect("internal error: failed to setup tracing");
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    let _emulator = Emulator::start(EmulatorConfig {
+        command: args.command,
+        state: args.state,
+        video: args.video,
+        recording: args.recording,
+        ..EmulatorConfig::default()
+    })
+    .await?;
+
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
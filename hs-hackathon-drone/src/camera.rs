@@ -0,0 +1,341 @@
+//! Frames from the drone's camera, as served by the aviator.
+//!
+//! [`Camera::snapshot`] fetches the latest frame, [`Camera::stream`] has the aviator push
+//! every frame as it is decoded. The stream is `multipart/x-mixed-replace` with one JPEG per
+//! part, which browsers show as a video. What is known about a frame besides its image is
+//! sent in the headers of its response or part, see [`Frame::headers`].
+
+use crate::raw::sensors::State;
+use crate::DroneError;
+use futures::Stream;
+use image::{codecs::jpeg::JpegDecoder, DynamicImage};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The environment variable overriding where [`Camera::connect`] finds the aviator.
+pub const AVIATOR_ENV_VAR: &str = "HS_HACKATHON_AVIATOR";
+
+/// Where [`Camera::connect`] finds the aviator if [`AVIATOR_ENV_VAR`] is not set.
+pub const DEFAULT_AVIATOR_URL: &str = "http://127.0.0.1:3000";
+
+/// The header with the sequence number of a frame.
+pub const SEQUENCE_HEADER: &str = "x-frame-sequence";
+
+/// The header with when a frame was captured, in microseconds since the UNIX epoch.
+pub const CAPTURED_HEADER: &str = "x-frame-captured";
+
+/// The header with when a frame was decoded, in microseconds since the UNIX epoch.
+pub const DECODED_HEADER: &str = "x-frame-decoded";
+
+/// The header with the resolution the drone sent a frame in, e.g. `960x720`.
+pub const RESOLUTION_HEADER: &str = "x-frame-resolution";
+
+/// The header with the telemetry of the drone at the time a frame was captured, in the
+/// format the drone sends it in.
+pub const STATE_HEADER: &str = "x-drone-state";
+
+/// The boundary between the frames of the stream.
+pub const STREAM_BOUNDARY: &str = "frame";
+
+/// A connection to the camera of the drone and abstraction to access the drones camera
+pub struct Camera {
+    client: reqwest::Client,
+    base: reqwest::Url,
+}
+
+/// A videoframe recieved from the drones camera
+#[derive(Clone)]
+pub struct Frame {
+    pub image: DynamicImage,
+    /// Counts up with every frame the aviator decodes.
+    pub sequence: u64,
+    /// When the aviator received the frame from the drone.
+    pub captured: SystemTime,
+    /// When the aviator had decoded the frame.
+    pub decoded: SystemTime,
+    /// Width and height of the frame as the drone sent it.
+    pub resolution: (u32, u32),
+    /// The telemetry of the drone when the frame was captured, if there was any yet.
+    pub state: Option<State>,
+}
+
+impl Frame {
+    /// How long ago the frame was captured, going by the clock of this machine
+    pub fn age(&self) -> Duration {
+        self.captured.elapsed().unwrap_or_default()
+    }
+
+    /// The headers the aviator sends along with the frame
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let (width, height) = self.resolution;
+        let mut headers = vec![
+            (SEQUENCE_HEADER, self.sequence.to_string()),
+            (CAPTURED_HEADER, micros(self.captured).to_string()),
+            (DECODED_HEADER, micros(self.decoded).to_string()),
+            (RESOLUTION_HEADER, format!("{width}x{height}")),
+        ];
+        if let Some(state) = &self.state {
+            headers.push((STATE_HEADER, state.to_string()));
+        }
+        headers
+    }
+
+    /// Decodes a JPEG the aviator sent with the given headers.
+    fn decode<'a>(
+        jpeg: &[u8],
+        header: impl Fn(&str) -> Option<&'a str>,
+    ) -> Result<Frame, DroneError> {
+        let decoder = JpegDecoder::new(jpeg)?;
+        let image = DynamicImage::from_decoder(decoder)?;
+        let time = |name| {
+            let micros = parse(name, header(name))?.unwrap_or_default();
+            Ok::<_, DroneError>(UNIX_EPOCH + Duration::from_micros(micros))
+        };
+        let resolution = match header(RESOLUTION_HEADER) {
+            Some(resolution) => resolution
+                .split_once('x')
+                .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+                .ok_or_else(|| invalid(RESOLUTION_HEADER, resolution))?,
+            None => (image.width(), image.height()),
+        };
+        Ok(Frame {
+            sequence: parse(SEQUENCE_HEADER, header(SEQUENCE_HEADER))?.unwrap_or_default(),
+            captured: time(CAPTURED_HEADER)?,
+            decoded: time(DECODED_HEADER)?,
+            resolution,
+            state: parse(STATE_HEADER, header(STATE_HEADER))?,
+            image,
+        })
+    }
+}
+
+impl Camera {
+    /// Connect to the aviator at [`AVIATOR_ENV_VAR`], or on this host
+    pub async fn connect() -> Result<Self, DroneError> {
+        let url = std::env::var(AVIATOR_ENV_VAR).unwrap_or_else(|_| DEFAULT_AVIATOR_URL.into());
+        Self::connect_to(&url).await
+    }
+
+    /// Connect to the aviator at `url`, e.g. `http://my-car:3000`
+    pub async fn connect_to(url: &str) -> Result<Self, DroneError> {
+        // without the trailing slash, joining would replace the last path segment
+        let base = format!("{}/", url.trim_end_matches('/'));
+        let base = reqwest::Url::parse(&base).map_err(|e| DroneError::InvalidUrl {
+            url: url.to_string(),
+            reason: e.to_string(),
+        })?;
+        Ok(Self {
+            client: reqwest::Client::new(),
+            base,
+        })
+    }
+
+    /// The latest frame
+    pub async fn snapshot(&self) -> Result<Frame, DroneError> {
+        let res = self.get("camera?clean=true").await?;
+        let headers = res.headers().clone();
+        let bytes = res.bytes().await?;
+        Frame::decode(&bytes, |name| headers.get(name)?.to_str().ok())
+    }
+
+    /// Every frame from now on, as soon as the aviator has it
+    ///
+    /// Frames that arrive while the last one is still being processed are skipped, so the
+    /// stream never falls behind. Tell by [`Frame::sequence`] how many were.
+    pub async fn stream(
+        &self,
+    ) -> Result<impl Stream<Item = Result<Frame, DroneError>>, DroneError> {
+        let res = self.get("camera/stream").await?;
+        let parts = Parts::default();
+        Ok(futures::stream::unfold(
+            (res, parts),
+            |(mut res, mut parts)| async move {
+                loop {
+                    match parts.next() {
+                        Ok(Some(frame)) => return Some((Ok(frame), (res, parts))),
+                        Ok(None) => {}
+                        Err(e) => return Some((Err(e), (res, Parts::failed()))),
+                    }
+                    if parts.failed {
+                        return None;
+                    }
+                    match res.chunk().await {
+                        Ok(Some(chunk)) => parts.buffer.extend_from_slice(&chunk),
+                        Ok(None) => return None,
+                        Err(e) => return Some((Err(e.into()), (res, Parts::failed()))),
+                    }
+                }
+            },
+        ))
+    }
+
+    async fn get(&self, path: &str) -> Result<reqwest::Response, DroneError> {
+        let url = self.base.join(path).map_err(|e| DroneError::InvalidUrl {
+            url: self.base.to_string(),
+            reason: e.to_string(),
+        })?;
+        let res = self.client.get(url).send().await?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await?;
+            return Err(DroneError::Status { status, body });
+        };
+        Ok(res)
+    }
+}
+
+/// The parts of the stream received so far
+#[derive(Default)]
+struct Parts {
+    buffer: Vec<u8>,
+    /// Set after an error, to end the stream
+    failed: bool,
+}
+
+impl Parts {
+    fn failed() -> Self {
+        Self {
+            buffer: Vec::new(),
+            failed: true,
+        }
+    }
+
+    /// The next complete frame in the buffer, if there is one
+    fn next(&mut self) -> Result<Option<Frame>, DroneError> {
+        if self.failed {
+            return Ok(None);
+        }
+        let Some(end) = find(&self.buffer, b"\r\n\r\n") else {
+            return Ok(None);
+        };
+        let head = std::str::from_utf8(&self.buffer[..end])
+            .map_err(|_| DroneError::Malformed(String::from("headers are not utf-8")))?;
+        let mut lines = head.lines().filter(|line| !line.is_empty());
+        if lines.next() != Some(&format!("--{STREAM_BOUNDARY}")) {
+            return Err(DroneError::Malformed(String::from("missing boundary")));
+        }
+        let mut headers = Vec::new();
+        for line in lines {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| DroneError::Malformed(format!("invalid header `{line}`")))?;
+            headers.push((name.trim().to_ascii_lowercase(), value.trim()));
+        }
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(header, _)| header == name)
+                .map(|(_, value)| *value)
+        };
+        let length: usize = parse("content-length", header("content-length"))?
+            .ok_or_else(|| DroneError::Malformed(String::from("missing content-length")))?;
+        let start = end + 4;
+        if self.buffer.len() < start + length {
+            return Ok(None);
+        }
+        let frame = Frame::decode(&self.buffer[start..start + length], header)?;
+        self.buffer.drain(..start + length);
+        Ok(Some(frame))
+    }
+}
+
+/// Parses the value of a header, if there is one
+fn parse<T: FromStr>(name: &str, value: Option<&str>) -> Result<Option<T>, DroneError> {
+    value
+        .map(|value| value.parse().map_err(|_| invalid(name, value)))
+        .transpose()
+}
+
+fn invalid(name: &str, value: &str) -> DroneError {
+    DroneError::Malformed(format!("invalid {name} `{value}`"))
+}
+
+fn micros(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// The part of the stream for `frame`, up to its JPEG of `length` bytes
+pub fn part_header(length: usize, frame: &Frame) -> String {
+    let mut part =
+        format!("--{STREAM_BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {length}\r\n");
+    for (name, value) in frame.headers() {
+        part.push_str(&format!("{name}: {value}\r\n"));
+    }
+    part.push_str("\r\n");
+    part
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+    use std::io::Cursor;
+
+    fn frame(sequence: u64, state: Option<State>) -> Frame {
+        let captured = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        Frame {
+            image: RgbImage::new(16, 8).into(),
+            sequence,
+            captured,
+            decoded: captured + Duration::from_millis(12),
+            resolution: (16, 8),
+            state,
+        }
+    }
+
+    fn part(frame: &Frame) -> Vec<u8> {
+        let mut jpeg = Cursor::new(Vec::new());
+        frame
+            .image
+            .write_to(&mut jpeg, image::ImageFormat::Jpeg)
+            .unwrap();
+        let jpeg = jpeg.into_inner();
+        let mut part = part_header(jpeg.len(), frame).into_bytes();
+        part.extend(jpeg);
+        part.extend(b"\r\n");
+        part
+    }
+
+    #[test]
+    fn test_parts() {
+        let state = State {
+            bat: 87,
+            h: 120,
+            ..State::default()
+        };
+        let sent = [frame(7, None), frame(8, Some(state))];
+        let stream = [part(&sent[0]), part(&sent[1])].concat();
+        let mut parts = Parts::default();
+        let mut frames = Vec::new();
+        // arrives in arbitrary chunks
+        for chunk in stream.chunks(100) {
+            parts.buffer.extend_from_slice(chunk);
+            while let Some(frame) = parts.next().unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(2, frames.len());
+        for (sent, received) in sent.iter().zip(&frames) {
+            assert_eq!(sent.sequence, received.sequence);
+            assert_eq!(sent.captured, received.captured);
+            assert_eq!(sent.decoded, received.decoded);
+            assert_eq!(sent.resolution, received.resolution);
+            assert_eq!(sent.state, received.state);
+        }
+        assert_eq!((16, 8), (frames[1].image.width(), frames[1].image.height()));
+
+        let mut parts = Parts {
+            buffer: b"--nope\r\n\r\n".to_vec(),
+            failed: false,
+        };
+        assert!(matches!(parts.next(), Err(DroneError::Malformed(_))));
+    }
+}
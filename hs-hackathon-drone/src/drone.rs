@@ -0,0 +1,638 @@
+use crate::raw::control::{send_commands, Command, CommandPolicy, QueryValue, Request, Response};
+use crate::raw::sensors::{State, Telemetry};
+use crate::raw::{RCV_ADDR, RCV_PORT, SND_ADDR, SND_PORT};
+use crate::safety::{FailsafeState, Position, SafetyEnvelope};
+use crate::DroneError;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::Instrument;
+#[allow(unused_imports)]
+use tracing::{debug, info, trace, warn};
+
+/// How long [`Drone::state`] waits for the first telemetry.
+const TELEMETRY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the drone is reminded that we are still here while idle. It lands on its own
+/// after 15 seconds without a command.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Speed in cm/s of [`Drone::move_by`].
+const MOVE_SPEED: u32 = 50;
+
+/// How many commands may wait to be sent.
+const QUEUE_SIZE: usize = 16;
+
+/// How many answered commands a slow watcher of [`Drone::watch_commands`] may fall behind.
+const ANSWERED_SIZE: usize = 64;
+
+/// Where to find the drone, and where to listen for what it sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DroneAddresses {
+    /// Where commands are sent to
+    pub drone: SocketAddr,
+    /// Where commands are sent from, and their answers received
+    pub command_bind: SocketAddr,
+    /// Where the telemetry is received
+    pub state_bind: SocketAddr,
+}
+
+impl Default for DroneAddresses {
+    /// The addresses of a drone with its own WiFi network
+    fn default() -> Self {
+        Self {
+            drone: SocketAddr::from((SND_ADDR, SND_PORT)),
+            command_bind: SocketAddr::from(([0, 0, 0, 0], SND_PORT)),
+            state_bind: SocketAddr::from((RCV_ADDR, RCV_PORT)),
+        }
+    }
+}
+
+/// A connection to the drone, to fly it directly rather than through the aviator
+///
+/// Only one program can talk to the drone at a time, so this cannot be used while the
+/// aviator is running.
+pub struct Drone {
+    commands: Commands,
+    state: watch::Receiver<Option<Telemetry>>,
+    tasks: Vec<JoinHandle<()>>,
+    guard: Option<(Arc<Guard>, JoinHandle<()>)>,
+    failsafe: Arc<watch::Sender<FailsafeState>>,
+}
+
+/// Enforces a [`SafetyEnvelope`]
+struct Guard {
+    envelope: SafetyEnvelope,
+    position: Mutex<Position>,
+    /// Held from checking a command until it is acknowledged, so that the next one is
+    /// checked against where this one took the drone
+    checking: tokio::sync::Mutex<()>,
+}
+
+impl Drone {
+    /// Connect to the drone on its default address and put it into SDK mode
+    pub async fn connect() -> Result<Self, DroneError> {
+        Self::connect_to(DroneAddresses::default()).await
+    }
+
+    /// Connect to the drone at the given addresses and put it into SDK mode
+    pub async fn connect_to(addresses: DroneAddresses) -> Result<Self, DroneError> {
+        let command_socket = UdpSocket::bind(addresses.command_bind).await?;
+        let state_socket = UdpSocket::bind(addresses.state_bind).await?;
+        Self::connect_with(addresses.drone, command_socket, state_socket).await
+    }
+
+    /// Connect to the drone at `drone` through already bound sockets and put it into SDK mode
+    ///
+    /// Commands are sent from `command_socket`, and the telemetry received on `state_socket`.
+    pub async fn connect_with(
+        drone: SocketAddr,
+        command_socket: UdpSocket,
+        state_socket: UdpSocket,
+    ) -> Result<Self, DroneError> {
+        let (command_tx, command_rx) = mpsc::channel(QUEUE_SIZE);
+        let (state_tx, state_rx) = watch::channel(None);
+        let commands = Commands {
+            queue: command_tx,
+            last_sent: Arc::new(Mutex::new(Instant::now())),
+            answered: broadcast::channel(ANSWERED_SIZE).0,
+        };
+
+        let dispatcher = tokio::spawn(
+            async move {
+                if let Err(e) = send_commands(command_socket, drone, command_rx).await {
+                    warn!("command loop failed: {e:?}");
+                }
+            }
+            .instrument(tracing::info_span!("command")),
+        );
+        let tracker = tokio::spawn(
+            async move {
+                if let Err(e) = track_state(state_socket, state_tx).await {
+                    warn!("state tracker failed: {e:?}");
+                }
+            }
+            .instrument(tracing::info_span!("state")),
+        );
+        let heartbeat =
+            tokio::spawn(heartbeat(commands.clone()).instrument(tracing::info_span!("heartbeat")));
+
+        let drone = Drone {
+            commands,
+            state: state_rx,
+            tasks: vec![dispatcher, tracker, heartbeat],
+            guard: None,
+            failsafe: Arc::new(watch::channel(FailsafeState::Nominal).0),
+        };
+        drone.send_ok(Command::SDKInit).await?;
+        Ok(drone)
+    }
+
+    /// Send a command and wait for the drone's response
+    ///
+    /// Commands are queued and sent one at a time, with the timeout and retries of
+    /// [`CommandPolicy::for_command`]. Commands with values the drone would not accept are
+    /// not sent at all.
+    pub async fn send(&self, command: Command) -> Result<Response, DroneError> {
+        let policy = CommandPolicy::for_command(&command);
+        self.send_with(command, policy).await
+    }
+
+    /// Send a command with the given timeout and retries, and wait for the drone's response
+    pub async fn send_with(
+        &self,
+        command: Command,
+        policy: CommandPolicy,
+    ) -> Result<Response, DroneError> {
+        command.validate()?;
+        let Some((guard, _)) = &self.guard else {
+            return self.commands.send(command, policy).await;
+        };
+        let _checking = guard.checking.lock().await;
+        let position = *guard.position.lock().expect("poisoned");
+        let checked = guard.envelope.check(
+            &position,
+            &command,
+            self.state.borrow().as_ref(),
+            SystemTime::now(),
+        );
+        checked?;
+
+        let response = self.commands.send(command.clone(), policy).await?;
+        if response == Response::Ok {
+            let mut position = guard.position.lock().expect("poisoned");
+            if let Some(next) = position.after(&command) {
+                *position = next;
+            }
+        }
+        Ok(response)
+    }
+
+    /// Keep the drone inside `envelope` from now on, or stop doing so with `None`
+    ///
+    /// The envelope is centered on where the drone is, so set it before takeoff.
+    pub fn set_envelope(&mut self, envelope: Option<SafetyEnvelope>) {
+        if let Some((_, enforcer)) = self.guard.take() {
+            enforcer.abort();
+        }
+        self.failsafe.send_replace(FailsafeState::Nominal);
+        let Some(envelope) = envelope else {
+            return;
+        };
+        let guard = Arc::new(Guard {
+            envelope,
+            position: Mutex::new(Position::default()),
+            checking: tokio::sync::Mutex::new(()),
+        });
+        let enforcer = tokio::spawn(
+            enforce(
+                Arc::clone(&guard),
+                self.commands.clone(),
+                self.state.clone(),
+                Arc::clone(&self.failsafe),
+            )
+            .instrument(tracing::info_span!("safety")),
+        );
+        self.guard = Some((guard, enforcer));
+    }
+
+    /// Where the drone should be relative to where it took off, if it has an envelope
+    pub fn position(&self) -> Option<Position> {
+        let (guard, _) = self.guard.as_ref()?;
+        let position = *guard.position.lock().expect("poisoned");
+        Some(position)
+    }
+
+    /// Watch how close the drone is to having to land
+    ///
+    /// Stays [`FailsafeState::Nominal`] while the drone has no envelope.
+    pub fn watch_failsafe(&self) -> watch::Receiver<FailsafeState> {
+        self.failsafe.subscribe()
+    }
+
+    /// Send a command the drone answers with `ok`
+    pub async fn send_ok(&self, command: Command) -> Result<(), DroneError> {
+        match self.send(command.clone()).await? {
+            Response::Ok => Ok(()),
+            Response::Timeout => Err(DroneError::Timeout {
+                command: command.to_string(),
+            }),
+            Response::Error(response) => Err(DroneError::Rejected {
+                command: command.to_string(),
+                response,
+            }),
+            Response::Value(value) => Err(DroneError::Rejected {
+                command: command.to_string(),
+                response: format!("{value:?}"),
+            }),
+        }
+    }
+
+    /// Ask the drone for a value, e.g. with [`Command::QueryBattery`]
+    pub async fn query(&self, command: Command) -> Result<QueryValue, DroneError> {
+        match self.send(command.clone()).await? {
+            Response::Value(value) => Ok(value),
+            Response::Timeout => Err(DroneError::Timeout {
+                command: command.to_string(),
+            }),
+            Response::Error(response) => Err(DroneError::Rejected {
+                command: command.to_string(),
+                response,
+            }),
+            Response::Ok => Err(DroneError::Rejected {
+                command: command.to_string(),
+                response: String::from("ok"),
+            }),
+        }
+    }
+
+    /// Take off and hover
+    pub async fn takeoff(&self) -> Result<(), DroneError> {
+        self.send_ok(Command::Takeoff).await
+    }
+
+    /// Land where the drone is
+    pub async fn land(&self) -> Result<(), DroneError> {
+        self.send_ok(Command::Land).await
+    }
+
+    /// Fly `x` cm forward, `y` cm left and `z` cm up
+    ///
+    /// The drone refuses to move less than 20cm along all axes, or more than 500cm along any.
+    pub async fn move_by(&self, x: i32, y: i32, z: i32) -> Result<(), DroneError> {
+        self.send_ok(Command::Go {
+            x,
+            y,
+            z,
+            speed: MOVE_SPEED,
+        })
+        .await
+    }
+
+    /// Turn by the given number of degrees, clockwise if positive
+    ///
+    /// Turning by zero degrees sends nothing.
+    pub async fn rotate(&self, degrees: i32) -> Result<(), DroneError> {
+        if degrees == 0 {
+            return Ok(());
+        }
+        let command = if degrees > 0 {
+            Command::Clockwise {
+                degrees: degrees.unsigned_abs(),
+            }
+        } else {
+            Command::CounterClockwise {
+                degrees: degrees.unsigned_abs(),
+            }
+        };
+        self.send_ok(command).await
+    }
+
+    /// The latest telemetry of the drone
+    ///
+    /// Waits for the first telemetry to arrive right after connecting.
+    pub async fn state(&self) -> Result<State, DroneError> {
+        let mut state = self.state.clone();
+        let latest = tokio::time::timeout(TELEMETRY_TIMEOUT, state.wait_for(Option::is_some))
+            .await
+            .map_err(|_| DroneError::Timeout {
+                command: String::from("telemetry"),
+            })?
+            .map_err(|_| DroneError::Disconnected)?;
+        Ok(latest.clone().expect("waited for the state").state)
+    }
+
+    /// Watch every command sent to the drone from now on along with its answer, including
+    /// the heartbeat and the landings of the [`SafetyEnvelope`]
+    pub fn watch_commands(&self) -> broadcast::Receiver<(Command, Response)> {
+        self.commands.answered.subscribe()
+    }
+
+    /// Watch the telemetry of the drone as it arrives, `None` until the first one does
+    pub fn watch_state(&self) -> watch::Receiver<Option<Telemetry>> {
+        self.state.clone()
+    }
+}
+
+impl Drop for Drone {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        if let Some((_, enforcer)) = &self.guard {
+            enforcer.abort();
+        }
+    }
+}
+
+/// The queue of the command dispatcher
+#[derive(Clone)]
+struct Commands {
+    queue: mpsc::Sender<Request>,
+    /// When the last command was queued, to know when the drone needs a heartbeat
+    last_sent: Arc<Mutex<Instant>>,
+    /// Every command along with its answer
+    answered: broadcast::Sender<(Command, Response)>,
+}
+
+impl Commands {
+    async fn send(&self, command: Command, policy: CommandPolicy) -> Result<Response, DroneError> {
+        *self.last_sent.lock().expect("poisoned") = Instant::now();
+        let (reply, response) = oneshot::channel();
+        let request = Request {
+            command: command.clone(),
+            policy,
+            reply,
+        };
+        self.queue
+            .send(request)
+            .await
+            .map_err(|_| DroneError::Disconnected)?;
+        let response = response.await.map_err(|_| DroneError::Disconnected)?;
+        // nobody might be watching
+        let _ = self.answered.send((command, response.clone()));
+        Ok(response)
+    }
+}
+
+/// Keeps the drone from landing on its own while no commands are sent
+async fn heartbeat(commands: Commands) {
+    debug!("started");
+    loop {
+        let due = *commands.last_sent.lock().expect("poisoned") + HEARTBEAT_INTERVAL;
+        if Instant::now() < due {
+            // fine -- means there are commands flowing
+            tokio::time::sleep_until(due).await;
+            continue;
+        }
+        debug!("thud");
+        // if a command fails to ack (eg, because drone shuts down),
+        // we don't want the heartbeat loop to stop!
+        let policy = CommandPolicy::for_command(&Command::Stop);
+        match commands.send(Command::Stop, policy).await {
+            Ok(Response::Timeout) => debug!("wait despite no ack"),
+            Ok(response) => debug!("wait after {response:?}"),
+            Err(_) => {
+                debug!("exiting since command channel is closed");
+                return;
+            }
+        }
+    }
+}
+
+/// Tracks the drone in its envelope, and lands it when it has to
+async fn enforce(
+    guard: Arc<Guard>,
+    commands: Commands,
+    mut state: watch::Receiver<Option<Telemetry>>,
+    failsafe: Arc<watch::Sender<FailsafeState>>,
+) {
+    debug!("started");
+    loop {
+        // wake up on telemetry, or when it is overdue
+        if let Ok(Err(_)) =
+            tokio::time::timeout(guard.envelope.telemetry_timeout, state.changed()).await
+        {
+            debug!("exiting since telemetry channel is closed");
+            return;
+        }
+        let latest = state.borrow_and_update().clone();
+        let flying = {
+            let mut position = guard.position.lock().expect("poisoned");
+            if let Some(latest) = &latest {
+                position.observe(latest);
+            }
+            position.flying
+        };
+        let next =
+            failsafe
+                .borrow()
+                .next(&guard.envelope, flying, latest.as_ref(), SystemTime::now());
+        let previous = failsafe.send_replace(next);
+        if next != previous {
+            match next {
+                FailsafeState::Nominal | FailsafeState::Landed { .. } => {
+                    info!(%previous, "failsafe: {next}")
+                }
+                _ => warn!(%previous, "failsafe: {next}"),
+            }
+        }
+        // until the drone is down, in case landing failed
+        if !matches!(next, FailsafeState::Landing { .. }) {
+            continue;
+        }
+        let policy = CommandPolicy::for_command(&Command::Land);
+        match commands.send(Command::Land, policy).await {
+            Ok(Response::Ok) => {
+                let mut position = guard.position.lock().expect("poisoned");
+                if let Some(landed) = position.after(&Command::Land) {
+                    *position = landed;
+                }
+            }
+            Ok(response) => warn!("failed to land: {response:?}"),
+            Err(_) => {
+                debug!("exiting since command channel is closed");
+                return;
+            }
+        }
+    }
+}
+
+/// Publishes the telemetry the drone broadcasts on `socket`. Blocks until nobody watches
+/// anymore.
+async fn track_state(
+    socket: UdpSocket,
+    state: watch::Sender<Option<Telemetry>>,
+) -> std::io::Result<()> {
+    debug!("started");
+    let mut buffer = [0u8; 2000];
+    loop {
+        trace!("await update");
+        let size = tokio::select! {
+            size = socket.recv(&mut buffer) => size?,
+            _ = state.closed() => return Ok(()),
+        };
+        trace!("got update");
+        let Ok(received) = std::str::from_utf8(&buffer[..size]) else {
+            warn!("got invalid utf-8 from drone: {:?}", &buffer[..size]);
+            continue;
+        };
+        match received.parse::<State>() {
+            Ok(update) => {
+                state.send_replace(Some(Telemetry::now(update)));
+            }
+            Err(e) => warn!("Invalid drone state ({e}): {received}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::safety::LandReason;
+    use crate::SafetyError;
+
+    #[tokio::test]
+    async fn test_fly() {
+        let (emulator, drone) = emulated().await;
+
+        let mut answered = drone.watch_commands();
+        drone.takeoff().await.unwrap();
+        assert_eq!(
+            (Command::Takeoff, Response::Ok),
+            answered.recv().await.unwrap()
+        );
+        drone.move_by(50, 0, -20).await.unwrap();
+        drone.rotate(-90).await.unwrap();
+        drone.rotate(0).await.unwrap();
+        drone.land().await.unwrap();
+        assert!(matches!(
+            drone.land().await,
+            Err(DroneError::Rejected { .. })
+        ));
+        assert!(matches!(
+            drone.rotate(400).await,
+            Err(DroneError::InvalidCommand(_))
+        ));
+        assert_eq!(
+            vec![
+                "command",
+                "takeoff",
+                "go 50 0 -20 50",
+                "ccw 90",
+                "land",
+                "land"
+            ],
+            emulator.received()
+        );
+
+        let state = drone.state().await.unwrap();
+        assert_eq!(100, state.bat);
+        assert_eq!(
+            QueryValue::Number(100.0),
+            drone.query(Command::QueryBattery).await.unwrap()
+        );
+        emulator.update_state(|state| state.bat = 12);
+        let mut updates = drone.watch_state();
+        let low = updates
+            .wait_for(|telemetry| telemetry.as_ref().is_some_and(|t| t.state.bat == 12))
+            .await;
+        assert!(low.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_envelope() {
+        let (emulator, mut drone) = emulated().await;
+        drone.set_envelope(Some(SafetyEnvelope {
+            max_x: 150,
+            ..SafetyEnvelope::default()
+        }));
+
+        // takeoff is refused until there is telemetry
+        drone.state().await.unwrap();
+        drone.takeoff().await.unwrap();
+        drone.move_by(100, 0, 0).await.unwrap();
+        assert!(matches!(
+            drone.move_by(100, 0, 0).await,
+            Err(DroneError::Unsafe(SafetyError::OutsideEnvelope {
+                x: 200,
+                y: 0
+            }))
+        ));
+        drone.rotate(180).await.unwrap();
+        drone.move_by(200, 0, 0).await.unwrap();
+        assert_eq!(
+            vec!["takeoff", "go 100 0 0 50", "cw 180", "go 200 0 0 50"],
+            emulator.received()[1..]
+        );
+
+        // warns, then lands on its own once the battery runs low
+        let mut failsafe = drone.watch_failsafe();
+        emulator.update_state(|state| state.bat = 15);
+        let warned = tokio::time::timeout(
+            Duration::from_secs(5),
+            failsafe.wait_for(|state| *state == FailsafeState::LowBattery { battery: 15 }),
+        )
+        .await
+        .map(|warned| warned.is_ok());
+        assert_eq!(Ok(true), warned);
+        emulator.update_state(|state| state.bat = 9);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while emulator.is_flying() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("did not land");
+        assert!(!drone.position().unwrap().flying);
+        let landed = tokio::time::timeout(
+            Duration::from_secs(5),
+            failsafe.wait_for(|state| matches!(state, FailsafeState::Landed { .. })),
+        )
+        .await;
+        assert_eq!(
+            FailsafeState::Landed {
+                reason: LandReason::LowBattery(9)
+            },
+            *landed.unwrap().unwrap()
+        );
+        assert!(matches!(
+            drone.takeoff().await,
+            Err(DroneError::Unsafe(SafetyError::LowBattery(9)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_grounded() {
+        let (emulator, mut drone) = emulated().await;
+        drone.set_envelope(Some(SafetyEnvelope::default()));
+        drone.state().await.unwrap();
+        drone.takeoff().await.unwrap();
+
+        // lands once it overheats, and stays down while it is hot
+        let mut failsafe = drone.watch_failsafe();
+        emulator.update_state(|state| state.temph = 90);
+        let landed = tokio::time::timeout(
+            Duration::from_secs(5),
+            failsafe.wait_for(|state| matches!(state, FailsafeState::Landed { .. })),
+        )
+        .await
+        .map(|landed| landed.map(|state| *state).ok());
+        assert_eq!(
+            Ok(Some(FailsafeState::Landed {
+                reason: LandReason::Overheating(90)
+            })),
+            landed
+        );
+        assert!(matches!(
+            drone.takeoff().await,
+            Err(DroneError::Unsafe(SafetyError::Grounded(
+                LandReason::Overheating(90)
+            )))
+        ));
+
+        emulator.update_state(|state| state.temph = 40);
+        let mut updates = drone.watch_state();
+        let cooled = updates
+            .wait_for(|telemetry| telemetry.as_ref().is_some_and(|t| t.state.temph == 40))
+            .await;
+        assert!(cooled.is_ok());
+        drop(cooled);
+        drone.takeoff().await.unwrap();
+    }
+
+    /// A drone connected to an emulator
+    async fn emulated() -> (Emulator, Drone) {
+        let (emulator, sockets) = Emulator::start_local().await.unwrap();
+        let command = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let drone = Drone::connect_with(emulator.command_addr(), command, sockets.state)
+            .await
+            .unwrap();
+        (emulator, drone)
+    }
+}
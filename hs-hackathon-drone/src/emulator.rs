@@ -0,0 +1,515 @@
+//! A stand-in for the drone, to run the aviator and the [`Drone`](crate::Drone) client
+//! without hardware.
+//!
+//! The [`Emulator`] speaks the Tello protocol over UDP: it answers commands on the command
+//! port, sends telemetry once the SDK mode is entered, and sends an H.264 stream once it is
+//! turned on with `streamon`. The video is either a recording of a raw annex-B stream, or
+//! generated on the fly.
+
+use crate::h264::NalSplitter;
+use crate::raw::control::Command;
+use crate::raw::sensors::State;
+use crate::raw::{RCV_PORT, SND_PORT, VID_PORT};
+use image::{Rgb, RgbImage};
+use openh264::encoder::{Encoder, EncoderConfig};
+use openh264::formats::YUVBuffer;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+#[allow(unused_imports)]
+use tracing::{debug, info, trace, warn};
+
+/// The drone splits its video stream into packets of this size.
+const VIDEO_PACKET_SIZE: usize = 1460;
+
+/// How many of the latest commands [`Emulator::received`] remembers.
+pub const RECEIVED_HISTORY: usize = 1024;
+
+/// Where the [`Emulator`] listens and sends to, and what it sends.
+#[derive(Debug, Clone)]
+pub struct EmulatorConfig {
+    /// Where commands are received.
+    pub command: SocketAddr,
+    /// Where telemetry is sent.
+    pub state: SocketAddr,
+    /// Where the video stream is sent.
+    pub video: SocketAddr,
+    /// How often telemetry is sent.
+    pub telemetry_interval: Duration,
+    /// How often a video frame is sent.
+    pub frame_interval: Duration,
+    /// Size of the generated video.
+    pub width: u32,
+    pub height: u32,
+    /// A raw annex-B H.264 stream to send in a loop rather than generating one.
+    pub recording: Option<PathBuf>,
+}
+
+impl Default for EmulatorConfig {
+    /// The addresses the aviator expects a drone to be at, on localhost.
+    fn default() -> Self {
+        Self {
+            command: SocketAddr::from(([127, 0, 0, 1], SND_PORT)),
+            state: SocketAddr::from(([127, 0, 0, 1], RCV_PORT)),
+            video: SocketAddr::from(([127, 0, 0, 1], VID_PORT)),
+            telemetry_interval: Duration::from_millis(100),
+            frame_interval: Duration::from_millis(33),
+            width: 960,
+            height: 720,
+            recording: None,
+        }
+    }
+}
+
+impl EmulatorConfig {
+    /// A config on a free port of localhost, sending telemetry to `state` and video to
+    /// `video`, with a small generated video.
+    pub fn local(state: SocketAddr, video: SocketAddr) -> Self {
+        Self {
+            command: SocketAddr::from(([127, 0, 0, 1], 0)),
+            state,
+            video,
+            width: 320,
+            height: 240,
+            ..Self::default()
+        }
+    }
+}
+
+/// Sockets on free ports of localhost that an [`Emulator::start_local`] sends to
+#[derive(Debug)]
+pub struct LocalSockets {
+    /// Where the telemetry is received
+    pub state: UdpSocket,
+    /// Where the video stream is received
+    pub video: UdpSocket,
+}
+
+/// What the emulated drone is doing.
+#[derive(Debug, Default)]
+struct Flight {
+    sdk: bool,
+    flying: bool,
+    speed: u32,
+    state: State,
+    received: VecDeque<String>,
+}
+
+impl Flight {
+    fn new() -> Self {
+        Self {
+            speed: 10,
+            state: State {
+                bat: 100,
+                templ: 60,
+                temph: 62,
+                baro: 100.0,
+                agz: -1000.0,
+                ..State::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    /// Handles a command, returning the answer if the drone sends one.
+    fn handle(&mut self, received: &str, streaming: &AtomicBool) -> Option<String> {
+        if self.received.len() == RECEIVED_HISTORY {
+            self.received.pop_front();
+        }
+        self.received.push_back(received.to_string());
+        let Ok(command) = received.parse::<Command>() else {
+            return Some(String::from("error"));
+        };
+        if !self.sdk && command != Command::SDKInit {
+            return Some(String::from("error"));
+        }
+        let ok = |ok: bool| Some(String::from(if ok { "ok" } else { "error" }));
+        let state = &mut self.state;
+        match command {
+            Command::SDKInit => {
+                self.sdk = true;
+                ok(true)
+            }
+            Command::Takeoff => {
+                let takeoff = !self.flying;
+                if takeoff {
+                    self.flying = true;
+                    state.h = 80;
+                    state.tof = 90;
+                }
+                ok(takeoff)
+            }
+            Command::Land | Command::Emergency => {
+                let was_flying = self.flying;
+                self.flying = false;
+                state.h = 0;
+                state.tof = 10;
+                ok(was_flying || command == Command::Emergency)
+            }
+            Command::EnableStream | Command::DisableStream => {
+                streaming.store(command == Command::EnableStream, Ordering::Relaxed);
+                ok(true)
+            }
+            Command::Rc { .. } => None,
+            Command::Speed { speed } => {
+                self.speed = speed;
+                ok(true)
+            }
+            Command::Up { cm } => self.fly(|state| state.h += cm as i32),
+            Command::Down { cm } => self.fly(|state| state.h = (state.h - cm as i32).max(20)),
+            Command::Go { z, .. } => self.fly(|state| state.h = (state.h + z).max(20)),
+            Command::Clockwise { degrees } => self.fly(|state| turn(state, degrees as i32)),
+            Command::CounterClockwise { degrees } => {
+                self.fly(|state| turn(state, -(degrees as i32)))
+            }
+            Command::Left { .. }
+            | Command::Right { .. }
+            | Command::Forward { .. }
+            | Command::Back { .. }
+            | Command::Flip { .. }
+            | Command::Curve { .. }
+            | Command::Stop => self.fly(|_| {}),
+            Command::QuerySpeed => Some(format!("{}.0", self.speed)),
+            Command::QueryBattery => Some(state.bat.to_string()),
+            Command::QueryFlightTime => Some(format!("{}s", state.time)),
+            Command::QueryWifi => Some(String::from("90")),
+            Command::QuerySdk => Some(String::from("20")),
+            Command::QuerySerialNumber => Some(String::from("0TQZEMULATOR")),
+            Command::SetSsidPass { .. }
+            | Command::JoinAccessPoint { .. }
+            | Command::EnableMissionPads
+            | Command::DisableMissionPads
+            | Command::MissionPadDirection { .. } => ok(true),
+        }
+    }
+
+    /// Moves the drone if it is in the air.
+    fn fly(&mut self, update: impl FnOnce(&mut State)) -> Option<String> {
+        if !self.flying {
+            return Some(String::from("error"));
+        }
+        update(&mut self.state);
+        self.state.tof = self.state.h + 10;
+        Some(String::from("ok"))
+    }
+}
+
+fn turn(state: &mut State, degrees: i32) {
+    state.yaw = (state.yaw + degrees + 180).rem_euclid(360) - 180;
+}
+
+/// An emulated drone, see the [module docs](self).
+///
+/// Stops when dropped.
+pub struct Emulator {
+    command: SocketAddr,
+    flight: Arc<Mutex<Flight>>,
+    shutdown: Arc<AtomicBool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Emulator {
+    /// Binds the command socket and starts answering commands.
+    pub async fn start(config: EmulatorConfig) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(config.command).await?;
+        let command = socket.local_addr()?;
+        let recording = match &config.recording {
+            Some(path) => Some(tokio::fs::read(path).await?),
+            None => None,
+        };
+
+        let flight = Arc::new(Mutex::new(Flight::new()));
+        let streaming = Arc::new(AtomicBool::new(false));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let commands = tokio::spawn(
+            answer_commands(socket, Arc::clone(&flight), Arc::clone(&streaming))
+                .instrument(tracing::info_span!("emulator-command")),
+        );
+        let telemetry = tokio::spawn(
+            send_telemetry(config.clone(), Arc::clone(&flight))
+                .instrument(tracing::info_span!("emulator-state")),
+        );
+        let for_video = Arc::clone(&shutdown);
+        std::thread::spawn(move || {
+            if let Err(e) = send_video(&config, recording, &streaming, &for_video) {
+                warn!("emulated video failed: {e:?}");
+            }
+        });
+
+        info!("emulating a drone at {command}");
+        Ok(Self {
+            command,
+            flight,
+            shutdown,
+            tasks: vec![commands, telemetry],
+        })
+    }
+
+    /// Starts an emulator with [`EmulatorConfig::local`] that sends to sockets it binds to
+    /// free ports of localhost
+    ///
+    /// Unlike picking free ports to bind later, nobody else can take them in the meantime.
+    pub async fn start_local() -> std::io::Result<(Self, LocalSockets)> {
+        let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+        let state = UdpSocket::bind(localhost).await?;
+        let video = UdpSocket::bind(localhost).await?;
+        let config = EmulatorConfig::local(state.local_addr()?, video.local_addr()?);
+        let emulator = Self::start(config).await?;
+        Ok((emulator, LocalSockets { state, video }))
+    }
+
+    /// The address commands are received at.
+    pub fn command_addr(&self) -> SocketAddr {
+        self.command
+    }
+
+    /// The last [`RECEIVED_HISTORY`] commands received, as they were sent.
+    pub fn received(&self) -> Vec<String> {
+        let flight = self.flight.lock().expect("poisoned");
+        flight.received.iter().cloned().collect()
+    }
+
+    /// Whether the drone is in the air.
+    pub fn is_flying(&self) -> bool {
+        self.flight.lock().expect("poisoned").flying
+    }
+
+    /// The telemetry the drone is sending.
+    pub fn state(&self) -> State {
+        self.flight.lock().expect("poisoned").state.clone()
+    }
+
+    /// Changes the telemetry the drone is sending, e.g. to drain its battery.
+    pub fn update_state(&self, update: impl FnOnce(&mut State)) {
+        update(&mut self.flight.lock().expect("poisoned").state);
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+async fn answer_commands(
+    socket: UdpSocket,
+    flight: Arc<Mutex<Flight>>,
+    streaming: Arc<AtomicBool>,
+) {
+    let mut buffer = [0u8; 2000];
+    loop {
+        let (size, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("failed to receive command: {e:?}");
+                continue;
+            }
+        };
+        let received = String::from_utf8_lossy(&buffer[..size]);
+        debug!("rcv: {received}");
+        let answer = flight
+            .lock()
+            .expect("poisoned")
+            .handle(received.trim(), &streaming);
+        if let Some(answer) = answer {
+            debug!("snd: {answer}");
+            if let Err(e) = socket.send_to(answer.as_bytes(), from).await {
+                warn!("failed to answer {from}: {e:?}");
+            }
+        }
+    }
+}
+
+async fn send_telemetry(config: EmulatorConfig, flight: Arc<Mutex<Flight>>) {
+    let socket = match UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("failed to bind telemetry socket: {e:?}");
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(config.telemetry_interval);
+    loop {
+        interval.tick().await;
+        let telemetry = {
+            let flight = flight.lock().expect("poisoned");
+            if !flight.sdk {
+                continue;
+            }
+            format!("{}\r\n", flight.state)
+        };
+        trace!("snd: {telemetry}");
+        if let Err(e) = socket.send_to(telemetry.as_bytes(), config.state).await {
+            trace!("failed to send telemetry: {e:?}");
+        }
+    }
+}
+
+/// Sends the recording, or a generated video, to the video address while streaming is on.
+fn send_video(
+    config: &EmulatorConfig,
+    recording: Option<Vec<u8>>,
+    streaming: &AtomicBool,
+    shutdown: &AtomicBool,
+) -> eyre::Result<()> {
+    let socket = std::net::UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+    let mut frames: Box<dyn FnMut() -> eyre::Result<Vec<u8>>> = match recording {
+        Some(recording) => {
+            let mut frames = recorded_frames(&recording).into_iter().cycle();
+            Box::new(move || Ok(frames.next().unwrap_or_default()))
+        }
+        None => {
+            let mut generator = Generator::new(config.width, config.height)?;
+            Box::new(move || generator.next_frame())
+        }
+    };
+    while !shutdown.load(Ordering::Relaxed) {
+        std::thread::sleep(config.frame_interval);
+        if !streaming.load(Ordering::Relaxed) {
+            continue;
+        }
+        for packet in frames()?.chunks(VIDEO_PACKET_SIZE) {
+            if let Err(e) = socket.send_to(packet, config.video) {
+                trace!("failed to send video: {e:?}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Splits an annex-B stream into pieces that each end with a picture.
+fn recorded_frames(recording: &[u8]) -> Vec<Vec<u8>> {
+    let mut nals = NalSplitter::new();
+    nals.push(recording);
+    let mut frames = Vec::new();
+    let mut frame = Vec::new();
+    let mut add = |unit: &[u8]| {
+        frame.extend_from_slice(unit);
+        // coded slices of non-IDR (1) and IDR (5) pictures
+        let start = unit.iter().position(|byte| *byte != 0).unwrap_or_default();
+        let kind = unit.get(start + 1).map(|header| header & 0x1f);
+        if matches!(kind, Some(1) | Some(5)) {
+            frames.push(std::mem::take(&mut frame));
+        }
+    };
+    while let Some(unit) = nals.next_nal() {
+        add(unit);
+    }
+    if let Some(unit) = nals.finish() {
+        add(unit);
+    }
+    if !frame.is_empty() {
+        frames.push(frame);
+    }
+    frames
+}
+
+/// Encodes a dark frame with a bright spot circling around.
+struct Generator {
+    encoder: Encoder,
+    image: RgbImage,
+    yuv: YUVBuffer,
+    frame: u32,
+}
+
+impl Generator {
+    fn new(width: u32, height: u32) -> eyre::Result<Self> {
+        Ok(Self {
+            encoder: Encoder::with_config(EncoderConfig::new(width, height))?,
+            image: RgbImage::new(width, height),
+            yuv: YUVBuffer::new(width as usize, height as usize),
+            frame: 0,
+        })
+    }
+
+    fn next_frame(&mut self) -> eyre::Result<Vec<u8>> {
+        let (width, height) = self.image.dimensions();
+        let angle = self.frame as f32 / 30.0;
+        let spot_x = (width as f32 / 2.0 * (1.0 + 0.6 * angle.cos())) as i64;
+        let spot_y = (height as f32 / 2.0 * (1.0 + 0.6 * angle.sin())) as i64;
+        for (x, y, pixel) in self.image.enumerate_pixels_mut() {
+            let near = (x as i64 - spot_x).abs() < 6 && (y as i64 - spot_y).abs() < 6;
+            *pixel = if near {
+                Rgb([255, 64, 64])
+            } else {
+                Rgb([16, 16, 24])
+            };
+        }
+        self.frame += 1;
+        self.yuv.read_rgb(self.image.as_raw());
+        Ok(self.encoder.encode(&self.yuv)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flight() {
+        let streaming = AtomicBool::new(false);
+        let mut flight = Flight::new();
+        assert_eq!(
+            Some("error"),
+            flight.handle("takeoff", &streaming).as_deref()
+        );
+        assert_eq!(Some("ok"), flight.handle("command", &streaming).as_deref());
+        assert_eq!(Some("error"), flight.handle("land", &streaming).as_deref());
+        assert_eq!(Some("ok"), flight.handle("takeoff", &streaming).as_deref());
+        assert_eq!(Some("ok"), flight.handle("up 40", &streaming).as_deref());
+        assert_eq!(120, flight.state.h);
+        assert_eq!(Some("ok"), flight.handle("cw 270", &streaming).as_deref());
+        assert_eq!(-90, flight.state.yaw);
+        assert_eq!(None, flight.handle("rc 0 0 0 0", &streaming));
+        assert_eq!(
+            Some("error"),
+            flight.handle("up 1000", &streaming).as_deref()
+        );
+        assert_eq!(
+            Some("100"),
+            flight.handle("battery?", &streaming).as_deref()
+        );
+        assert_eq!(Some("ok"), flight.handle("streamon", &streaming).as_deref());
+        assert!(streaming.load(Ordering::Relaxed));
+        assert_eq!(Some("ok"), flight.handle("land", &streaming).as_deref());
+        assert_eq!(0, flight.state.h);
+
+        // a long running emulator only remembers the latest commands
+        for _ in 0..RECEIVED_HISTORY {
+            flight.handle("battery?", &streaming);
+        }
+        assert_eq!(RECEIVED_HISTORY, flight.received.len());
+        assert_eq!(
+            Some("battery?"),
+            flight.received.front().map(String::as_str)
+        );
+    }
+
+    #[test]
+    fn test_generated_video() {
+        let mut generator = Generator::new(64, 48).unwrap();
+        let stream: Vec<u8> = (0..3)
+            .flat_map(|_| generator.next_frame().unwrap())
+            .collect();
+        assert_eq!(3, recorded_frames(&stream).len());
+
+        let mut decoder = openh264::decoder::Decoder::new().unwrap();
+        let mut decoded = 0;
+        for frame in recorded_frames(&stream) {
+            if decoder.decode(&frame).unwrap().is_some() {
+                decoded += 1;
+            }
+        }
+        assert!(decoded > 0);
+    }
+}
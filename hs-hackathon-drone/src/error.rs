@@ -0,0 +1,214 @@
+use crate::safety::LandReason;
+use std::error::Error;
+use std::fmt;
+
+/// Why talking to the drone, or the aviator in front of it, failed.
+#[derive(Debug)]
+pub enum DroneError {
+    /// The aviator could not be reached.
+    Request(reqwest::Error),
+    /// The address of the aviator is not a valid URL.
+    InvalidUrl { url: String, reason: String },
+    /// The aviator answered with an error status.
+    Status {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    /// The frame could not be decoded.
+    Decode(image::ImageError),
+    /// The aviator sent something that is not a frame.
+    Malformed(String),
+    /// The sockets to talk to the drone could not be set up.
+    Io(std::io::Error),
+    /// The drone answered a command with something other than `ok`.
+    Rejected { command: String, response: String },
+    /// The drone did not answer in time.
+    Timeout { command: String },
+    /// The connection to the drone was closed.
+    Disconnected,
+    /// The drone would not accept the command.
+    InvalidCommand(CommandError),
+    /// The command would take the drone outside of its safety envelope.
+    Unsafe(SafetyError),
+}
+
+impl fmt::Display for DroneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DroneError::Request(_) => write!(f, "failed to reach the aviator"),
+            DroneError::InvalidUrl { url, reason } => {
+                write!(f, "`{url}` is not a valid URL: {reason}")
+            }
+            DroneError::Status { status, body } => {
+                write!(f, "the aviator answered {status}: {body}")
+            }
+            DroneError::Decode(_) => write!(f, "failed to decode the frame"),
+            DroneError::Malformed(reason) => {
+                write!(f, "the aviator sent a malformed frame: {reason}")
+            }
+            DroneError::Io(_) => write!(f, "failed to set up the connection to the drone"),
+            DroneError::Rejected { command, response } => {
+                write!(f, "the drone answered `{command}` with: {response}")
+            }
+            DroneError::Timeout { command } => {
+                write!(f, "the drone did not answer `{command}` in time")
+            }
+            DroneError::Disconnected => write!(f, "the connection to the drone was closed"),
+            DroneError::InvalidCommand(_) => write!(f, "the drone would not accept the command"),
+            DroneError::Unsafe(_) => write!(f, "the command is unsafe"),
+        }
+    }
+}
+
+impl Error for DroneError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DroneError::Request(e) => Some(e),
+            DroneError::Decode(e) => Some(e),
+            DroneError::Io(e) => Some(e),
+            DroneError::InvalidCommand(e) => Some(e),
+            DroneError::Unsafe(e) => Some(e),
+            DroneError::Status { .. }
+            | DroneError::InvalidUrl { .. }
+            | DroneError::Malformed(_)
+            | DroneError::Rejected { .. }
+            | DroneError::Timeout { .. }
+            | DroneError::Disconnected => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for DroneError {
+    fn from(e: reqwest::Error) -> Self {
+        DroneError::Request(e)
+    }
+}
+
+impl From<image::ImageError> for DroneError {
+    fn from(e: image::ImageError) -> Self {
+        DroneError::Decode(e)
+    }
+}
+
+impl From<std::io::Error> for DroneError {
+    fn from(e: std::io::Error) -> Self {
+        DroneError::Io(e)
+    }
+}
+
+impl From<CommandError> for DroneError {
+    fn from(e: CommandError) -> Self {
+        DroneError::InvalidCommand(e)
+    }
+}
+
+/// Why a [`Command`](crate::Command) is invalid or could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// There is no such command.
+    Unknown(String),
+    /// The command has the wrong number of arguments, or they are not numbers.
+    Malformed(String),
+    /// A value, e.g. a distance or a speed, is out of its bounds.
+    OutOfRange {
+        name: &'static str,
+        value: i64,
+        min: i64,
+        max: i64,
+    },
+    /// All coordinates of a point are between -20 and 20.
+    TooClose([i32; 3]),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown(command) => write!(f, "unknown command `{command}`"),
+            CommandError::Malformed(command) => write!(f, "malformed command `{command}`"),
+            CommandError::OutOfRange {
+                name,
+                value,
+                min,
+                max,
+            } => write!(f, "{name} must be between {min} and {max}, not {value}"),
+            CommandError::TooClose([x, y, z]) => write!(
+                f,
+                "({x}, {y}, {z}) is too close, one coordinate must be beyond 20cm"
+            ),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+/// Why the telemetry of the drone could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TelemetryError {
+    /// A part of the telemetry is not a `key:value` pair.
+    Malformed(String),
+    /// A field every drone sends is missing.
+    Missing(String),
+    /// The value of a field could not be parsed.
+    Invalid { field: String, value: String },
+}
+
+impl fmt::Display for TelemetryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TelemetryError::Malformed(pair) => write!(f, "`{pair}` is not a key:value pair"),
+            TelemetryError::Missing(field) => write!(f, "the telemetry has no `{field}`"),
+            TelemetryError::Invalid { field, value } => {
+                write!(f, "`{value}` is not a valid `{field}`")
+            }
+        }
+    }
+}
+
+impl Error for TelemetryError {}
+
+impl From<SafetyError> for DroneError {
+    fn from(e: SafetyError) -> Self {
+        DroneError::Unsafe(e)
+    }
+}
+
+/// Why a command would take the drone outside of its
+/// [`SafetyEnvelope`](crate::safety::SafetyEnvelope).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SafetyError {
+    /// The drone would end up at the given position in cm, outside of the box.
+    OutsideEnvelope { x: i32, y: i32 },
+    /// The drone would end up at the given height in cm, above the ceiling.
+    AboveCeiling { z: i32, ceiling: i32 },
+    /// The battery is too low to take off.
+    LowBattery(i32),
+    /// The drone may not take off, as it would have to land again right away.
+    Grounded(LandReason),
+    /// Where the command takes the drone cannot be told in advance, e.g. `rc`.
+    Unpredictable(String),
+}
+
+impl fmt::Display for SafetyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SafetyError::OutsideEnvelope { x, y } => {
+                write!(f, "the drone would leave the enclosure at ({x}, {y})cm")
+            }
+            SafetyError::AboveCeiling { z, ceiling } => {
+                write!(
+                    f,
+                    "the drone would fly at {z}cm, above the ceiling at {ceiling}cm"
+                )
+            }
+            SafetyError::LowBattery(battery) => {
+                write!(f, "the battery is too low to take off at {battery}%")
+            }
+            SafetyError::Grounded(reason) => write!(f, "the drone may not take off, {reason}"),
+            SafetyError::Unpredictable(command) => {
+                write!(f, "`{command}` cannot be checked against the enclosure")
+            }
+        }
+    }
+}
+
+impl Error for SafetyError {}
@@ -0,0 +1,185 @@
+//! Splitting the drone's H.264 byte stream into NAL units.
+//!
+//! The drone sends its video as an annex-B byte stream, where every NAL unit starts with a
+//! start code of two or three zero bytes and a one. The stream is cut into UDP packets with no
+//! regard for where NAL units start, so a start code may well span two packets.
+
+/// Splits an annex-B byte stream into NAL units as it arrives
+///
+/// Remembers how far it has scanned for start codes, so every byte is looked at once no matter
+/// how the stream is cut up. The NAL units are borrowed from the splitter, and start with their
+/// start code of three or four bytes.
+///
+/// ```
+/// use hs_hackathon_drone::h264::NalSplitter;
+///
+/// let mut splitter = NalSplitter::new();
+/// splitter.push(&[0, 0, 0, 1, 0x67, 0x42, 0, 0]);
+/// // the first NAL unit is complete once the next one starts
+/// assert_eq!(None, splitter.next_nal());
+/// splitter.push(&[1, 0x68]);
+/// assert_eq!(Some(&[0, 0, 0, 1, 0x67, 0x42][..]), splitter.next_nal());
+/// assert_eq!(None, splitter.next_nal());
+/// assert_eq!(Some(&[0, 0, 1, 0x68][..]), splitter.finish());
+/// ```
+#[derive(Debug, Default)]
+pub struct NalSplitter {
+    buffer: Vec<u8>,
+    /// Where the start code of the NAL unit being completed begins, once there is one
+    start: Option<usize>,
+    /// Up to where the buffer has been scanned for start codes
+    scanned: usize,
+    /// How many zero bytes the scanned part of the buffer ends with
+    zeros: usize,
+}
+
+impl NalSplitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends the next piece of the byte stream
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.compact();
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next NAL unit completed by what was pushed so far
+    ///
+    /// Anything before the first start code is skipped, as it cannot be decoded without the
+    /// start of its NAL unit.
+    pub fn next_nal(&mut self) -> Option<&[u8]> {
+        while self.scanned < self.buffer.len() {
+            let at = self.scanned;
+            self.scanned += 1;
+            match self.buffer[at] {
+                0 => self.zeros += 1,
+                1 if self.zeros >= 2 => {
+                    // zeros ahead of the start code are trailing_zero_8bits or part of a
+                    // four byte start code, so they belong to the next NAL unit either way
+                    let code = at - self.zeros;
+                    self.zeros = 0;
+                    if let Some(start) = self.start.replace(code) {
+                        return Some(&self.buffer[start..code]);
+                    }
+                }
+                _ => self.zeros = 0,
+            }
+        }
+        None
+    }
+
+    /// The last NAL unit, once the byte stream ended
+    ///
+    /// Nothing tells where the last NAL unit ends but the end of the stream.
+    pub fn finish(&mut self) -> Option<&[u8]> {
+        while self.next_nal().is_some() {}
+        let start = self.start.take()?;
+        self.zeros = 0;
+        Some(&self.buffer[start..])
+    }
+
+    /// Drops what was handed out or skipped, keeping the NAL unit being completed
+    ///
+    /// Moves no more than the start of that NAL unit, which is dropped only once.
+    fn compact(&mut self) {
+        // without a start code, only the zeros at the end might still start one
+        let drop = self.start.unwrap_or(self.scanned - self.zeros);
+        if drop == 0 {
+            return;
+        }
+        self.buffer.drain(..drop);
+        self.start = self.start.map(|start| start - drop);
+        self.scanned -= drop;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// NAL units that cannot contain a start code, like the ones of an encoder, each with a
+    /// start code of three or four bytes
+    fn nal_units() -> impl Strategy<Value = Vec<Vec<u8>>> {
+        let nal = (
+            any::<bool>(),
+            1..=255u8,
+            prop::collection::vec(prop_oneof![3 => 1..=255u8, 1 => Just(0u8)], 0..300),
+        )
+            .prop_map(|(long, header, payload)| {
+                let mut nal = if long {
+                    vec![0, 0, 0, 1]
+                } else {
+                    vec![0, 0, 1]
+                };
+                nal.push(header);
+                for byte in payload {
+                    // emulation prevention keeps zeros apart, and the payload ends in a one bit
+                    if byte != 0 || nal.last() != Some(&0) {
+                        nal.push(byte);
+                    }
+                }
+                if nal.last() == Some(&0) {
+                    nal.push(0x80);
+                }
+                nal
+            });
+        prop::collection::vec(nal, 1..20)
+    }
+
+    /// Splits `stream` after each of `cuts`, as UDP packets might
+    fn split(stream: &[u8], cuts: &[usize]) -> Vec<Vec<u8>> {
+        let mut cuts: Vec<_> = cuts.iter().map(|cut| cut % (stream.len() + 1)).collect();
+        cuts.push(stream.len());
+        cuts.sort_unstable();
+        let mut splitter = NalSplitter::new();
+        let mut nals = Vec::new();
+        let mut from = 0;
+        for to in cuts {
+            splitter.push(&stream[from..to]);
+            from = to;
+            while let Some(nal) = splitter.next_nal() {
+                nals.push(nal.to_vec());
+            }
+        }
+        nals.extend(splitter.finish().map(<[u8]>::to_vec));
+        nals
+    }
+
+    proptest! {
+        #[test]
+        fn test_fragmented(nals in nal_units(), cuts in prop::collection::vec(any::<usize>(), 0..50)) {
+            let stream = nals.concat();
+            prop_assert_eq!(&nals, &split(&stream, &[]));
+            prop_assert_eq!(&nals, &split(&stream, &cuts));
+        }
+
+        #[test]
+        fn test_byte_by_byte(nals in nal_units()) {
+            let stream = nals.concat();
+            let cuts: Vec<_> = (0..stream.len()).collect();
+            prop_assert_eq!(&nals, &split(&stream, &cuts));
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        let stream = [
+            0x42, 0x80, // the end of a NAL unit that started before
+            0, 0, 0, 1, 0x67, 0x42, 0, 0, 3, 1, // the 3 prevents emulating a start code
+            0, 0, 1, 0x68, 0xce, //
+            0, 0, 0, 0, 1, 0x65, 0x88, // with a trailing zero
+        ];
+        let nals = split(&stream, &[3, 4, 11, 18]);
+        assert_eq!(
+            vec![
+                vec![0, 0, 0, 1, 0x67, 0x42, 0, 0, 3, 1],
+                vec![0, 0, 1, 0x68, 0xce],
+                vec![0, 0, 0, 0, 1, 0x65, 0x88],
+            ],
+            nals
+        );
+        assert_eq!(None, NalSplitter::new().finish());
+    }
+}
@@ -0,0 +1,950 @@
+use crate::CommandError;
+use clap::{Subcommand, ValueEnum};
+use std::{net::SocketAddr, ops::RangeInclusive, str::FromStr, time::Duration};
+use strum::Display;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+#[allow(unused_imports)]
+use tracing::{debug, info, trace, warn};
+
+/// Information on what each command does can be found in the
+/// [Tello docs](https://dl-cdn.ryzerobotics.com/downloads/Tello/Tello%20SDK%202.0%20User%20Guide.pdf).
+///
+/// Distances are in cm, speeds in cm/s and angles in degrees. Use [`Command::validate`] to
+/// check that the drone accepts the values, or parse a command from its wire format.
+#[derive(Subcommand, Display, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    #[strum(to_string = "command")]
+    SDKInit,
+    #[strum(to_string = "takeoff")]
+    Takeoff,
+    #[strum(to_string = "land")]
+    Land,
+    #[strum(to_string = "streamon")]
+    EnableStream,
+    #[strum(to_string = "streamoff")]
+    DisableStream,
+    /// Stops all motors immediately, dropping the drone out of the air.
+    #[strum(to_string = "emergency")]
+    Emergency,
+    #[strum(to_string = "up {cm}")]
+    Up {
+        #[clap(long)]
+        cm: u32,
+    },
+    #[strum(to_string = "down {cm}")]
+    Down {
+        #[clap(long)]
+        cm: u32,
+    },
+    #[strum(to_string = "left {cm}")]
+    Left {
+        #[clap(long)]
+        cm: u32,
+    },
+    #[strum(to_string = "right {cm}")]
+    Right {
+        #[clap(long)]
+        cm: u32,
+    },
+    #[strum(to_string = "forward {cm}")]
+    Forward {
+        #[clap(long)]
+        cm: u32,
+    },
+    #[strum(to_string = "back {cm}")]
+    Back {
+        #[clap(long)]
+        cm: u32,
+    },
+    #[strum(to_string = "cw {degrees}")]
+    Clockwise {
+        #[clap(long)]
+        degrees: u32,
+    },
+    #[strum(to_string = "ccw {degrees}")]
+    CounterClockwise {
+        #[clap(long)]
+        degrees: u32,
+    },
+    #[strum(to_string = "flip {direction}")]
+    Flip {
+        #[clap(long)]
+        direction: FlipDirection,
+    },
+    /// Fly `x` cm forward, `y` cm left and `z` cm up at `speed` cm/s.
+    #[strum(to_string = "go {x} {y} {z} {speed}")]
+    Go {
+        #[clap(long, allow_hyphen_values = true)]
+        x: i32,
+        #[clap(long, allow_hyphen_values = true)]
+        y: i32,
+        #[clap(long, allow_hyphen_values = true)]
+        z: i32,
+        #[clap(long)]
+        speed: u32,
+    },
+    /// Fly an arc through the first point to the second one at `speed` cm/s.
+    ///
+    /// The drone refuses arcs with a radius outside of 0.5m to 10m.
+    #[strum(to_string = "curve {x1} {y1} {z1} {x2} {y2} {z2} {speed}")]
+    Curve {
+        #[clap(long, allow_hyphen_values = true)]
+        x1: i32,
+        #[clap(long, allow_hyphen_values = true)]
+        y1: i32,
+        #[clap(long, allow_hyphen_values = true)]
+        z1: i32,
+        #[clap(long, allow_hyphen_values = true)]
+        x2: i32,
+        #[clap(long, allow_hyphen_values = true)]
+        y2: i32,
+        #[clap(long, allow_hyphen_values = true)]
+        z2: i32,
+        #[clap(long)]
+        speed: u32,
+    },
+    /// Hover in place.
+    #[strum(to_string = "stop")]
+    Stop,
+    #[strum(to_string = "speed {speed}")]
+    Speed {
+        #[clap(long)]
+        speed: u32,
+    },
+    /// Set the remote control channels, each between -100 and 100.
+    ///
+    /// The drone does not answer this one.
+    #[strum(to_string = "rc {left_right} {forward_backward} {up_down} {yaw}")]
+    Rc {
+        #[clap(long, allow_hyphen_values = true)]
+        left_right: i32,
+        #[clap(long, allow_hyphen_values = true)]
+        forward_backward: i32,
+        #[clap(long, allow_hyphen_values = true)]
+        up_down: i32,
+        #[clap(long, allow_hyphen_values = true)]
+        yaw: i32,
+    },
+    #[strum(to_string = "wifi {ssid} {pass}")]
+    SetSsidPass {
+        #[clap(long)]
+        ssid: String,
+        #[clap(long)]
+        pass: String,
+    },
+    /// Join the given access point rather than opening one.
+    #[strum(to_string = "ap {ssid} {pass}")]
+    JoinAccessPoint {
+        #[clap(long)]
+        ssid: String,
+        #[clap(long)]
+        pass: String,
+    },
+    #[strum(to_string = "mon")]
+    EnableMissionPads,
+    #[strum(to_string = "moff")]
+    DisableMissionPads,
+    /// Detect mission pads below (0), in front of (1) or below and in front of (2) the drone.
+    #[strum(to_string = "mdirection {direction}")]
+    MissionPadDirection {
+        #[clap(long)]
+        direction: u8,
+    },
+    #[strum(to_string = "speed?")]
+    QuerySpeed,
+    #[strum(to_string = "battery?")]
+    QueryBattery,
+    #[strum(to_string = "time?")]
+    QueryFlightTime,
+    #[strum(to_string = "wifi?")]
+    QueryWifi,
+    #[strum(to_string = "sdk?")]
+    QuerySdk,
+    #[strum(to_string = "sn?")]
+    QuerySerialNumber,
+}
+
+/// The direction of a [`Command::Flip`].
+#[derive(ValueEnum, Display, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlipDirection {
+    #[strum(to_string = "l")]
+    Left,
+    #[strum(to_string = "r")]
+    Right,
+    #[strum(to_string = "f")]
+    Forward,
+    #[strum(to_string = "b")]
+    Back,
+}
+
+const DISTANCE: RangeInclusive<i64> = 20..=500;
+const COORDINATE: RangeInclusive<i64> = -500..=500;
+const ANGLE: RangeInclusive<i64> = 1..=360;
+const SPEED: RangeInclusive<i64> = 10..=100;
+const CURVE_SPEED: RangeInclusive<i64> = 10..=60;
+const CHANNEL: RangeInclusive<i64> = -100..=100;
+
+impl Command {
+    /// Whether the drone takes a while to acknowledge the command.
+    pub fn is_slow(&self) -> bool {
+        matches!(
+            self,
+            Command::SDKInit
+                | Command::Takeoff
+                | Command::Land
+                | Command::Up { .. }
+                | Command::Down { .. }
+                | Command::Left { .. }
+                | Command::Right { .. }
+                | Command::Forward { .. }
+                | Command::Back { .. }
+                | Command::Clockwise { .. }
+                | Command::CounterClockwise { .. }
+                | Command::Flip { .. }
+                | Command::Go { .. }
+                | Command::Curve { .. }
+        )
+    }
+
+    /// Whether the command asks for a value, e.g. `battery?`.
+    pub fn is_query(&self) -> bool {
+        matches!(
+            self,
+            Command::QuerySpeed
+                | Command::QueryBattery
+                | Command::QueryFlightTime
+                | Command::QueryWifi
+                | Command::QuerySdk
+                | Command::QuerySerialNumber
+        )
+    }
+
+    /// Whether sending the command twice does the same as sending it once.
+    pub fn is_repeatable(&self) -> bool {
+        self.is_query()
+            || matches!(
+                self,
+                Command::SDKInit
+                    | Command::EnableStream
+                    | Command::DisableStream
+                    | Command::Stop
+                    | Command::Speed { .. }
+                    | Command::EnableMissionPads
+                    | Command::DisableMissionPads
+                    | Command::MissionPadDirection { .. }
+            )
+    }
+
+    /// Whether the drone answers the command at all.
+    pub fn expects_response(&self) -> bool {
+        !matches!(self, Command::Rc { .. })
+    }
+
+    /// Checks that the drone accepts the values of the command.
+    pub fn validate(&self) -> Result<(), CommandError> {
+        match *self {
+            Command::Up { cm }
+            | Command::Down { cm }
+            | Command::Left { cm }
+            | Command::Right { cm }
+            | Command::Forward { cm }
+            | Command::Back { cm } => check("distance", cm, DISTANCE),
+            Command::Clockwise { degrees } | Command::CounterClockwise { degrees } => {
+                check("angle", degrees, ANGLE)
+            }
+            Command::Go { x, y, z, speed } => {
+                check_point([x, y, z])?;
+                check("speed", speed, SPEED)
+            }
+            Command::Curve {
+                x1,
+                y1,
+                z1,
+                x2,
+                y2,
+                z2,
+                speed,
+            } => {
+                check_point([x1, y1, z1])?;
+                check_point([x2, y2, z2])?;
+                check("speed", speed, CURVE_SPEED)
+            }
+            Command::Speed { speed } => check("speed", speed, SPEED),
+            Command::Rc {
+                left_right,
+                forward_backward,
+                up_down,
+                yaw,
+            } => {
+                check("left/right", left_right, CHANNEL)?;
+                check("forward/backward", forward_backward, CHANNEL)?;
+                check("up/down", up_down, CHANNEL)?;
+                check("yaw", yaw, CHANNEL)
+            }
+            Command::MissionPadDirection { direction } => check("direction", direction, 0..=2),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn check(
+    name: &'static str,
+    value: impl Into<i64>,
+    range: RangeInclusive<i64>,
+) -> Result<(), CommandError> {
+    let value = value.into();
+    if range.contains(&value) {
+        Ok(())
+    } else {
+        Err(CommandError::OutOfRange {
+            name,
+            value,
+            min: *range.start(),
+            max: *range.end(),
+        })
+    }
+}
+
+/// The drone only flies to points at least 20cm away along one of the axes.
+fn check_point(point: [i32; 3]) -> Result<(), CommandError> {
+    for (name, value) in ["x", "y", "z"].into_iter().zip(point) {
+        check(name, value, COORDINATE)?;
+    }
+    if point.iter().all(|value| value.abs() <= 20) {
+        return Err(CommandError::TooClose(point));
+    }
+    Ok(())
+}
+
+impl FromStr for Command {
+    type Err = CommandError;
+
+    /// Parses the wire format of a command, e.g. `go 50 0 -20 30`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+        let malformed = || CommandError::Malformed(s.to_string());
+        let arity = |n: usize| {
+            if args.len() == n {
+                Ok(())
+            } else {
+                Err(malformed())
+            }
+        };
+        fn num<T: FromStr>(
+            arg: &str,
+            malformed: impl Fn() -> CommandError,
+        ) -> Result<T, CommandError> {
+            arg.parse().map_err(|_| malformed())
+        }
+        let arg = |i: usize| -> Result<i32, CommandError> { num(args[i], malformed) };
+        let uarg = |i: usize| -> Result<u32, CommandError> { num(args[i], malformed) };
+
+        let command = match name {
+            "command" | "takeoff" | "land" | "streamon" | "streamoff" | "emergency" | "stop"
+            | "mon" | "moff" | "speed?" | "battery?" | "time?" | "wifi?" | "sdk?" | "sn?" => {
+                arity(0)?;
+                match name {
+                    "command" => Command::SDKInit,
+                    "takeoff" => Command::Takeoff,
+                    "land" => Command::Land,
+                    "streamon" => Command::EnableStream,
+                    "streamoff" => Command::DisableStream,
+                    "emergency" => Command::Emergency,
+                    "stop" => Command::Stop,
+                    "mon" => Command::EnableMissionPads,
+                    "moff" => Command::DisableMissionPads,
+                    "speed?" => Command::QuerySpeed,
+                    "battery?" => Command::QueryBattery,
+                    "time?" => Command::QueryFlightTime,
+                    "wifi?" => Command::QueryWifi,
+                    "sdk?" => Command::QuerySdk,
+                    _ => Command::QuerySerialNumber,
+                }
+            }
+            "up" | "down" | "left" | "right" | "forward" | "back" | "cw" | "ccw" | "speed" => {
+                arity(1)?;
+                let value = uarg(0)?;
+                match name {
+                    "up" => Command::Up { cm: value },
+                    "down" => Command::Down { cm: value },
+                    "left" => Command::Left { cm: value },
+                    "right" => Command::Right { cm: value },
+                    "forward" => Command::Forward { cm: value },
+                    "back" => Command::Back { cm: value },
+                    "cw" => Command::Clockwise { degrees: value },
+                    "ccw" => Command::CounterClockwise { degrees: value },
+                    _ => Command::Speed { speed: value },
+                }
+            }
+            "flip" => {
+                arity(1)?;
+                let direction = match args[0] {
+                    "l" => FlipDirection::Left,
+                    "r" => FlipDirection::Right,
+                    "f" => FlipDirection::Forward,
+                    "b" => FlipDirection::Back,
+                    _ => return Err(malformed()),
+                };
+                Command::Flip { direction }
+            }
+            "go" => {
+                arity(4)?;
+                Command::Go {
+                    x: arg(0)?,
+                    y: arg(1)?,
+                    z: arg(2)?,
+                    speed: uarg(3)?,
+                }
+            }
+            "curve" => {
+                arity(7)?;
+                Command::Curve {
+                    x1: arg(0)?,
+                    y1: arg(1)?,
+                    z1: arg(2)?,
+                    x2: arg(3)?,
+                    y2: arg(4)?,
+                    z2: arg(5)?,
+                    speed: uarg(6)?,
+                }
+            }
+            "rc" => {
+                arity(4)?;
+                Command::Rc {
+                    left_right: arg(0)?,
+                    forward_backward: arg(1)?,
+                    up_down: arg(2)?,
+                    yaw: arg(3)?,
+                }
+            }
+            "wifi" | "ap" => {
+                arity(2)?;
+                let (ssid, pass) = (args[0].to_string(), args[1].to_string());
+                if name == "wifi" {
+                    Command::SetSsidPass { ssid, pass }
+                } else {
+                    Command::JoinAccessPoint { ssid, pass }
+                }
+            }
+            "mdirection" => {
+                arity(1)?;
+                Command::MissionPadDirection {
+                    direction: num(args[0], malformed)?,
+                }
+            }
+            _ => return Err(CommandError::Unknown(s.to_string())),
+        };
+        command.validate()?;
+        Ok(command)
+    }
+}
+
+/// How long to wait for the answer to a command, and how often to try again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandPolicy {
+    /// How long to wait for each answer.
+    pub timeout: Duration,
+    /// How often to send the command again if the drone does not answer in time.
+    pub retries: u32,
+}
+
+impl CommandPolicy {
+    /// What suits the command: slow commands get more time, and only those safe to repeat
+    /// are retried. Retrying a move the drone did but failed to acknowledge would move it
+    /// twice.
+    pub fn for_command(command: &Command) -> Self {
+        let timeout = Duration::from_secs(if command.is_slow() { 30 } else { 7 });
+        let retries = if command.is_repeatable() { 2 } else { 0 };
+        Self { timeout, retries }
+    }
+}
+
+/// The answer of the drone to a command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    /// The drone did what it was told.
+    Ok,
+    /// The drone refused, with its reason.
+    Error(String),
+    /// The drone did not answer in time, even after retrying.
+    Timeout,
+    /// The answer to a query, e.g. `battery?`.
+    Value(QueryValue),
+}
+
+/// The answer to a query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryValue {
+    /// E.g. the battery percentage, or the flight time in seconds.
+    Number(f64),
+    /// E.g. the serial number.
+    Text(String),
+}
+
+impl Response {
+    /// Interprets what the drone sent in answer to `command`.
+    ///
+    /// Returns `None` if it cannot be the answer to `command`, e.g. a number in answer to
+    /// `takeoff`, as it must be a late answer to an earlier command.
+    fn parse(command: &Command, answer: &str) -> Option<Self> {
+        let answer = answer.trim();
+        let is_error = answer.starts_with("error")
+            || answer.starts_with("unknown command")
+            || answer.starts_with("out of range");
+        if is_error {
+            return Some(Response::Error(answer.to_string()));
+        }
+        if !command.is_query() {
+            return match answer {
+                "ok" => Some(Response::Ok),
+                _ if answer.parse::<f64>().is_ok() => None,
+                _ => Some(Response::Error(answer.to_string())),
+            };
+        }
+        if answer == "ok" {
+            return None;
+        }
+        // the flight time comes with a unit, e.g. `12s`
+        let number = answer.strip_suffix('s').unwrap_or(answer).parse::<f64>();
+        Some(Response::Value(match number {
+            Ok(number) => QueryValue::Number(number),
+            Err(_) => QueryValue::Text(answer.to_string()),
+        }))
+    }
+}
+
+impl std::fmt::Display for Response {
+    /// Writes what the drone answered, or `timeout` if it did not.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Response::Ok => write!(f, "ok"),
+            Response::Error(reason) => write!(f, "{reason}"),
+            Response::Timeout => write!(f, "timeout"),
+            Response::Value(QueryValue::Number(number)) => write!(f, "{number}"),
+            Response::Value(QueryValue::Text(text)) => write!(f, "{text}"),
+        }
+    }
+}
+
+/// A command waiting to be sent by [`send_commands`].
+#[derive(Debug)]
+pub struct Request {
+    pub command: Command,
+    pub policy: CommandPolicy,
+    pub reply: oneshot::Sender<Response>,
+}
+
+/// Sent after a command timed out, to tell its late answer apart from the answer to the next
+/// command. Its answer is a number, so every `ok` or `error` before it must be a late one.
+const RESYNC: Command = Command::QueryBattery;
+
+/// Sends the commands from [src] to the drone at `remote_addr` over `socket`, one at a time
+/// and in order. Blocks until [src] is closed.
+///
+/// The answer to each command is sent back through its `reply` channel. Commands the drone
+/// does not answer are acknowledged with [`Response::Ok`] right away. Once a command timed
+/// out, the next one is only sent after a round-trip of [`RESYNC`], and times out if that
+/// does.
+pub async fn send_commands(
+    socket: UdpSocket,
+    remote_addr: SocketAddr,
+    mut src: mpsc::Receiver<Request>,
+) -> std::io::Result<()> {
+    debug!("connecting to: {}", &remote_addr);
+
+    let mut buf = [0u8; 2000];
+    // whether every answer the drone still owes is to the command being sent
+    let mut synced = true;
+    while let Some(Request {
+        command,
+        policy,
+        reply,
+    }) = src.recv().await
+    {
+        let mut response = Response::Timeout;
+        for attempt in 0..=policy.retries {
+            if !synced {
+                let deadline = Instant::now() + policy.timeout;
+                synced = resync(&socket, remote_addr, &mut buf, deadline).await?;
+                if !synced {
+                    warn!("drone does not answer {RESYNC}, not sending {command}");
+                    continue;
+                }
+            }
+            discard_pending(&socket, &mut buf)?;
+
+            debug!("snd: {command} (attempt {})", attempt + 1);
+            socket
+                .send_to(command.to_string().as_bytes(), remote_addr)
+                .await?;
+            if !command.expects_response() {
+                response = Response::Ok;
+                break;
+            }
+
+            let deadline = Instant::now() + policy.timeout;
+            if let Some(answer) = await_answer(&socket, &mut buf, &command, deadline).await? {
+                response = answer;
+                break;
+            }
+            warn!("not getting ack from drone for {command}; is it on?");
+            synced = false;
+        }
+        let _ = reply.send(response);
+    }
+    info!("no more commands -- exiting");
+    Ok(())
+}
+
+/// Sends [`RESYNC`] and discards the late answers arriving before its own, until `deadline`.
+///
+/// Returns whether its answer arrived.
+async fn resync(
+    socket: &UdpSocket,
+    remote_addr: SocketAddr,
+    buf: &mut [u8],
+    deadline: Instant,
+) -> std::io::Result<bool> {
+    debug!("snd: {RESYNC} (resynchronising)");
+    socket
+        .send_to(RESYNC.to_string().as_bytes(), remote_addr)
+        .await?;
+    loop {
+        match await_answer(socket, buf, &RESYNC, deadline).await? {
+            Some(Response::Value(_)) => return Ok(true),
+            Some(late) => info!("discarding late ack from drone: {late}"),
+            None => return Ok(false),
+        }
+    }
+}
+
+/// Waits for the answer to `command` until `deadline`, skipping what cannot be its answer.
+async fn await_answer(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    command: &Command,
+    deadline: Instant,
+) -> std::io::Result<Option<Response>> {
+    loop {
+        let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(buf)).await else {
+            return Ok(None);
+        };
+        let size = received?;
+        trace!("got ack");
+        let Ok(msg) = std::str::from_utf8(&buf[..size]) else {
+            warn!("got invalid utf-8 from drone: {:?}", &buf[..size]);
+            continue;
+        };
+        debug!("rcv: {}", msg);
+        match Response::parse(command, msg) {
+            Some(response) => return Ok(Some(response)),
+            None => info!("unexpected ack from drone for {command}: {msg}"),
+        }
+    }
+}
+
+/// Discards the answers that already arrived.
+fn discard_pending(socket: &UdpSocket, buf: &mut [u8]) -> std::io::Result<()> {
+    loop {
+        match socket.try_recv(buf) {
+            Ok(size) => info!(
+                "discarding unexpected ack from drone: {}",
+                String::from_utf8_lossy(&buf[..size])
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wire_serde() {
+        assert_eq!("command", Command::SDKInit.to_string());
+        assert_eq!("takeoff", Command::Takeoff.to_string());
+        assert_eq!("land", Command::Land.to_string());
+        assert_eq!("up 20", Command::Up { cm: 20 }.to_string());
+        let go = Command::Go {
+            x: 50,
+            y: -20,
+            z: 0,
+            speed: 30,
+        };
+        assert_eq!("go 50 -20 0 30", go.to_string());
+        assert_eq!(
+            "ccw 90",
+            Command::CounterClockwise { degrees: 90 }.to_string()
+        );
+        let flip = Command::Flip {
+            direction: FlipDirection::Back,
+        };
+        assert_eq!("flip b", flip.to_string());
+        let rc = Command::Rc {
+            left_right: -100,
+            forward_backward: 0,
+            up_down: 50,
+            yaw: 100,
+        };
+        assert_eq!("rc -100 0 50 100", rc.to_string());
+        assert_eq!("mon", Command::EnableMissionPads.to_string());
+        assert_eq!("sn?", Command::QuerySerialNumber.to_string());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let commands = [
+            Command::SDKInit,
+            Command::Takeoff,
+            Command::Land,
+            Command::EnableStream,
+            Command::DisableStream,
+            Command::Emergency,
+            Command::Up { cm: 20 },
+            Command::Down { cm: 500 },
+            Command::Left { cm: 100 },
+            Command::Right { cm: 30 },
+            Command::Forward { cm: 40 },
+            Command::Back { cm: 50 },
+            Command::Clockwise { degrees: 1 },
+            Command::CounterClockwise { degrees: 360 },
+            Command::Flip {
+                direction: FlipDirection::Left,
+            },
+            Command::Go {
+                x: -500,
+                y: 0,
+                z: 21,
+                speed: 100,
+            },
+            Command::Curve {
+                x1: 30,
+                y1: 20,
+                z1: 0,
+                x2: 60,
+                y2: 40,
+                z2: 0,
+                speed: 60,
+            },
+            Command::Stop,
+            Command::Speed { speed: 10 },
+            Command::Rc {
+                left_right: 0,
+                forward_backward: -100,
+                up_down: 100,
+                yaw: -1,
+            },
+            Command::SetSsidPass {
+                ssid: String::from("tello"),
+                pass: String::from("hunter2"),
+            },
+            Command::JoinAccessPoint {
+                ssid: String::from("hs-rust-nation"),
+                pass: String::from("hunter2"),
+            },
+            Command::EnableMissionPads,
+            Command::DisableMissionPads,
+            Command::MissionPadDirection { direction: 2 },
+            Command::QuerySpeed,
+            Command::QueryBattery,
+            Command::QueryFlightTime,
+            Command::QueryWifi,
+            Command::QuerySdk,
+            Command::QuerySerialNumber,
+        ];
+        for command in commands {
+            let wire = command.to_string();
+            assert_eq!(Ok(command), wire.parse(), "{wire}");
+        }
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let out_of_range = |wire: &str| {
+            matches!(
+                wire.parse::<Command>(),
+                Err(CommandError::OutOfRange { .. })
+            )
+        };
+        assert!(out_of_range("up 19"));
+        assert!(out_of_range("back 501"));
+        assert!(out_of_range("cw 0"));
+        assert!(out_of_range("ccw 361"));
+        assert!(out_of_range("go 501 0 0 50"));
+        assert!(out_of_range("go 50 0 0 9"));
+        assert!(out_of_range("curve 30 20 0 60 40 0 61"));
+        assert!(out_of_range("speed 101"));
+        assert!(out_of_range("rc 0 0 0 101"));
+        assert!(out_of_range("mdirection 3"));
+        assert_eq!(
+            Err(CommandError::TooClose([20, -20, 0])),
+            "go 20 -20 0 50".parse::<Command>()
+        );
+        assert!(matches!(
+            "up twenty".parse::<Command>(),
+            Err(CommandError::Malformed(_))
+        ));
+        assert!(matches!(
+            "go 50 0 0".parse::<Command>(),
+            Err(CommandError::Malformed(_))
+        ));
+        assert!(matches!(
+            "flip x".parse::<Command>(),
+            Err(CommandError::Malformed(_))
+        ));
+        assert!(matches!(
+            "hover".parse::<Command>(),
+            Err(CommandError::Unknown(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let battery = Command::QueryBattery;
+        let time = Command::QueryFlightTime;
+        let serial = Command::QuerySerialNumber;
+        assert_eq!(Some(Response::Ok), Response::parse(&Command::Takeoff, "ok"));
+        assert_eq!(
+            Some(Response::Error(String::from("error Not joystick"))),
+            Response::parse(&Command::Takeoff, "error Not joystick\r\n")
+        );
+        assert_eq!(None, Response::parse(&Command::Takeoff, "87"));
+        assert_eq!(
+            Some(Response::Value(QueryValue::Number(87.0))),
+            Response::parse(&battery, "87\r\n")
+        );
+        assert_eq!(
+            Some(Response::Value(QueryValue::Number(12.0))),
+            Response::parse(&time, "12s")
+        );
+        assert_eq!(
+            Some(Response::Value(QueryValue::Text(String::from(
+                "0TQZH77ED00H9F"
+            )))),
+            Response::parse(&serial, "0TQZH77ED00H9F")
+        );
+        assert_eq!(None, Response::parse(&battery, "ok"));
+        assert!(matches!(
+            Response::parse(&battery, "unknown command: battery?"),
+            Some(Response::Error(_))
+        ));
+    }
+
+    /// Sends the request to the dispatcher and returns what the drone received.
+    async fn dispatch(
+        requests: &mpsc::Sender<Request>,
+        command: Command,
+        timeout: Duration,
+        retries: u32,
+    ) -> oneshot::Receiver<Response> {
+        let (reply, response) = oneshot::channel();
+        let policy = CommandPolicy { timeout, retries };
+        requests
+            .send(Request {
+                command,
+                policy,
+                reply,
+            })
+            .await
+            .unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_late_answers_and_retries() {
+        let drone = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (requests, src) = mpsc::channel(4);
+        tokio::spawn(send_commands(socket, drone.local_addr().unwrap(), src));
+        let mut buf = [0u8; 64];
+        let short = Duration::from_millis(100);
+
+        // the drone answers too late
+        let takeoff = dispatch(&requests, Command::Takeoff, short, 0).await;
+        let (size, client) = drone.recv_from(&mut buf).await.unwrap();
+        assert_eq!(b"takeoff", &buf[..size]);
+        assert_eq!(Response::Timeout, takeoff.await.unwrap());
+
+        // which must not be taken for the answer to the next command
+        drone.send_to(b"ok", client).await.unwrap();
+        let battery = dispatch(&requests, Command::QueryBattery, short * 10, 0).await;
+        resynchronise(&drone, client, &[]).await;
+        let (size, _) = drone.recv_from(&mut buf).await.unwrap();
+        assert_eq!(b"battery?", &buf[..size]);
+        drone.send_to(b"87", client).await.unwrap();
+        assert_eq!(
+            Response::Value(QueryValue::Number(87.0)),
+            battery.await.unwrap()
+        );
+
+        // repeatable commands are tried again
+        let stream = dispatch(&requests, Command::EnableStream, short, 1).await;
+        for attempt in 0..2 {
+            if attempt > 0 {
+                resynchronise(&drone, client, &[]).await;
+            }
+            let (size, _) = drone.recv_from(&mut buf).await.unwrap();
+            assert_eq!(b"streamon", &buf[..size]);
+        }
+        drone.send_to(b"ok", client).await.unwrap();
+        assert_eq!(Response::Ok, stream.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_late_ok() {
+        let drone = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (requests, src) = mpsc::channel(4);
+        tokio::spawn(send_commands(socket, drone.local_addr().unwrap(), src));
+        let mut buf = [0u8; 64];
+        let short = Duration::from_millis(100);
+
+        let takeoff = dispatch(&requests, Command::Takeoff, short, 0).await;
+        let (_, client) = drone.recv_from(&mut buf).await.unwrap();
+        assert_eq!(Response::Timeout, takeoff.await.unwrap());
+
+        // the takeoff is acknowledged long after, just ahead of the answer to the landing
+        let land = dispatch(&requests, Command::Land, short * 10, 0).await;
+        tokio::time::sleep(short * 6).await;
+        resynchronise(&drone, client, &[b"ok"]).await;
+        let (size, _) = drone.recv_from(&mut buf).await.unwrap();
+        assert_eq!(b"land", &buf[..size]);
+        drone.send_to(b"error Not landed", client).await.unwrap();
+        assert_eq!(
+            Response::Error(String::from("error Not landed")),
+            land.await.unwrap()
+        );
+
+        // a drone that stops answering loses commands rather than acknowledge the wrong ones
+        let stop = dispatch(&requests, Command::Stop, short, 0).await;
+        let (size, _) = drone.recv_from(&mut buf).await.unwrap();
+        assert_eq!(b"stop", &buf[..size]);
+        assert_eq!(Response::Timeout, stop.await.unwrap());
+        let flip = Command::Flip {
+            direction: FlipDirection::Left,
+        };
+        let flip = dispatch(&requests, flip, short, 0).await;
+        let (size, _) = drone.recv_from(&mut buf).await.unwrap();
+        assert_eq!(b"battery?", &buf[..size]);
+        drone.send_to(b"ok", client).await.unwrap();
+        assert_eq!(Response::Timeout, flip.await.unwrap());
+    }
+
+    /// Answers the [`RESYNC`] the drone receives, after sending `late` answers.
+    async fn resynchronise(drone: &UdpSocket, client: SocketAddr, late: &[&[u8]]) {
+        let mut buf = [0u8; 64];
+        let (size, _) = drone.recv_from(&mut buf).await.unwrap();
+        assert_eq!(RESYNC.to_string().as_bytes(), &buf[..size]);
+        for answer in late {
+            drone.send_to(answer, client).await.unwrap();
+        }
+        drone.send_to(b"87", client).await.unwrap();
+    }
+}
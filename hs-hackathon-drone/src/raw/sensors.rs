@@ -0,0 +1,301 @@
+use crate::TelemetryError;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::trace;
+
+/// The telemetry the drone broadcasts, see the
+/// [Tello docs](https://dl-cdn.ryzerobotics.com/downloads/Tello/Tello%20SDK%202.0%20User%20Guide.pdf).
+///
+/// The mission pad fields are only sent by a Tello EDU with mission pads enabled.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct State {
+    pub pitch: i32,
+    // the degree of the attitude pitch.
+    pub roll: i32,
+    // the degree of the attitude roll.
+    pub yaw: i32,
+    // the degree of the attitude yaw.
+    pub vgx: i32,
+    // the speed of “x” axis.
+    pub vgy: i32,
+    // the speed of the “y” axis.
+    pub vgz: i32,
+    // the speed of the “z” axis.
+    pub templ: i32,
+    // the lowest temperature in degree Celsius.
+    pub temph: i32,
+    // the highest temperature in degree Celsius
+    pub tof: i32,
+    // the time of flight distance in cm.
+    pub h: i32,
+    // the height in cm.
+    pub bat: i32,
+    // the percentage of the current battery level.
+    pub baro: f32,
+    // the barometer measurement in cm.
+    pub time: i32,
+    // the amount of time the motor has been used.
+    pub agx: f32,
+    // the acceleration of the “x” axis.
+    pub agy: f32,
+    // the acceleration of the “y” axis.
+    pub agz: f32, // the acceleration of the “z” axis.
+    /// The id of the detected mission pad, -1 if there is none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mid: Option<i32>,
+    /// The position in cm relative to the mission pad, -100 if there is none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub z: Option<i32>,
+    /// The pitch, roll and yaw in degrees relative to the mission pad.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mpry: Option<[i32; 3]>,
+    /// Fields this version does not know about, as they were received.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub unknown: BTreeMap<String, String>,
+}
+
+/// A [`State`] along with when it was received.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Telemetry {
+    #[serde(flatten)]
+    pub state: State,
+    /// Serialized as milliseconds since the unix epoch.
+    #[serde(serialize_with = "unix_millis")]
+    pub received: SystemTime,
+}
+
+impl Telemetry {
+    /// Stamps `state` as received just now.
+    pub fn now(state: State) -> Self {
+        Self {
+            state,
+            received: SystemTime::now(),
+        }
+    }
+}
+
+fn unix_millis<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    serializer.serialize_u64(millis)
+}
+
+impl FromStr for State {
+    type Err = TelemetryError;
+
+    /// Parses the `key:value;` pairs the drone sends, in any order.
+    fn from_str(received: &str) -> Result<Self, Self::Err> {
+        trace!("received: {}", &received);
+        let mut fields = BTreeMap::new();
+        for pair in received.trim().split(';').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once(':')
+                .ok_or_else(|| TelemetryError::Malformed(pair.to_string()))?;
+            fields.insert(key.trim(), value.trim());
+        }
+
+        let mut state = State {
+            pitch: take(&mut fields, "pitch")?,
+            roll: take(&mut fields, "roll")?,
+            yaw: take(&mut fields, "yaw")?,
+            vgx: take(&mut fields, "vgx")?,
+            vgy: take(&mut fields, "vgy")?,
+            vgz: take(&mut fields, "vgz")?,
+            templ: take(&mut fields, "templ")?,
+            temph: take(&mut fields, "temph")?,
+            tof: take(&mut fields, "tof")?,
+            h: take(&mut fields, "h")?,
+            bat: take(&mut fields, "bat")?,
+            baro: take(&mut fields, "baro")?,
+            time: take(&mut fields, "time")?,
+            agx: take(&mut fields, "agx")?,
+            agy: take(&mut fields, "agy")?,
+            agz: take(&mut fields, "agz")?,
+            mid: take_optional(&mut fields, "mid")?,
+            x: take_optional(&mut fields, "x")?,
+            y: take_optional(&mut fields, "y")?,
+            z: take_optional(&mut fields, "z")?,
+            mpry: None,
+            unknown: BTreeMap::new(),
+        };
+        if let Some(mpry) = fields.remove("mpry") {
+            let invalid = || TelemetryError::Invalid {
+                field: String::from("mpry"),
+                value: mpry.to_string(),
+            };
+            let angles: Vec<i32> = mpry
+                .split(',')
+                .map(|angle| angle.trim().parse().map_err(|_| invalid()))
+                .collect::<Result<_, _>>()?;
+            state.mpry = Some(angles.try_into().map_err(|_| invalid())?);
+        }
+        state.unknown = fields
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Ok(state)
+    }
+}
+
+impl fmt::Display for State {
+    /// Writes the wire format the drone sends, without the trailing `\r\n`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in [
+            ("mid", self.mid),
+            ("x", self.x),
+            ("y", self.y),
+            ("z", self.z),
+        ] {
+            if let Some(value) = value {
+                write!(f, "{key}:{value};")?;
+            }
+        }
+        if let Some([pitch, roll, yaw]) = self.mpry {
+            write!(f, "mpry:{pitch},{roll},{yaw};")?;
+        }
+        write!(
+            f,
+            "pitch:{};roll:{};yaw:{};vgx:{};vgy:{};vgz:{};templ:{};temph:{};tof:{};h:{};bat:{};baro:{};time:{};agx:{};agy:{};agz:{};",
+            self.pitch,
+            self.roll,
+            self.yaw,
+            self.vgx,
+            self.vgy,
+            self.vgz,
+            self.templ,
+            self.temph,
+            self.tof,
+            self.h,
+            self.bat,
+            self.baro,
+            self.time,
+            self.agx,
+            self.agy,
+            self.agz,
+        )?;
+        for (key, value) in &self.unknown {
+            write!(f, "{key}:{value};")?;
+        }
+        Ok(())
+    }
+}
+
+fn take<T: FromStr>(fields: &mut BTreeMap<&str, &str>, field: &str) -> Result<T, TelemetryError> {
+    take_optional(fields, field)?.ok_or_else(|| TelemetryError::Missing(field.to_string()))
+}
+
+fn take_optional<T: FromStr>(
+    fields: &mut BTreeMap<&str, &str>,
+    field: &str,
+) -> Result<Option<T>, TelemetryError> {
+    fields
+        .remove(field)
+        .map(|value| {
+            value.parse().map_err(|_| TelemetryError::Invalid {
+                field: field.to_string(),
+                value: value.to_string(),
+            })
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_real_example() {
+        let msg = "pitch:0;roll:0;yaw:-93;vgx:0;vgy:0;vgz:0;templ:71;temph:74;tof:10;h:0;bat:32;baro:-33.48;time:0;agx:8.00;agy:0.00;agz:-1002.00;\r\n";
+        let parsed = msg.parse::<State>();
+        assert_eq!(71, parsed.unwrap().templ);
+    }
+
+    #[test]
+    fn test_parse_without_trailing_newlines() {
+        let msg = "pitch:0;roll:0;yaw:-93;vgx:0;vgy:0;vgz:0;templ:71;temph:74;tof:10;h:0;bat:32;baro:-33.48;time:0;agx:8.00;agy:0.00;agz:-1002.00;";
+        let parsed = msg.parse::<State>();
+        assert_eq!(71, parsed.unwrap().templ);
+    }
+
+    #[test]
+    fn test_parse_zeros() {
+        let msg = "pitch:0;roll:0;yaw:0;vgx:0;vgy:0;vgz:0;templ:0;temph:0;tof:0;h:0;bat:0;baro:0.0;time:0;agx:0.0;agy:0.0;agz:0.0;\r\n";
+        let parsed = msg.parse::<State>();
+        assert_eq!(0, parsed.unwrap().templ);
+    }
+
+    #[test]
+    fn test_telemetry_json() {
+        let telemetry = Telemetry {
+            state: State {
+                bat: 32,
+                tof: 10,
+                ..State::default()
+            },
+            received: UNIX_EPOCH + std::time::Duration::from_millis(1500),
+        };
+        let json = serde_json::to_value(&telemetry).unwrap();
+        assert_eq!(32, json["bat"]);
+        assert_eq!(10, json["tof"]);
+        assert_eq!(1500, json["received"]);
+    }
+
+    #[test]
+    fn test_parse_mission_pads() {
+        let msg = "mid:3;x:-12;y:40;z:80;mpry:1,-2,90;pitch:0;roll:0;yaw:-93;vgx:0;vgy:0;vgz:0;templ:71;temph:74;tof:10;h:0;bat:32;baro:-33.48;time:0;agx:8.00;agy:0.00;agz:-1002.00;\r\n";
+        let parsed = msg.parse::<State>().unwrap();
+        assert_eq!(Some(3), parsed.mid);
+        assert_eq!(
+            (Some(-12), Some(40), Some(80)),
+            (parsed.x, parsed.y, parsed.z)
+        );
+        assert_eq!(Some([1, -2, 90]), parsed.mpry);
+        assert_eq!(-93, parsed.yaw);
+        assert!(parsed.unknown.is_empty());
+
+        let parsed = "pitch:0;roll:0;yaw:0;vgx:0;vgy:0;vgz:0;templ:0;temph:0;tof:0;h:0;bat:0;baro:0.0;time:0;agx:0.0;agy:0.0;agz:0.0;".parse::<State>().unwrap();
+        assert_eq!(None, parsed.mid);
+        assert_eq!(None, parsed.mpry);
+    }
+
+    #[test]
+    fn test_parse_unknown_and_invalid_fields() {
+        let msg = "pitch:0;roll:0;yaw:0;vgx:0;vgy:0;vgz:0;templ:0;temph:0;tof:0;h:0;bat:0;baro:0.0;time:0;agx:0.0;agy:0.0;agz:0.0;wind:3;";
+        let parsed = msg.parse::<State>().unwrap();
+        assert_eq!(Some("3"), parsed.unknown.get("wind").map(String::as_str));
+
+        let invalid = msg.replace("bat:0", "bat:full");
+        assert_eq!(
+            Err(TelemetryError::Invalid {
+                field: String::from("bat"),
+                value: String::from("full"),
+            }),
+            invalid.parse::<State>()
+        );
+        let missing = msg.replace("tof:0;", "");
+        assert_eq!(
+            Err(TelemetryError::Missing(String::from("tof"))),
+            missing.parse::<State>()
+        );
+        assert!(matches!(
+            msg.replace("bat:0", "bat0").parse::<State>(),
+            Err(TelemetryError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_wire_round_trip() {
+        let msg = "mid:3;x:-12;y:40;z:80;mpry:1,-2,90;pitch:0;roll:0;yaw:-93;vgx:0;vgy:0;vgz:0;templ:71;temph:74;tof:10;h:0;bat:32;baro:-33.48;time:0;agx:8;agy:0;agz:-1002;wind:3;";
+        let parsed = msg.parse::<State>().unwrap();
+        assert_eq!(msg, parsed.to_string());
+        assert_eq!(Ok(parsed.clone()), parsed.to_string().parse());
+    }
+}
@@ -0,0 +1,456 @@
+//! Keeping the drone inside the enclosure.
+//!
+//! A [`SafetyEnvelope`] is a box around the takeoff point along with a ceiling. With it set
+//! through [`Drone::set_envelope`](crate::Drone::set_envelope), the drone tracks where it
+//! should be from the commands it acknowledged and the height and yaw in its telemetry, and
+//! rejects commands that would take it outside. It also lands the drone on its own when
+//! its battery runs low, it overheats or its telemetry stops arriving, going through the
+//! [`FailsafeState`]s along the way.
+
+use crate::raw::control::Command;
+use crate::raw::sensors::Telemetry;
+use crate::SafetyError;
+use serde::Serialize;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// Where the drone may fly, and when it has to land.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SafetyEnvelope {
+    /// How far the drone may get forward or backward of the takeoff point, in cm.
+    pub max_x: i32,
+    /// How far the drone may get left or right of the takeoff point, in cm.
+    pub max_y: i32,
+    /// How high the drone may fly, in cm.
+    pub ceiling: i32,
+    /// The battery percentage at which to warn that the drone will land soon.
+    pub warn_battery: i32,
+    /// The battery percentage at which the drone lands.
+    pub land_battery: i32,
+    /// The temperature in °C at which the drone lands.
+    pub max_temperature: i32,
+    /// How long the drone may fly without telemetry before it lands.
+    pub telemetry_timeout: Duration,
+}
+
+impl Default for SafetyEnvelope {
+    /// Three 20cm nudges in any direction from where the drone was put in the enclosure.
+    fn default() -> Self {
+        Self {
+            max_x: 60,
+            max_y: 60,
+            ceiling: 180,
+            warn_battery: 20,
+            land_battery: 10,
+            max_temperature: 85,
+            telemetry_timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+/// Why the drone lands on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LandReason {
+    /// The battery is at the given percentage.
+    LowBattery(i32),
+    /// The drone is at the given temperature in °C.
+    Overheating(i32),
+    /// No telemetry arrived in time.
+    TelemetryLost,
+}
+
+impl SafetyEnvelope {
+    /// Why the drone needs to land given its latest telemetry, if it does.
+    pub fn land_reason(&self, latest: Option<&Telemetry>, now: SystemTime) -> Option<LandReason> {
+        let Some(latest) = latest else {
+            return Some(LandReason::TelemetryLost);
+        };
+        let age = now.duration_since(latest.received).unwrap_or_default();
+        if age > self.telemetry_timeout {
+            Some(LandReason::TelemetryLost)
+        } else if latest.state.bat <= self.land_battery {
+            Some(LandReason::LowBattery(latest.state.bat))
+        } else if latest.state.temph >= self.max_temperature {
+            Some(LandReason::Overheating(latest.state.temph))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for LandReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LandReason::LowBattery(battery) => write!(f, "battery at {battery}%"),
+            LandReason::Overheating(temperature) => write!(f, "overheating at {temperature}°C"),
+            LandReason::TelemetryLost => write!(f, "no telemetry"),
+        }
+    }
+}
+
+/// How close the drone is to having to land.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum FailsafeState {
+    /// All is well.
+    #[default]
+    Nominal,
+    /// The battery is at the given percentage, the drone will land soon.
+    LowBattery { battery: i32 },
+    /// No telemetry arrives while the drone is on the ground.
+    TelemetryLost,
+    /// The drone is being landed.
+    Landing { reason: LandReason },
+    /// The drone was landed, and may only take off again once the reason is gone.
+    Landed { reason: LandReason },
+}
+
+impl FailsafeState {
+    /// The state to move to from this one.
+    ///
+    /// A drone that was landed stays [`Landed`](FailsafeState::Landed) until it takes off
+    /// again.
+    pub fn next(
+        self,
+        envelope: &SafetyEnvelope,
+        flying: bool,
+        latest: Option<&Telemetry>,
+        now: SystemTime,
+    ) -> FailsafeState {
+        let reason = envelope.land_reason(latest, now);
+        let battery = latest.map(|latest| latest.state.bat);
+        let low_battery = battery.filter(|battery| *battery <= envelope.warn_battery);
+        match (self, flying) {
+            (FailsafeState::Landing { reason }, true) => FailsafeState::Landing { reason },
+            (FailsafeState::Landing { reason }, false) => FailsafeState::Landed { reason },
+            (FailsafeState::Landed { reason }, false) => FailsafeState::Landed { reason },
+            (_, true) => match (reason, low_battery) {
+                (Some(reason), _) => FailsafeState::Landing { reason },
+                (None, Some(battery)) => FailsafeState::LowBattery { battery },
+                (None, None) => FailsafeState::Nominal,
+            },
+            (_, false) => match (reason, low_battery) {
+                (Some(LandReason::TelemetryLost), _) => FailsafeState::TelemetryLost,
+                (_, Some(battery)) => FailsafeState::LowBattery { battery },
+                _ => FailsafeState::Nominal,
+            },
+        }
+    }
+}
+
+impl fmt::Display for FailsafeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailsafeState::Nominal => write!(f, "nominal"),
+            FailsafeState::LowBattery { battery } => write!(f, "low battery at {battery}%"),
+            FailsafeState::TelemetryLost => write!(f, "no telemetry"),
+            FailsafeState::Landing { reason } => write!(f, "landing, {reason}"),
+            FailsafeState::Landed { reason } => write!(f, "landed, {reason}"),
+        }
+    }
+}
+
+/// Where the drone should be, relative to where it took off.
+///
+/// `x` points forward and `y` left as the drone faced at takeoff, `z` up. `yaw` is in
+/// degrees, clockwise.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Position {
+    pub flying: bool,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub yaw: f32,
+    /// The yaw in the telemetry at takeoff.
+    yaw_at_takeoff: Option<f32>,
+}
+
+/// How high the drone hovers after takeoff, in cm.
+const TAKEOFF_HEIGHT: f32 = 80.0;
+
+impl Position {
+    /// Where the drone ends up after `command`, or `None` if that cannot be told.
+    pub fn after(&self, command: &Command) -> Option<Position> {
+        let mut next = *self;
+        match *command {
+            Command::Takeoff => {
+                next = Position {
+                    flying: true,
+                    z: TAKEOFF_HEIGHT,
+                    yaw_at_takeoff: self.yaw_at_takeoff,
+                    ..Position::default()
+                };
+            }
+            Command::Land | Command::Emergency => {
+                next.flying = false;
+                next.z = 0.0;
+            }
+            Command::Up { cm } => next.z += cm as f32,
+            Command::Down { cm } => next.z -= cm as f32,
+            Command::Forward { cm } => next.advance(cm as f32, 0.0),
+            Command::Back { cm } => next.advance(-(cm as f32), 0.0),
+            Command::Left { cm } => next.advance(0.0, cm as f32),
+            Command::Right { cm } => next.advance(0.0, -(cm as f32)),
+            Command::Go { x, y, z, .. } => {
+                next.advance(x as f32, y as f32);
+                next.z += z as f32;
+            }
+            Command::Curve { x2, y2, z2, .. } => {
+                next.advance(x2 as f32, y2 as f32);
+                next.z += z2 as f32;
+            }
+            Command::Clockwise { degrees } => next.yaw += degrees as f32,
+            Command::CounterClockwise { degrees } => next.yaw -= degrees as f32,
+            // the drone flies for as long as it is told to
+            Command::Rc { .. } => return None,
+            _ => {}
+        }
+        Some(next)
+    }
+
+    /// Moves `forward` cm forward and `left` cm left of where the drone faces.
+    fn advance(&mut self, forward: f32, left: f32) {
+        let (sin, cos) = self.yaw.to_radians().sin_cos();
+        self.x += forward * cos + left * sin;
+        self.y += left * cos - forward * sin;
+    }
+
+    /// Takes the height and yaw from the telemetry.
+    pub fn observe(&mut self, telemetry: &Telemetry) {
+        let yaw = telemetry.state.yaw as f32;
+        if !self.flying {
+            self.yaw_at_takeoff = Some(yaw);
+            return;
+        }
+        self.z = telemetry.state.h as f32;
+        if let Some(at_takeoff) = self.yaw_at_takeoff {
+            self.yaw = yaw - at_takeoff;
+        }
+    }
+}
+
+impl SafetyEnvelope {
+    /// Checks that `command` keeps the drone at `position` inside the envelope.
+    ///
+    /// Takeoff is refused for as long as the latest telemetry gives a reason to land.
+    pub fn check(
+        &self,
+        position: &Position,
+        command: &Command,
+        latest: Option<&Telemetry>,
+        now: SystemTime,
+    ) -> Result<(), SafetyError> {
+        if *command == Command::Takeoff {
+            match self.land_reason(latest, now) {
+                Some(LandReason::LowBattery(battery)) => {
+                    return Err(SafetyError::LowBattery(battery))
+                }
+                Some(reason) => return Err(SafetyError::Grounded(reason)),
+                None => {}
+            }
+        }
+        let next = position
+            .after(command)
+            .ok_or_else(|| SafetyError::Unpredictable(command.to_string()))?;
+        // a curve might bulge out of the box on its way to the end point
+        let via = match *command {
+            Command::Curve { x1, y1, z1, .. } => position.after(&Command::Go {
+                x: x1,
+                y: y1,
+                z: z1,
+                speed: 10,
+            }),
+            _ => None,
+        };
+        for point in std::iter::once(next).chain(via) {
+            if point.z > self.ceiling as f32 {
+                return Err(SafetyError::AboveCeiling {
+                    z: point.z.round() as i32,
+                    ceiling: self.ceiling,
+                });
+            }
+            if point.x.abs() > self.max_x as f32 || point.y.abs() > self.max_y as f32 {
+                return Err(SafetyError::OutsideEnvelope {
+                    x: point.x.round() as i32,
+                    y: point.y.round() as i32,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw::sensors::State;
+
+    #[test]
+    fn test_dead_reckoning() {
+        let flying = Position::default().after(&Command::Takeoff).unwrap();
+        let turned = flying.after(&Command::Clockwise { degrees: 90 }).unwrap();
+        // facing right, forward is to the right of where the drone took off
+        let moved = turned.after(&Command::Forward { cm: 100 }).unwrap();
+        assert!(moved.x.abs() < 1e-3);
+        assert!((moved.y + 100.0).abs() < 1e-3);
+        let moved = moved.after(&Command::Left { cm: 50 }).unwrap();
+        assert!((moved.x - 50.0).abs() < 1e-3);
+        assert_eq!(
+            None,
+            moved.after(&Command::Rc {
+                left_right: 0,
+                forward_backward: 10,
+                up_down: 0,
+                yaw: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let envelope = SafetyEnvelope::default();
+        let landed = Position::default();
+        let now = SystemTime::now();
+        let telemetry = |bat, temph| Telemetry {
+            state: State {
+                bat,
+                temph,
+                ..State::default()
+            },
+            received: now,
+        };
+        let check = |position: &Position, command: &Command, latest: Option<Telemetry>| {
+            envelope.check(position, command, latest.as_ref(), now)
+        };
+        check(&landed, &Command::Takeoff, Some(telemetry(50, 40))).unwrap();
+        assert_eq!(
+            Err(SafetyError::LowBattery(8)),
+            check(&landed, &Command::Takeoff, Some(telemetry(8, 40)))
+        );
+        // landed for overheating, and still too hot
+        assert_eq!(
+            Err(SafetyError::Grounded(LandReason::Overheating(90))),
+            check(&landed, &Command::Takeoff, Some(telemetry(50, 90)))
+        );
+        assert_eq!(
+            Err(SafetyError::Grounded(LandReason::TelemetryLost)),
+            check(&landed, &Command::Takeoff, None)
+        );
+
+        let flying = landed.after(&Command::Takeoff).unwrap();
+        check(&flying, &Command::Forward { cm: 60 }, None).unwrap();
+        assert_eq!(
+            Err(SafetyError::OutsideEnvelope { x: 0, y: -200 }),
+            check(&flying, &Command::Right { cm: 200 }, None)
+        );
+        assert_eq!(
+            Err(SafetyError::AboveCeiling {
+                z: 200,
+                ceiling: 180
+            }),
+            check(&flying, &Command::Up { cm: 120 }, None)
+        );
+        let bulging = Command::Curve {
+            x1: 200,
+            y1: 0,
+            z1: 0,
+            x2: 100,
+            y2: 0,
+            z2: 0,
+            speed: 20,
+        };
+        assert!(check(&flying, &bulging, None).is_err());
+    }
+
+    #[test]
+    fn test_observe() {
+        let telemetry = |yaw, h| Telemetry {
+            state: State {
+                yaw,
+                h,
+                ..State::default()
+            },
+            received: SystemTime::now(),
+        };
+        let mut position = Position::default();
+        position.observe(&telemetry(-93, 0));
+        position = position.after(&Command::Takeoff).unwrap();
+        position.observe(&telemetry(-3, 75));
+        assert_eq!(90.0, position.yaw);
+        assert_eq!(75.0, position.z);
+    }
+
+    #[test]
+    fn test_land_reason() {
+        let envelope = SafetyEnvelope::default();
+        let now = SystemTime::now();
+        let telemetry = |bat, temph, age| Telemetry {
+            state: State {
+                bat,
+                temph,
+                ..State::default()
+            },
+            received: now - Duration::from_secs(age),
+        };
+        assert_eq!(None, envelope.land_reason(Some(&telemetry(50, 70, 0)), now));
+        assert_eq!(
+            Some(LandReason::LowBattery(10)),
+            envelope.land_reason(Some(&telemetry(10, 70, 0)), now)
+        );
+        assert_eq!(
+            Some(LandReason::Overheating(90)),
+            envelope.land_reason(Some(&telemetry(50, 90, 0)), now)
+        );
+        assert_eq!(
+            Some(LandReason::TelemetryLost),
+            envelope.land_reason(Some(&telemetry(50, 70, 5)), now)
+        );
+        assert_eq!(
+            Some(LandReason::TelemetryLost),
+            envelope.land_reason(None, now)
+        );
+    }
+
+    #[test]
+    fn test_failsafe_transitions() {
+        let envelope = SafetyEnvelope::default();
+        let now = SystemTime::now();
+        let telemetry = |bat| Telemetry {
+            state: State {
+                bat,
+                temph: 70,
+                ..State::default()
+            },
+            received: now,
+        };
+        let next = |state: FailsafeState, flying, bat| {
+            state.next(&envelope, flying, Some(&telemetry(bat)), now)
+        };
+
+        let state = next(FailsafeState::Nominal, true, 50);
+        assert_eq!(FailsafeState::Nominal, state);
+        let state = next(state, true, 20);
+        assert_eq!(FailsafeState::LowBattery { battery: 20 }, state);
+        let state = next(state, true, 10);
+        let landing = LandReason::LowBattery(10);
+        assert_eq!(FailsafeState::Landing { reason: landing }, state);
+        // keeps landing for the original reason
+        let state = next(state, true, 9);
+        assert_eq!(FailsafeState::Landing { reason: landing }, state);
+        let state = next(state, false, 9);
+        assert_eq!(FailsafeState::Landed { reason: landing }, state);
+        let state = next(state, false, 80);
+        assert_eq!(FailsafeState::Landed { reason: landing }, state);
+        assert_eq!(FailsafeState::Nominal, next(state, true, 80));
+
+        let lost = FailsafeState::Nominal.next(&envelope, false, None, now);
+        assert_eq!(FailsafeState::TelemetryLost, lost);
+        assert_eq!(
+            FailsafeState::Landing {
+                reason: LandReason::TelemetryLost
+            },
+            lost.next(&envelope, true, None, now)
+        );
+    }
+}
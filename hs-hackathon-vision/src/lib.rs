@@ -7,10 +7,14 @@ use crate::raw::{BLUE, GREEN, RED};
 use image::{DynamicImage, Rgba};
 pub use raw::bounding_box::BoundingBox;
 pub use raw::colors::Color;
+pub use raw::errors::VisionError;
 pub use raw::led_detector::{Led, LedDetectionConfig};
 
 /// Detect all LEDs that are visible in a given frame
-pub fn detect(frame: &DynamicImage, configuration: &LedDetectionConfig) -> eyre::Result<Vec<Led>> {
+pub fn detect(
+    frame: &DynamicImage,
+    configuration: &LedDetectionConfig,
+) -> Result<Vec<Led>, VisionError> {
     get_leds(frame, configuration)
 }
 
@@ -1,4 +1,4 @@
-use eyre::ensure;
+use crate::raw::errors::VisionError;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct BoundingBox {
@@ -9,9 +9,15 @@ pub struct BoundingBox {
 }
 
 impl BoundingBox {
-    pub fn new(x_min: u32, y_min: u32, x_max: u32, y_max: u32) -> eyre::Result<Self> {
-        ensure!(x_min <= x_max, "x_min must be inferior or equal than x_max");
-        ensure!(y_min <= y_max, "y_min must be inferior or equal than y_max");
+    pub fn new(x_min: u32, y_min: u32, x_max: u32, y_max: u32) -> Result<Self, VisionError> {
+        if x_min > x_max || y_min > y_max {
+            return Err(VisionError::InvalidBoundingBox {
+                x_min,
+                y_min,
+                x_max,
+                y_max,
+            });
+        }
         Ok(Self {
             x_min,
             y_min,
@@ -39,27 +45,8 @@ impl BoundingBox {
         y_min: u32,
         x_max: u32,
         y_max: u32,
-    ) -> eyre::Result<()> {
-        ensure!(
-            x_min <= self.x_max,
-            "x_min must be inferior or equal than x_max"
-        );
-        self.x_min = x_min;
-        ensure!(
-            y_min <= self.y_max,
-            "y_min must be inferior or equal than y_max"
-        );
-        self.y_min = y_min;
-        ensure!(
-            x_max >= self.x_min,
-            "x_min must be inferior or equal than x_max"
-        );
-        self.x_max = x_max;
-        ensure!(
-            y_max >= self.y_min,
-            "y_min must be inferior or equal than y_max"
-        );
-        self.y_max = y_max;
+    ) -> Result<(), VisionError> {
+        *self = Self::new(x_min, y_min, x_max, y_max)?;
         Ok(())
     }
 
@@ -72,3 +59,25 @@ impl BoundingBox {
             || (self.x_max - self.x_min > max_size.0 || self.y_max - self.y_min > max_size.1))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_coordinates() {
+        assert_eq!(
+            Err(VisionError::InvalidBoundingBox {
+                x_min: 10,
+                y_min: 0,
+                x_max: 5,
+                y_max: 5
+            }),
+            BoundingBox::new(10, 0, 5, 5)
+        );
+
+        let mut bbox = BoundingBox::new(0, 0, 5, 5).unwrap();
+        assert!(bbox.set_coordinates(0, 6, 5, 5).is_err());
+        assert_eq!(BoundingBox::new(0, 0, 5, 5).unwrap(), bbox);
+    }
+}
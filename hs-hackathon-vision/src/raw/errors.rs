@@ -0,0 +1,39 @@
+use std::error::Error;
+use std::fmt;
+
+/// Why detecting LEDs in a frame failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VisionError {
+    /// The minimum of a bounding box lies beyond its maximum.
+    InvalidBoundingBox {
+        x_min: u32,
+        y_min: u32,
+        x_max: u32,
+        y_max: u32,
+    },
+    /// Two images that are combined pixel by pixel differ in size.
+    DimensionMismatch { left: (u32, u32), right: (u32, u32) },
+}
+
+impl fmt::Display for VisionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VisionError::InvalidBoundingBox {
+                x_min,
+                y_min,
+                x_max,
+                y_max,
+            } => write!(
+                f,
+                "bounding box ({x_min}, {y_min})-({x_max}, {y_max}) must have its minimum inferior or equal to its maximum"
+            ),
+            VisionError::DimensionMismatch { left, right } => write!(
+                f,
+                "images must have the same dimensions, not {}x{} and {}x{}",
+                left.0, left.1, right.0, right.1
+            ),
+        }
+    }
+}
+
+impl Error for VisionError {}
@@ -1,3 +1,4 @@
+use crate::raw::errors::VisionError;
 use crate::raw::preprocessor::BrightArea;
 use crate::raw::{
     bounding_box::BoundingBox, colors::detect_color, colors::Color,
@@ -38,7 +39,7 @@ impl Default for LedDetectionConfig {
         }
     }
 }
-fn find_leds_areas(img: &ImageBuffer<Luma<u8>, Vec<u8>>) -> eyre::Result<Vec<BoundingBox>> {
+fn find_leds_areas(img: &ImageBuffer<Luma<u8>, Vec<u8>>) -> Result<Vec<BoundingBox>, VisionError> {
     let mut visited: Vec<Vec<bool>> =
         vec![vec![false; img.height() as usize]; img.width() as usize];
     let mut bounding_boxes = Vec::new();
@@ -85,7 +86,10 @@ fn find_leds_areas(img: &ImageBuffer<Luma<u8>, Vec<u8>>) -> eyre::Result<Vec<Bou
     Ok(bounding_boxes)
 }
 
-pub fn get_leds(image: &DynamicImage, config: &LedDetectionConfig) -> eyre::Result<Vec<Led>> {
+pub fn get_leds(
+    image: &DynamicImage,
+    config: &LedDetectionConfig,
+) -> Result<Vec<Led>, VisionError> {
     let BrightArea {
         thresholded,
         resized,
@@ -115,7 +119,7 @@ pub fn get_leds(image: &DynamicImage, config: &LedDetectionConfig) -> eyre::Resu
                 }
             }
         })
-        .collect::<eyre::Result<Vec<Led>>>()?;
+        .collect::<Result<Vec<Led>, VisionError>>()?;
     Ok(bounding_boxes_with_color)
 }
 
@@ -3,6 +3,7 @@
 pub mod bounding_box;
 pub mod colors;
 pub mod distance;
+pub mod errors;
 pub mod led_detector;
 pub mod preprocessor;
 pub mod utils;
@@ -57,7 +58,7 @@ mod tests {
             max_size: (50, 50),
         };
 
-        let leds = get_leds(&mut img, &config).unwrap();
+        let leds = get_leds(&img, &config).unwrap();
 
         let red_led = leds
             .iter()
@@ -102,7 +103,7 @@ mod tests {
             max_size: (20, 20),
         };
 
-        let leds = get_leds(&mut img, &config).unwrap();
+        let leds = get_leds(&img, &config).unwrap();
         assert_eq!(leds.len(), 1);
         assert_eq!(leds[0].color, Color::Green);
         assert_eq!(leds[0].bbox.x_min(), 586);
@@ -129,7 +130,7 @@ mod tests {
             max_size: (20, 20),
         };
 
-        let leds = get_leds(&mut img, &config).unwrap();
+        let leds = get_leds(&img, &config).unwrap();
         draw_bounding_boxes(&mut img, vec![leds[0].bbox], Rgba(RED));
         img.save("car_red_bbox.png").unwrap();
         assert_eq!(leds.len(), 1);
@@ -158,7 +159,7 @@ mod tests {
             max_size: (20, 20),
         };
 
-        let leds = get_leds(&mut img, &config).unwrap();
+        let leds = get_leds(&img, &config).unwrap();
         draw_bounding_boxes(&mut img, vec![leds[0].bbox], Rgba(BLUE));
         img.save("car_blue_bbox.png").unwrap();
         assert_eq!(leds.len(), 1);
@@ -1,5 +1,5 @@
+use crate::raw::errors::VisionError;
 use crate::raw::led_detector::LedDetectionConfig;
-use eyre::eyre;
 use image::{DynamicImage, GenericImageView, ImageBuffer, Luma, Rgba};
 
 pub struct BrightArea {
@@ -10,7 +10,7 @@ pub struct BrightArea {
 pub fn extract_bright_areas(
     image: &DynamicImage,
     config: &LedDetectionConfig,
-) -> eyre::Result<BrightArea> {
+) -> Result<BrightArea, VisionError> {
     // Resize to make blur fast enough
     let resized = image.resize(config.width, config.height, config.filter);
 
@@ -24,9 +24,12 @@ pub fn extract_bright_areas(
     })
 }
 
-fn subtract(img1: &DynamicImage, img2: &DynamicImage) -> eyre::Result<DynamicImage> {
+fn subtract(img1: &DynamicImage, img2: &DynamicImage) -> Result<DynamicImage, VisionError> {
     if img1.dimensions() != img2.dimensions() {
-        return Err(eyre!("Images must have the same dimensions"));
+        return Err(VisionError::DimensionMismatch {
+            left: img1.dimensions(),
+            right: img2.dimensions(),
+        });
     }
 
     // TODO: optimise this code; should be able to do this on the underlying array
@@ -49,7 +52,7 @@ fn difference_of_gaussians(
     img: &DynamicImage,
     radius1: f32,
     radius2: f32,
-) -> eyre::Result<DynamicImage> {
+) -> Result<DynamicImage, VisionError> {
     subtract(&img.blur(radius1), &img.blur(radius2))
 }
 
@@ -1,4 +1,5 @@
 use crate::raw::bounding_box::BoundingBox;
+use crate::raw::errors::VisionError;
 use image::{DynamicImage, GenericImage, Rgba};
 
 pub fn draw_bounding_box(img: &mut DynamicImage, bbox: BoundingBox, border_color: Rgba<u8>) {
@@ -31,7 +32,7 @@ pub fn bbox_resize(
     bbox: &BoundingBox,
     original_size: &(u32, u32),
     resized_size: &(u32, u32),
-) -> eyre::Result<BoundingBox> {
+) -> Result<BoundingBox, VisionError> {
     let scale_x = original_size.0 as f32 / resized_size.0 as f32;
     let scale_y = original_size.1 as f32 / resized_size.1 as f32;
 
@@ -21,10 +21,16 @@ pub mod drone {
 
 /// A hardware abstraction layer over the motor and wheels of the RC car
 pub mod car {
-    pub use hs_hackathon_car::{Angle, MotorSocket, Velocity, WheelOrientation};
+    pub use hs_hackathon_car::{
+        calibration, config, ramp, sim, Angle, CarConfig, DifferentialDrive, MotorError,
+        MotorSocket, Ramp, ServoCalibration, StepDirection, StepStyle, Stepper, StepperKit,
+        Velocity, Watchdog, WheelOrientation,
+    };
 }
 
 /// A computer vision api to detect LEDs inside of video frames recieved from drones
 pub mod vision {
-    pub use hs_hackathon_vision::{detect, distance, BoundingBox, Color, Led, LedDetectionConfig};
+    pub use hs_hackathon_vision::{
+        detect, distance, BoundingBox, Color, Led, LedDetectionConfig, VisionError,
+    };
 }